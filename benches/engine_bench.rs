@@ -1,5 +1,5 @@
 use criterion::{criterion_group, criterion_main, BatchSize, Criterion, ParameterizedBenchmark};
-use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use kvs::{KvStore, KvsEngine, MemoryEngine, SledKvsEngine};
 use rand::prelude::*;
 use sled::Db;
 use std::iter;
@@ -49,13 +49,13 @@ fn get_bench(c: &mut Criterion) {
             let mut store = KvStore::open(temp_dir.path()).unwrap();
             for key_i in 1..(1 << i) {
                 store
-                    .set(format!("key{}", key_i), "value".to_string())
+                    .set_string(format!("key{}", key_i), "value".to_string())
                     .unwrap();
             }
             let mut rng = SmallRng::from_seed([0; 16]);
             b.iter(|| {
                 store
-                    .get(format!("key{}", rng.gen_range(1, 1 << i)))
+                    .get_string(format!("key{}", rng.gen_range(1, 1 << i)))
                     .unwrap();
             })
         },
@@ -65,12 +65,27 @@ fn get_bench(c: &mut Criterion) {
         let temp_dir = TempDir::new().unwrap();
         let mut db = SledKvsEngine::new(&temp_dir.path().into());
         for key_i in 1..(1 << i) {
-            db.set(format!("key{}", key_i), "value".to_string())
+            db.set_string(format!("key{}", key_i), "value".to_string())
                 .unwrap();
         }
         let mut rng = SmallRng::from_seed([0; 16]);
         b.iter(|| {
-            db.get(format!("key{}", rng.gen_range(1, 1 << i))).unwrap();
+            db.get_string(format!("key{}", rng.gen_range(1, 1 << i)))
+                .unwrap();
+        })
+    })
+    .with_function("memory", |b, i| {
+        let engine = MemoryEngine::new();
+        for key_i in 1..(1 << i) {
+            engine
+                .set_string(format!("key{}", key_i), "value".to_string())
+                .unwrap();
+        }
+        let mut rng = SmallRng::from_seed([0; 16]);
+        b.iter(|| {
+            engine
+                .get_string(format!("key{}", rng.gen_range(1, 1 << i)))
+                .unwrap();
         })
     });
     c.bench("get_bench", bench);
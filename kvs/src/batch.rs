@@ -0,0 +1,49 @@
+use std::time::Duration;
+
+use crate::{kvs::expires_at, log::LogCommand};
+
+/// puts and deletes which `KvsEngine::write_batch` applies all at once or not at all,
+/// they are applied in the order they were added
+#[derive(Debug, Clone, Default)]
+pub struct WriteBatch {
+    pub(crate) commands: Vec<LogCommand>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    pub fn put(&mut self, key: Vec<u8>, value: Vec<u8>) -> &mut Self {
+        self.commands.push(LogCommand::Insert {
+            key,
+            value,
+            expires_at: None,
+        });
+        self
+    }
+
+    /// `put` of a key which disappears `ttl` after it's added
+    pub fn put_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> &mut Self {
+        self.commands.push(LogCommand::Insert {
+            key,
+            value,
+            expires_at: Some(expires_at(ttl)),
+        });
+        self
+    }
+
+    /// deleting a missing key is not an error within a batch
+    pub fn delete(&mut self, key: Vec<u8>) -> &mut Self {
+        self.commands.push(LogCommand::Remove { key });
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.commands.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.is_empty()
+    }
+}
@@ -0,0 +1,585 @@
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Instant;
+
+use crate::{
+    hint::{hint_file_name, write_hint_file, HintEntry},
+    kvs::{
+        fold, is_expired, now_millis, version_millis, Keyspace, Keyspaces, KvStoreReaders,
+        KvStoreValue, KvStoreWriter,
+    },
+    log::{write_record, LogCommand, LogWriter},
+    merge::MergeOperators,
+    storage::Storage,
+    Error, KvStore, Result, Retention,
+};
+
+/// a compacted segment is written under this extension and renamed when complete
+const COMPACTING_EXTENSION: &'static str = "compacting";
+
+#[derive(Debug)]
+enum CompactionRequest {
+    Run(Option<Sender<Result<()>>>),
+    Shutdown,
+}
+
+/// what a compaction rewrites, consecutive segments so their records keep their order
+#[derive(Debug)]
+pub struct CompactionPlan {
+    pub run: HashSet<String>,
+    /// the segments replayed before the run, they are left as they are
+    pub older: HashSet<String>,
+    /// keyspaces dropped in the run which could have records in the older segments
+    pub drops: Vec<(String, u64)>,
+    /// generation of the segment the run is compacted into
+    pub comp_gen: usize,
+}
+
+/// segments a compaction left behind, they stay on the disk while a snapshot could read them
+#[derive(Debug, Default)]
+pub struct RetiredSegments {
+    state: Mutex<RetiredState>,
+}
+
+#[derive(Debug, Default)]
+struct RetiredState {
+    pins: usize,
+    /// storage and name of every retired segment
+    retired: Vec<(Arc<dyn Storage>, String)>,
+}
+
+impl RetiredSegments {
+    /// removes the segments right away unless a snapshot is around
+    fn retire(&self, storage: &Arc<dyn Storage>, log_names: Vec<String>) -> Result<()> {
+        let mut state = self.state.lock().expect("mutex not poisoned");
+        if state.pins > 0 {
+            state.retired.extend(
+                log_names
+                    .into_iter()
+                    .map(|log_name| (Arc::clone(storage), log_name)),
+            );
+            return Ok(());
+        }
+
+        for log_name in log_names {
+            remove_segment(storage.as_ref(), &log_name)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// keeps every segment retired in its lifetime on the disk
+#[derive(Debug)]
+pub struct SegmentPin {
+    segments: Arc<RetiredSegments>,
+}
+
+impl SegmentPin {
+    pub fn new(segments: &Arc<RetiredSegments>) -> Self {
+        segments.state.lock().expect("mutex not poisoned").pins += 1;
+
+        SegmentPin {
+            segments: Arc::clone(segments),
+        }
+    }
+}
+
+impl Drop for SegmentPin {
+    fn drop(&mut self) {
+        let mut state = self.segments.state.lock().expect("mutex not poisoned");
+        state.pins -= 1;
+
+        if state.pins == 0 {
+            // the next compaction removes whatever is left
+            for (storage, log_name) in std::mem::take(&mut state.retired) {
+                let _ = remove_segment(storage.as_ref(), &log_name);
+            }
+        }
+    }
+}
+
+/// the sequence numbers the live snapshots read as of, with how many of them read as of each,
+/// a compaction keeps the version of a key each of them reads
+#[derive(Debug, Default)]
+pub struct PinnedVersions {
+    versions: Mutex<BTreeMap<u64, usize>>,
+}
+
+impl PinnedVersions {
+    /// oldest first
+    fn pinned(&self) -> Vec<u64> {
+        self.versions
+            .lock()
+            .expect("mutex not poisoned")
+            .keys()
+            .cloned()
+            .collect()
+    }
+}
+
+/// the last write a snapshot reads as of, the versions it reads outlive the retention
+/// and the segments retired meanwhile stay on the disk until it's dropped
+#[derive(Debug)]
+pub struct SnapshotPin {
+    pub seq: u64,
+    versions: Arc<PinnedVersions>,
+    _segments: SegmentPin,
+}
+
+impl SnapshotPin {
+    /// has to be taken under the lock of the writer, so no compaction is planned meanwhile
+    pub fn new(seq: u64, versions: &Arc<PinnedVersions>, segments: SegmentPin) -> Self {
+        *versions
+            .versions
+            .lock()
+            .expect("mutex not poisoned")
+            .entry(seq)
+            .or_insert(0) += 1;
+
+        SnapshotPin {
+            seq,
+            versions: Arc::clone(versions),
+            _segments: segments,
+        }
+    }
+}
+
+impl Drop for SnapshotPin {
+    /// the versions no other snapshot reads go with the next compaction
+    fn drop(&mut self) {
+        let mut versions = self.versions.versions.lock().expect("mutex not poisoned");
+        if let Entry::Occupied(mut count) = versions.entry(self.seq) {
+            *count.get_mut() -= 1;
+            if *count.get() == 0 {
+                count.remove();
+            }
+        }
+    }
+}
+
+/// removes a segment along with its hint file
+fn remove_segment(storage: &dyn Storage, log_name: &str) -> Result<()> {
+    for name in &[log_name.to_owned(), hint_file_name(log_name)] {
+        match storage.remove(name) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+    }
+
+    Ok(())
+}
+
+/// background worker which rewrites the live records of sealed segments
+#[derive(Debug)]
+pub struct Compactor {
+    sender: Mutex<Sender<CompactionRequest>>,
+    handle: Option<JoinHandle<()>>,
+    running: Arc<AtomicBool>,
+}
+
+impl Compactor {
+    pub fn start(
+        writer: Arc<Mutex<KvStoreWriter>>,
+        keyspaces: Arc<RwLock<Keyspaces>>,
+        mut readers: KvStoreReaders,
+        retired: Arc<RetiredSegments>,
+        pinned: Arc<PinnedVersions>,
+        retention: Retention,
+        operators: Arc<MergeOperators>,
+    ) -> Self {
+        let (sender, receiver) = channel();
+        let running = Arc::new(AtomicBool::new(false));
+        let worker_running = Arc::clone(&running);
+
+        let handle = thread::spawn(move || {
+            for request in receiver {
+                match request {
+                    CompactionRequest::Run(reply) => {
+                        let result = compact(
+                            &writer,
+                            &keyspaces,
+                            &mut readers,
+                            &retired,
+                            &pinned,
+                            retention,
+                            &operators,
+                        );
+                        worker_running.store(false, Ordering::SeqCst);
+
+                        if let Some(reply) = reply {
+                            let _ = reply.send(result);
+                        }
+                    }
+                    CompactionRequest::Shutdown => break,
+                }
+            }
+        });
+
+        Compactor {
+            sender: Mutex::new(sender),
+            handle: Some(handle),
+            running,
+        }
+    }
+
+    /// schedules a compaction unless one is already on its way
+    pub fn trigger(&self) {
+        if !self.running.swap(true, Ordering::SeqCst) {
+            let _ = self
+                .sender
+                .lock()
+                .expect("mutex not poisoned")
+                .send(CompactionRequest::Run(None));
+        }
+    }
+
+    /// schedules a compaction and waits for its result
+    pub fn run(&self) -> Result<()> {
+        let (reply, result) = channel();
+        self.running.store(true, Ordering::SeqCst);
+        self.sender
+            .lock()
+            .expect("mutex not poisoned")
+            .send(CompactionRequest::Run(Some(reply)))
+            .expect("compaction worker is alive while the store is");
+
+        result
+            .recv()
+            .expect("compaction worker replies to every run")
+    }
+
+    /// removes compacted segments a crash left unfinished
+    /// and the segments which are not among the live ones
+    pub fn remove_unfinished(storage: &dyn Storage, live_log_names: &[String]) -> Result<()> {
+        for name in storage.list()? {
+            if name.ends_with(&format!(".{}", COMPACTING_EXTENSION)) {
+                storage.remove(&name)?;
+            }
+        }
+
+        for log_name in KvStore::get_log_files_names(storage)? {
+            if !live_log_names.contains(&log_name) {
+                remove_segment(storage, &log_name)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        let _ = self
+            .sender
+            .lock()
+            .expect("mutex not poisoned")
+            .send(CompactionRequest::Shutdown);
+
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// copies the records of the consecutive segments with the most stale bytes which are
+/// still needed into one new segment, the current value of every key and the older versions
+/// `retention` or a snapshot keeps, reads and writes go on except for the short moments
+/// the writer is locked
+fn compact(
+    writer: &Mutex<KvStoreWriter>,
+    keyspaces: &RwLock<Keyspaces>,
+    readers: &mut KvStoreReaders,
+    retired: &RetiredSegments,
+    pinned: &PinnedVersions,
+    retention: Retention,
+    operators: &MergeOperators,
+) -> Result<()> {
+    let started = Instant::now();
+    let (storage, plan, keyspaces, pinned) = {
+        let mut writer = writer.lock().expect("mutex not poisoned");
+        let plan = match writer.plan_compaction()? {
+            Some(plan) => plan,
+            None => return Ok(()),
+        };
+        // the records of a keyspace dropped before now are left behind
+        let keyspaces: Vec<_> = keyspaces
+            .read()
+            .expect("lock not poisoned")
+            .values()
+            .map(|keyspace| (Arc::clone(keyspace), versions(keyspace, &plan.run)))
+            .collect();
+        // a snapshot taken from now on reads the newest versions, which are kept anyway
+        let pinned = pinned.pinned();
+
+        (writer.storage(), plan, keyspaces, pinned)
+    };
+    let in_run = |location: &KvStoreValue| plan.run.contains(&location.0);
+
+    let comp_log_name = format!("{}.{}", plan.comp_gen, "log");
+    let comp_name = format!("{}.{}", comp_log_name, COMPACTING_EXTENSION);
+    let mut comp_writer = LogWriter::new(storage.create(&comp_name)?)?;
+    let records_start = comp_writer.pos;
+
+    let mut comp_hints = Vec::new();
+    for (name, seq) in &plan.drops {
+        let pos = comp_writer.pos;
+        let len = write_record(&mut comp_writer, *seq, name, &LogCommand::DropKeyspace)?;
+        comp_hints.push(HintEntry {
+            keyspace: name.clone(),
+            key: Vec::new(),
+            seq: *seq,
+            pos,
+            len,
+            expires_at: None,
+            removed: false,
+            dropped: true,
+            range_end: None,
+        });
+    }
+
+    let now = now_millis();
+    // by key as well, a range tombstone is a version of many keys
+    let mut moved: HashMap<Vec<u8>, HashMap<KvStoreValue, KvStoreValue>> = HashMap::new();
+    for (keyspace, versions) in &keyspaces {
+        for (key, locations) in versions {
+            let kept = kept_versions(
+                readers, operators, retention, &pinned, now, key, locations, &in_run,
+            )?;
+
+            // a key which is gone for good needs no records at all,
+            // unless the older segments have records of it which would come back
+            let gone = match kept.first() {
+                Some((_, _, LogCommand::Insert { expires_at, .. })) => is_expired(*expires_at, now),
+                Some((_, _, LogCommand::Merge { .. })) => false,
+                _ => true,
+            };
+            let shadows = locations
+                .iter()
+                .any(|location| plan.older.contains(&location.0));
+            if gone && kept.len() <= 1 && !shadows {
+                continue;
+            }
+
+            for (location, seq, command) in kept.into_iter().rev() {
+                // the versions in the other segments stay where they are
+                if !in_run(location) {
+                    continue;
+                }
+                // the records are grouped by key, a range tombstone would remove
+                // the newer versions of the keys after it, only its removal of this key is kept
+                let command = match command {
+                    LogCommand::DeleteRange { .. } => LogCommand::Remove { key: key.clone() },
+                    command => command,
+                };
+                let pos = comp_writer.pos;
+                let len = write_record(&mut comp_writer, seq, &keyspace.name, &command)?;
+
+                comp_hints.push(HintEntry {
+                    keyspace: keyspace.name.clone(),
+                    key: key.clone(),
+                    seq,
+                    pos,
+                    len,
+                    expires_at: location.3,
+                    removed: match command {
+                        LogCommand::Remove { .. } => true,
+                        _ => false,
+                    },
+                    dropped: false,
+                    range_end: None,
+                });
+                let new_location = (
+                    comp_log_name.clone(),
+                    pos as usize,
+                    len as usize,
+                    location.3,
+                );
+                moved
+                    .entry(key.clone())
+                    .or_insert_with(HashMap::new)
+                    .insert(location.clone(), new_location);
+            }
+        }
+    }
+    comp_writer.flush()?;
+    comp_writer.get_ref().sync()?;
+    storage.rename(&comp_name, &comp_log_name)?;
+    storage.sync()?;
+    write_hint_file(
+        storage.as_ref(),
+        &comp_log_name,
+        comp_writer.pos,
+        &comp_hints,
+    )?;
+    // from here on a crash leaves the new segment in place of the ones it replaces
+    writer
+        .lock()
+        .expect("mutex not poisoned")
+        .install_compacted(&plan)?;
+
+    // records left out of the new segment are gone,
+    // the ones written while copying already are in a newer segment and stay as they are
+    let moved_to = |key: &[u8], location: &KvStoreValue| {
+        moved
+            .get(key)
+            .and_then(|locations| locations.get(location))
+            .cloned()
+    };
+    let mut live = 0;
+    for (keyspace, _) in &keyspaces {
+        let mut index = keyspace.index.write().expect("lock not poisoned");
+        let mut history = keyspace.history.write().expect("lock not poisoned");
+
+        let mut gone = Vec::new();
+        for (key, location) in index.iter_mut() {
+            if in_run(location) {
+                match moved_to(key, location) {
+                    Some(new_location) => {
+                        live += new_location.2;
+                        *location = new_location;
+                    }
+                    // expired, the sweeper could be yet to get to it
+                    None => gone.push(key.to_owned()),
+                }
+            }
+        }
+        for key in gone {
+            index.remove(&key);
+        }
+
+        history.retain(|key, superseded| {
+            superseded.retain(|location| !in_run(location) || moved_to(key, location).is_some());
+            for location in superseded.iter_mut() {
+                if let Some(new_location) = moved_to(key, location) {
+                    *location = new_location;
+                }
+            }
+
+            !superseded.is_empty()
+        });
+    }
+    readers.forget_compacted();
+    writer.lock().expect("mutex not poisoned").record_compacted(
+        &plan,
+        comp_writer.pos as usize,
+        (comp_writer.pos - records_start) as usize - live,
+        started.elapsed(),
+    );
+
+    retired.retire(&storage, plan.run.into_iter().collect())
+}
+
+/// the versions of a key `retention` keeps and the ones the snapshots as of the `pinned`
+/// sequence numbers read, newest first, operands are folded into a value
+/// so the versions before them can go, a key with operands which can't be folded keeps all,
+/// so does a key with an operand outside of the run as its value is made of the run's records
+fn kept_versions<'a, F>(
+    readers: &mut KvStoreReaders,
+    operators: &MergeOperators,
+    retention: Retention,
+    pinned: &[u64],
+    now: u64,
+    key: &[u8],
+    locations: &'a [KvStoreValue],
+    in_run: F,
+) -> Result<Vec<(&'a KvStoreValue, u64, LogCommand)>>
+where
+    F: Fn(&KvStoreValue) -> bool,
+{
+    // the pinned sequence numbers older than the versions seen so far
+    let mut pinned = pinned.to_vec();
+    let mut kept = Vec::new();
+    for (nth, (i, location)) in locations.iter().enumerate().rev().enumerate() {
+        let (seq, command) = readers.read_command(location)?;
+        // the newest version at or before a snapshot is the one it reads
+        let mut read = false;
+        while pinned.last().map_or(false, |pinned| *pinned >= seq) {
+            pinned.pop();
+            read = true;
+        }
+
+        if read || retention.keeps(nth, version_millis(seq), now) {
+            kept.push((i, location, seq, command));
+        } else if pinned.is_empty() {
+            break;
+        }
+    }
+
+    let outside_operand = kept.iter().any(|(_, location, _, command)| match command {
+        LogCommand::Merge { .. } => !in_run(location),
+        _ => false,
+    });
+    if outside_operand {
+        return all_versions(readers, locations);
+    }
+
+    for (i, _, seq, command) in kept.iter_mut() {
+        if let LogCommand::Merge { .. } = command {
+            let folded = fold(
+                key,
+                &locations[..=*i],
+                operators,
+                version_millis(*seq),
+                |location| readers.read_command(location),
+            );
+            *command = match folded {
+                Ok(Some(value)) => LogCommand::Insert {
+                    key: key.to_vec(),
+                    value,
+                    expires_at: None,
+                },
+                Ok(None) => LogCommand::Remove { key: key.to_vec() },
+                Err(Error::MergeFailed) | Err(Error::UnknownMergeOperator) => {
+                    return all_versions(readers, locations);
+                }
+                Err(e) => return Err(e),
+            };
+        }
+    }
+
+    Ok(kept
+        .into_iter()
+        .map(|(_, location, seq, command)| (location, seq, command))
+        .collect())
+}
+
+/// every version of a key as it was written, newest first
+fn all_versions<'a>(
+    readers: &mut KvStoreReaders,
+    locations: &'a [KvStoreValue],
+) -> Result<Vec<(&'a KvStoreValue, u64, LogCommand)>> {
+    locations
+        .iter()
+        .rev()
+        .map(|location| {
+            let (seq, command) = readers.read_command(location)?;
+            Ok((location, seq, command))
+        })
+        .collect()
+}
+
+/// every version of the keys of the keyspace with a record in the run, oldest first
+fn versions(keyspace: &Keyspace, run: &HashSet<String>) -> BTreeMap<Vec<u8>, Vec<KvStoreValue>> {
+    let index = keyspace.index.read().expect("lock not poisoned");
+    let history = keyspace.history.read().expect("lock not poisoned");
+
+    let mut versions: BTreeMap<Vec<u8>, Vec<KvStoreValue>> = history
+        .iter()
+        .map(|(key, superseded)| (key.to_owned(), superseded.clone()))
+        .collect();
+    for (key, location) in index.iter() {
+        versions
+            .entry(key.to_owned())
+            .or_insert_with(Vec::new)
+            .push(location.clone());
+    }
+    versions.retain(|_, locations| locations.iter().any(|location| run.contains(&location.0)));
+
+    versions
+}
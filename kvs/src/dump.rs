@@ -0,0 +1,165 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::iter::once;
+use std::time::Duration;
+
+use crate::{Error, KvsEngine, Result, WriteBatch};
+
+/// how many records of a keyspace an import writes at once
+const IMPORT_BATCH_LEN: usize = 1024;
+
+/// a line of a dump, the format of `export` and `import` is JSON Lines with a record per key:
+///
+/// ```text
+/// {"keyspace":"users","key":"alice","value":"1","ttl_millis":5000,"version":1589000000000000}
+/// {"key_hex":"00ff","value_hex":"fe01"}
+/// ```
+///
+/// `keyspace` is left out for the default keyspace, a key or a value which isn't UTF-8
+/// goes in `key_hex` or `value_hex` instead, `ttl_millis` is the time the key had left
+/// and is left out for a key which never expires, `version` is the one the key had
+/// in the exported store, the import writes new versions
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct DumpRecord {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    keyspace: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    key_hex: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    value_hex: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ttl_millis: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<u64>,
+}
+
+/// writes every key of every keyspace of `engine`, a handle to the default keyspace,
+/// and returns how many, the keys written meanwhile may or may not make it,
+/// a snapshot is exported as of the moment it was taken
+pub fn export<E: KvsEngine, W: Write>(engine: &E, writer: W) -> Result<u64> {
+    let mut writer = BufWriter::new(writer);
+    let mut count = 0;
+    for keyspace in once(String::new()).chain(engine.tree_names()?) {
+        let handle = match keyspace.as_str() {
+            "" => engine.clone(),
+            name => engine.open_tree(name)?,
+        };
+
+        for pair in handle.scan(.., None)? {
+            let (key, value) = pair?;
+            let ttl = match handle.ttl(key.clone()) {
+                Ok(ttl) => ttl,
+                // removed or expired since the scan read it
+                Err(Error::KeyNotFound) => continue,
+                Err(e) => return Err(e),
+            };
+            let version = handle
+                .get_versions(key.clone(), 1)?
+                .first()
+                .map(|(version, _)| *version);
+
+            let (key, key_hex) = encode_bytes(key);
+            let (value, value_hex) = encode_bytes(value);
+            let record = DumpRecord {
+                keyspace: keyspace.clone(),
+                key,
+                key_hex,
+                value,
+                value_hex,
+                ttl_millis: ttl.map(|ttl| ttl.as_millis() as u64),
+                version,
+            };
+            serde_json::to_writer(&mut writer, &record)?;
+            writer.write_all(b"\n")?;
+            count += 1;
+        }
+    }
+    writer.flush()?;
+
+    Ok(count)
+}
+
+/// writes the records read from `reader` into `engine`, a handle to the default keyspace,
+/// and returns how many, the records go in batches which are made durable at once
+/// instead of one by one, the batches written before a bad record stay
+pub fn import<E: KvsEngine, R: Read>(engine: &E, reader: R) -> Result<u64> {
+    let mut handle: Option<(String, E)> = None;
+    let mut batch = WriteBatch::new();
+    let mut count = 0;
+    for record in Deserializer::from_reader(BufReader::new(reader)).into_iter::<DumpRecord>() {
+        let record = record?;
+        let key = decode_bytes(record.key, record.key_hex)?;
+        let value = decode_bytes(record.value, record.value_hex)?;
+
+        let same_keyspace = match &handle {
+            Some((keyspace, _)) => *keyspace == record.keyspace,
+            None => false,
+        };
+        if !same_keyspace {
+            if let Some((_, handle)) = &handle {
+                handle.write_batch(std::mem::take(&mut batch))?;
+            }
+            let next = match record.keyspace.as_str() {
+                "" => engine.clone(),
+                name => engine.open_tree(name)?,
+            };
+            handle = Some((record.keyspace, next));
+        }
+
+        match record.ttl_millis {
+            Some(ttl) => batch.put_with_ttl(key, value, Duration::from_millis(ttl)),
+            None => batch.put(key, value),
+        };
+        count += 1;
+
+        if batch.len() >= IMPORT_BATCH_LEN {
+            if let Some((_, handle)) = &handle {
+                handle.write_batch(std::mem::take(&mut batch))?;
+            }
+        }
+    }
+    if let Some((_, handle)) = &handle {
+        handle.write_batch(batch)?;
+    }
+
+    Ok(count)
+}
+
+/// the bytes as a string if they are UTF-8, as hex otherwise
+fn encode_bytes(bytes: Vec<u8>) -> (Option<String>, Option<String>) {
+    match String::from_utf8(bytes) {
+        Ok(string) => (Some(string), None),
+        Err(e) => {
+            let hex = e
+                .as_bytes()
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect();
+            (None, Some(hex))
+        }
+    }
+}
+
+fn decode_bytes(string: Option<String>, hex: Option<String>) -> Result<Vec<u8>> {
+    match (string, hex) {
+        (Some(string), None) => Ok(string.into_bytes()),
+        (None, Some(hex)) if hex.len() % 2 == 0 && hex.is_ascii() => (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<std::result::Result<_, _>>()
+            .map_err(|_| invalid_record("bad hex")),
+        _ => Err(invalid_record(
+            "a record needs exactly one of a string and a hex key and value",
+        )),
+    }
+}
+
+fn invalid_record(msg: &str) -> Error {
+    Error::Serialization(serde::de::Error::custom(msg))
+}
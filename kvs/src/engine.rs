@@ -1,9 +1,150 @@
-use crate::Result;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::time::Duration;
 
-pub trait KvsEngine {
-    fn get(&mut self, key: String) -> Result<Option<String>>;
+use crate::{EngineStats, Error, Result, WriteBatch};
 
-    fn set(&mut self, key: String, value: String) -> Result<()>;
+/// `(key, value)` pairs of a scan in ascending key order
+pub type ScanIter = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>>>;
 
-    fn remove(&mut self, key: String) -> Result<()>;
+/// a storage engine, clones are handles to the same storage
+/// which can be used from many threads at once,
+/// keys and values are arbitrary bytes
+pub trait KvsEngine: Clone + Send + Sync + 'static {
+    /// `None` for a missing or expired key
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+
+    /// `set` of a key which disappears after `ttl`
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+
+    fn remove(&self, key: Vec<u8>) -> Result<()>;
+
+    /// merges `operand` into the value of `key` with the merge operator named `operator`
+    /// and returns the value it makes, the key loses its expiry like with `set`,
+    /// fails with `Error::MergeFailed` and leaves the key alone
+    /// for an operand the operator can't combine with the value
+    fn merge(&self, key: Vec<u8>, operator: &str, operand: Vec<u8>) -> Result<Vec<u8>>;
+
+    /// removes the keys from `start` up to but excluding `end` at once,
+    /// an empty `end` leaves the range open
+    fn delete_range(&self, start: Vec<u8>, end: Vec<u8>) -> Result<()>;
+
+    /// removes the keys starting with `prefix` at once
+    fn delete_prefix(&self, prefix: Vec<u8>) -> Result<()> {
+        let end = prefix_end(&prefix);
+        self.delete_range(prefix, end)
+    }
+
+    /// the last `n` versions of `key` the retention kept, newest first,
+    /// a `None` value is a removal
+    fn get_versions(&self, key: Vec<u8>, n: usize) -> Result<Vec<(u64, Option<Vec<u8>>)>>;
+
+    /// the value `key` had right after the write of `version`
+    fn get_at(&self, key: Vec<u8>, version: u64) -> Result<Option<Vec<u8>>>;
+
+    /// the time `key` has left, `None` for a key which never expires,
+    /// fails with `Error::KeyNotFound` for a missing one
+    fn ttl(&self, key: Vec<u8>) -> Result<Option<Duration>>;
+
+    /// applies every put and delete of `batch` or none of them
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// replaces the value of `key` with `new` if it's still `expected`, `None` is a missing key,
+    /// fails with `Error::ConditionFailed` holding the current value otherwise
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()>;
+
+    /// `set` of a key which isn't there yet
+    fn set_if_absent(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+
+    /// `remove` of a key which still holds `expected`
+    fn remove_if_equals(&self, key: Vec<u8>, expected: Vec<u8>) -> Result<()> {
+        self.compare_and_swap(key, Some(expected), None)
+    }
+
+    /// a read-only handle which sees the data as of now while writers go on,
+    /// its writes fail with `Error::ReadOnly`
+    fn snapshot(&self) -> Result<Self>;
+
+    /// pairs with keys in `range`, at most `limit` of them
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: Option<usize>) -> Result<ScanIter>;
+
+    /// pairs with keys starting with `prefix`
+    fn scan_prefix(&self, prefix: Vec<u8>) -> Result<ScanIter>;
+
+    /// a handle to the keyspace `name` of the same store, it's created on first use,
+    /// keys of different keyspaces never collide
+    fn open_tree(&self, name: &str) -> Result<Self>;
+
+    /// removes the keyspace `name` with all of its keys at once, the handles to it
+    /// see it empty afterwards and their writes fail with `Error::KeyspaceDropped`,
+    /// so do the point reads of the sled engine
+    fn drop_tree(&self, name: &str) -> Result<()>;
+
+    /// names of the keyspaces besides the default one in ascending order
+    fn tree_names(&self) -> Result<Vec<String>>;
+
+    /// figures of the whole store, whichever keyspace the handle is for
+    fn stats(&self) -> Result<EngineStats>;
+
+    /// writes a consistent copy of the whole store into the new directory `dest`
+    /// while reads and writes go on, opening `dest` restores it
+    fn checkpoint(&self, dest: &Path) -> Result<()>;
+
+    /// `get` for a value known to be a string, fails with `Error::NotUtf8` otherwise
+    fn get_string(&self, key: String) -> Result<Option<String>> {
+        match self.get(key.into_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value).map_err(|_| Error::NotUtf8)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn set_string(&self, key: String, value: String) -> Result<()> {
+        self.set(key.into_bytes(), value.into_bytes())
+    }
+
+    fn remove_string(&self, key: String) -> Result<()> {
+        self.remove(key.into_bytes())
+    }
+}
+
+/// the default keyspace has no name and the names starting with `__` are kept for the engines
+pub(crate) fn check_keyspace_name(name: &str) -> Result<()> {
+    if name.is_empty() || name.len() > 255 || name.starts_with("__") {
+        return Err(Error::InvalidKeyspace);
+    }
+
+    Ok(())
+}
+
+/// the bounds of the keys `delete_range` removes
+pub(crate) fn delete_range_bounds(
+    start: Vec<u8>,
+    end: Vec<u8>,
+) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    match end.len() {
+        0 => (Bound::Included(start), Bound::Unbounded),
+        _ => (Bound::Included(start), Bound::Excluded(end)),
+    }
+}
+
+/// the first key past every key starting with `prefix`, empty if there is none
+fn prefix_end(prefix: &[u8]) -> Vec<u8> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            break;
+        }
+    }
+
+    end
 }
@@ -1,44 +1,131 @@
+use std::fmt;
+use std::io;
+
+use crate::log::LogError;
+
 #[derive(Debug)]
 pub enum Error {
-    LogOpen(String),
+    /// the storage failed, the kind of the source tells a full disk from a permission problem
+    Io(io::Error),
+    /// the record at `offset` of `segment` doesn't match its checksum or is cut off
+    Corruption {
+        segment: String,
+        offset: u64,
+    },
+    /// a record of a legacy json segment couldn't be decoded
+    Serialization(serde_json::Error),
+    /// sled failed
+    Engine(sled::Error),
+    /// another process has the store open, `pid` is the writer's one if it's known
+    Locked {
+        pid: Option<u32>,
+    },
+    ReadOnly,
+    /// a key or value asked for as a `String` holds other bytes
+    NotUtf8,
     KeyNotFound,
-    LogReaderNotFound,
-    InsertError,
-    RemoveError,
+    /// the value of a conditional write wasn't the expected one, `None` for a missing key
+    ConditionFailed {
+        current: Option<Vec<u8>>,
+    },
+    /// a keyspace name which is empty, longer than 255 bytes or starts with `__`
+    InvalidKeyspace,
+    /// the keyspace of the handle was dropped
+    KeyspaceDropped,
+    /// no merge operator is registered under the name
+    UnknownMergeOperator,
+    /// a merge operator name which is longer than 255 bytes
+    InvalidMergeOperator,
+    /// a merge operator can't combine an operand with the value
+    MergeFailed,
+    /// the engine can't do it, e.g. a checkpoint of the memory engine
+    Unsupported,
 }
 
-impl From<std::io::Error> for Error {
-    fn from(_: std::io::Error) -> Self {
-        Error::LogOpen("error while trying to open a log".to_owned())
+impl Error {
+    /// attaches the name of the segment a `LogError` came from
+    pub(crate) fn from_log(log_name: &str, err: LogError) -> Self {
+        match err {
+            LogError::Corrupted { pos } | LogError::Truncated { pos } => Error::Corruption {
+                segment: log_name.to_owned(),
+                offset: pos,
+            },
+            LogError::Io(e) => e.into(),
+            LogError::Json(e) => e.into(),
+        }
     }
 }
 
-impl From<std::str::Utf8Error> for Error {
-    fn from(_: std::str::Utf8Error) -> Self {
-        Error::LogOpen("error while trying to deserialize a log".to_owned())
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "io error: {}", e),
+            Error::Corruption { segment, offset } => write!(
+                f,
+                "corrupted record at offset {} of segment {}",
+                offset, segment
+            ),
+            Error::Serialization(e) => write!(f, "serialization error: {}", e),
+            Error::Engine(e) => write!(f, "sled error: {}", e),
+            Error::Locked { pid: Some(pid) } => write!(f, "the store is locked by process {}", pid),
+            Error::Locked { pid: None } => write!(f, "the store is locked by another process"),
+            Error::ReadOnly => write!(f, "the store is read-only"),
+            Error::NotUtf8 => write!(f, "not valid UTF-8"),
+            Error::KeyNotFound => write!(f, "key not found"),
+            Error::ConditionFailed { .. } => write!(f, "the value is not the expected one"),
+            Error::InvalidKeyspace => write!(f, "invalid keyspace name"),
+            Error::KeyspaceDropped => write!(f, "the keyspace was dropped"),
+            Error::UnknownMergeOperator => write!(f, "unknown merge operator"),
+            Error::InvalidMergeOperator => write!(f, "invalid merge operator name"),
+            Error::MergeFailed => write!(f, "the operand can't be merged into the value"),
+            Error::Unsupported => write!(f, "the engine doesn't support it"),
+        }
     }
 }
 
-impl From<serde_json::error::Error> for Error {
-    fn from(_: serde_json::error::Error) -> Self {
-        Error::LogOpen("error while trying to deserialize a log".to_owned())
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            Error::Serialization(e) => Some(e),
+            Error::Engine(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
     }
 }
 
-impl From<std::num::ParseIntError> for Error {
-    fn from(_: std::num::ParseIntError) -> Self {
-        Error::LogOpen("error in probably while fetching number from log name".to_owned())
+impl From<std::str::Utf8Error> for Error {
+    fn from(_: std::str::Utf8Error) -> Self {
+        Error::NotUtf8
     }
 }
 
-impl From<std::net::AddrParseError> for Error {
-    fn from(_: std::net::AddrParseError) -> Self {
-        Error::LogOpen("error during parsing address".to_owned())
+impl From<serde_json::error::Error> for Error {
+    fn from(e: serde_json::error::Error) -> Self {
+        Error::Serialization(e)
     }
 }
 
 impl From<sled::Error> for Error {
-    fn from(_: sled::Error) -> Self {
-        Error::LogOpen("error from SLED side".to_owned())
+    fn from(e: sled::Error) -> Self {
+        Error::Engine(e)
+    }
+}
+
+/// the transactions of the sled engine never abort on purpose
+impl<E> From<sled::TransactionError<E>> for Error {
+    fn from(e: sled::TransactionError<E>) -> Self {
+        match e {
+            sled::TransactionError::Storage(e) => Error::Engine(e),
+            sled::TransactionError::Abort(_) => {
+                Error::Engine(sled::Error::Unsupported("transaction aborted".to_owned()))
+            }
+        }
     }
 }
@@ -0,0 +1,190 @@
+use crate::error::Error;
+use crate::storage::Storage;
+use std::io::{self, Read, Write};
+
+const HINT_FILE_EXTENSION_NAME: &'static str = "hint";
+/// hints of other versions are ignored and their segments replayed instead
+const HINT_MAGIC: &'static [u8; 8] = b"KVSHINT4";
+
+// magic | segment len | entries count
+const HINT_HEADER_LEN: usize = 8 + 8 + 8;
+// kind | seq | pos | len | expires at, 0 for never | key len | keyspace len
+const HINT_ENTRY_HEADER_LEN: usize = 1 + 8 + 8 + 8 + 8 + 4 + 1;
+
+const HINT_KIND_INSERT: u8 = 0;
+const HINT_KIND_REMOVE: u8 = 1;
+const HINT_KIND_KEYSPACE_DROP: u8 = 2;
+/// the key is the start of the range, the end follows the keyspace with its u32 length first
+const HINT_KIND_DELETE_RANGE: u8 = 3;
+
+/// where a record of a sealed segment lives, without its value
+#[derive(Debug, Clone, PartialEq)]
+pub struct HintEntry {
+    /// empty for the default keyspace
+    pub keyspace: String,
+    pub key: Vec<u8>,
+    pub seq: u64,
+    pub pos: u64,
+    pub len: u64,
+    pub expires_at: Option<u64>,
+    pub removed: bool,
+    /// the whole keyspace was dropped, `key` is empty
+    pub dropped: bool,
+    /// `Some` for a range tombstone from `key` up to it, an empty end leaves the range open
+    pub range_end: Option<Vec<u8>>,
+}
+
+/// `<gen>.hint` for `<gen>.log`
+pub fn hint_file_name(log_name: &str) -> String {
+    format!(
+        "{}.{}",
+        log_name.trim_end_matches(".log"),
+        HINT_FILE_EXTENSION_NAME
+    )
+}
+
+/// atomically replaces the hint file of `log_name`,
+/// `segment_len` ties the hint to the exact content of the segment
+pub fn write_hint_file(
+    storage: &dyn Storage,
+    log_name: &str,
+    segment_len: u64,
+    entries: &[HintEntry],
+) -> Result<(), Error> {
+    let mut buf = Vec::with_capacity(HINT_HEADER_LEN + entries.len() * HINT_ENTRY_HEADER_LEN);
+    buf.extend_from_slice(HINT_MAGIC);
+    buf.extend_from_slice(&segment_len.to_le_bytes());
+    buf.extend_from_slice(&(entries.len() as u64).to_le_bytes());
+
+    for entry in entries {
+        buf.push(match (entry.removed, entry.dropped, &entry.range_end) {
+            (_, _, Some(_)) => HINT_KIND_DELETE_RANGE,
+            (_, true, _) => HINT_KIND_KEYSPACE_DROP,
+            (true, _, _) => HINT_KIND_REMOVE,
+            _ => HINT_KIND_INSERT,
+        });
+        buf.extend_from_slice(&entry.seq.to_le_bytes());
+        buf.extend_from_slice(&entry.pos.to_le_bytes());
+        buf.extend_from_slice(&entry.len.to_le_bytes());
+        buf.extend_from_slice(&entry.expires_at.unwrap_or(0).to_le_bytes());
+        buf.extend_from_slice(&(entry.key.len() as u32).to_le_bytes());
+        buf.push(entry.keyspace.len() as u8);
+        buf.extend_from_slice(&entry.key);
+        buf.extend_from_slice(entry.keyspace.as_bytes());
+        if let Some(range_end) = &entry.range_end {
+            buf.extend_from_slice(&(range_end.len() as u32).to_le_bytes());
+            buf.extend_from_slice(range_end);
+        }
+    }
+
+    let crc = crc32fast::hash(&buf);
+    buf.extend_from_slice(&crc.to_le_bytes());
+
+    let name = hint_file_name(log_name);
+    let tmp_name = format!("{}.tmp", name);
+    let mut file = storage.create(&tmp_name)?;
+    file.write_all(&buf)?;
+    file.sync()?;
+    storage.rename(&tmp_name, &name)?;
+
+    Ok(())
+}
+
+/// loads the hint file of `log_name`,
+/// `None` if it's missing, damaged or was written for different segment content
+pub fn read_hint_file(
+    storage: &dyn Storage,
+    log_name: &str,
+    segment_len: u64,
+) -> Result<Option<Vec<HintEntry>>, Error> {
+    let mut buf = Vec::new();
+    match storage.open(&hint_file_name(log_name)) {
+        Ok(mut file) => file.read_to_end(&mut buf)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+
+    Ok(decode_hints(&buf, segment_len))
+}
+
+fn decode_hints(buf: &[u8], segment_len: u64) -> Option<Vec<HintEntry>> {
+    if buf.len() < HINT_HEADER_LEN + 4 || &buf[..8] != &HINT_MAGIC[..] {
+        return None;
+    }
+
+    let (content, crc) = buf.split_at(buf.len() - 4);
+    if crc32fast::hash(content) != read_u32(crc, 0) {
+        return None;
+    }
+    if read_u64(content, 8) != segment_len {
+        return None;
+    }
+
+    let count = read_u64(content, 16) as usize;
+    let mut entries = Vec::with_capacity(count.min(content.len() / HINT_ENTRY_HEADER_LEN));
+    let mut i = HINT_HEADER_LEN;
+    for _ in 0..count {
+        if i + HINT_ENTRY_HEADER_LEN > content.len() {
+            return None;
+        }
+        let kind = content[i];
+        let seq = read_u64(content, i + 1);
+        let pos = read_u64(content, i + 9);
+        let len = read_u64(content, i + 17);
+        let expires_at = Some(read_u64(content, i + 25)).filter(|&at| at != 0);
+        let key_len = read_u32(content, i + 33) as usize;
+        let keyspace_len = content[i + 37] as usize;
+        i += HINT_ENTRY_HEADER_LEN;
+
+        if i + key_len + keyspace_len > content.len() {
+            return None;
+        }
+        let key = content[i..i + key_len].to_vec();
+        i += key_len;
+        let keyspace = String::from_utf8(content[i..i + keyspace_len].to_vec()).ok()?;
+        i += keyspace_len;
+
+        let range_end = match kind {
+            HINT_KIND_DELETE_RANGE => {
+                if i + 4 > content.len() {
+                    return None;
+                }
+                let end_len = read_u32(content, i) as usize;
+                i += 4;
+                if i + end_len > content.len() {
+                    return None;
+                }
+                let range_end = content[i..i + end_len].to_vec();
+                i += end_len;
+                Some(range_end)
+            }
+            _ => None,
+        };
+
+        entries.push(HintEntry {
+            keyspace,
+            key,
+            seq,
+            pos,
+            len,
+            expires_at,
+            removed: kind == HINT_KIND_REMOVE,
+            dropped: kind == HINT_KIND_KEYSPACE_DROP,
+            range_end,
+        });
+    }
+
+    Some(entries)
+}
+
+pub(crate) fn read_u32(buf: &[u8], at: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes.copy_from_slice(&buf[at..at + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+pub(crate) fn read_u64(buf: &[u8], at: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&buf[at..at + 8]);
+    u64::from_le_bytes(bytes)
+}
@@ -1,283 +1,2246 @@
 use serde_json::Deserializer;
 
-use std::collections::HashMap;
-use std::ffi::OsString;
-use std::fs::{read_dir, File, ReadDir};
-use std::io::{copy, Read, Seek, SeekFrom, Write};
-use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::convert::TryFrom;
+use std::io::{self, copy, Read, Seek, SeekFrom, Write};
+use std::iter::once;
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use crate::error::Error;
 use crate::{
-    log::{create_log_file, LogCommand, LogReader, LogWriter},
-    KvsEngine,
+    compaction::{
+        CompactionPlan, Compactor, PinnedVersions, RetiredSegments, SegmentPin, SnapshotPin,
+    },
+    engine::{check_keyspace_name, delete_range_bounds, ScanIter},
+    hint::{hint_file_name, read_hint_file, write_hint_file, HintEntry},
+    lock::DirLock,
+    log::{
+        create_log_file, scan_damaged_tail, write_record, JsonLogCommand, LogCommand, LogError,
+        LogFormat, LogReader, LogRecord, LogWriter,
+    },
+    manifest::Manifest,
+    merge::{builtin_operators, MergeOperators},
+    options::{Durability, Options},
+    periodic::Periodic,
+    storage::{DiskStorage, Storage, StorageFile},
+    EngineStats, KvsEngine, SegmentStats, WriteBatch,
 };
 
 pub type Result<T> = std::result::Result<T, Error>;
 
-const COMPACTION_THRESHOLD: usize = 1024 * 1024;
+pub const COMPACTION_THRESHOLD: usize = 1024 * 1024;
+pub const MAX_SEGMENT_SIZE: usize = 64 * 1024 * 1024;
+pub const GARBAGE_RATIO: f64 = 0.5;
+const QUARANTINE_EXTENSION: &'static str = "corrupt";
+/// how many pairs a scan reads under one lock of the index
+const SCAN_BATCH_LEN: usize = 128;
+/// how many times a read-only open reads the manifest again when a compaction races it
+const READ_ONLY_OPEN_ATTEMPTS: usize = 3;
 
 type LogName = String;
 type PositionInLog = usize;
 type LenInLog = usize;
-pub type KvStoreValue = (LogName, PositionInLog, LenInLog);
+/// unix time in milliseconds after which the key is gone
+type ExpiresAt = Option<u64>;
+pub type KvStoreValue = (LogName, PositionInLog, LenInLog, ExpiresAt);
+
+pub(crate) type KeyDir = BTreeMap<Vec<u8>, KvStoreValue>;
+/// superseded records of every key, oldest first, removals included,
+/// ordered so a snapshot finds the keys removed since it was taken
+pub(crate) type History = BTreeMap<Vec<u8>, Vec<KvStoreValue>>;
+/// keys with an expiry ordered by it along with their keyspace,
+/// entries of keys written again since are stale
+pub(crate) type ExpiryQueue = BTreeSet<(u64, String, Vec<u8>)>;
+/// keyspaces of a store by name, the default one is named ""
+pub(crate) type Keyspaces = BTreeMap<String, Arc<Keyspace>>;
+
+/// the records of a single keyspace, the keyspaces of a store share its segments
+#[derive(Debug, Default)]
+pub(crate) struct Keyspace {
+    pub(crate) name: String,
+    pub(crate) index: RwLock<KeyDir>,
+    pub(crate) history: RwLock<History>,
+}
+
+impl Keyspace {
+    fn new(name: &str) -> Self {
+        Keyspace {
+            name: name.to_owned(),
+            ..Keyspace::default()
+        }
+    }
+
+    /// counts the current values as stale once the keyspace is dropped,
+    /// the rest of its records already are
+    fn mark_dropped(&self, usage: &mut SegmentsUsage) {
+        for location in self.index.read().expect("lock not poisoned").values() {
+            add_stale(usage, &location.0, location.2);
+        }
+    }
+}
+
+/// size and stale bytes of a segment, a compaction goes for the segments which are mostly stale
+#[derive(Debug, Default, Clone)]
+struct SegmentUsage {
+    /// unknown for the session log until it's sealed
+    len: usize,
+    stale: usize,
+    /// keyspaces dropped in the segment with the version of the drop,
+    /// the drop has to outlive the older segments which hold their records
+    drops: Vec<(String, u64)>,
+}
+
+/// usage of the live segments by name
+type SegmentsUsage = HashMap<LogName, SegmentUsage>;
+
+fn add_stale(usage: &mut SegmentsUsage, log_name: &str, len: usize) {
+    usage.entry(log_name.to_owned()).or_default().stale += len;
+}
+
+fn add_drop(usage: &mut SegmentsUsage, log_name: &str, keyspace: &str, seq: u64, len: usize) {
+    let usage = usage.entry(log_name.to_owned()).or_default();
+    usage.stale += len;
+    usage.drops.push((keyspace.to_owned(), seq));
+}
+
+/// what to do with a segment which is damaged anywhere but at the tail of the newest one
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RecoveryMode {
+    /// fail `open` with `Error::Corruption`
+    Refuse,
+    /// rename the segment to `<name>.corrupt` and open without it
+    Quarantine,
+}
+
+/// outcome of replaying the log files on `open`
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RecoveryReport {
+    pub records_kept: usize,
+    pub records_discarded: usize,
+    pub truncated_bytes: u64,
+    pub quarantined: Vec<String>,
+}
+
+/// end of the valid data in a segment
+struct SegmentDamage {
+    pos: u64,
+    /// no valid record follows the damage, i.e. a write was cut off
+    torn: bool,
+    /// the records from `pos` on
+    records: usize,
+}
 
 // #[derive(Default)]
-/// key-value storage model
-#[derive(Debug)]
+/// key-value storage model, clones share the same store
+#[derive(Debug, Clone)]
 pub struct KvStore {
-    store: HashMap<String, KvStoreValue>,
-    readers: HashMap<String, LogReader<std::fs::File>>,
-    writer: LogWriter<std::fs::File>,
-    session_log_name: String,
-    path: PathBuf,
-    uncompacted: usize,
+    /// the keyspace the handle reads and writes
+    keyspace: Arc<Keyspace>,
+    keyspaces: Arc<RwLock<Keyspaces>>,
+    readers: Arc<ReadersPool>,
+    operators: Arc<MergeOperators>,
+    /// `None` for a read-only store
+    compactor: Option<Arc<Compactor>>,
+    /// `Some` only for `Durability::EveryNMillis`
+    syncer: Option<Arc<Periodic>>,
+    sweeper: Option<Arc<Periodic>>,
+    writer: Option<Arc<Mutex<KvStoreWriter>>>,
+    recovery: Arc<RecoveryReport>,
+    /// `None` for a store which isn't on the disk or is read-only
+    lock: Option<Arc<DirLock>>,
+    retired: Arc<RetiredSegments>,
+    /// the sequence numbers the live snapshots read as of
+    pinned: Arc<PinnedVersions>,
+    /// `Some` for a snapshot
+    snapshot: Option<Arc<SnapshotPin>>,
 }
 
 impl KvsEngine for KvStore {
     /// get value by key
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        if let Some((log_name, position_in_log, len_in_log)) = self.store.get(&key) {
-            if let Some(reader) = self.readers.get_mut(log_name) {
-                reader.seek(SeekFrom::Start(*position_in_log as u64));
-                if let Some(LogCommand::Insert { value, .. }) =
-                    serde_json::from_reader(reader.take(*len_in_log as u64))?
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if let Some(as_of) = self.as_of() {
+            let index = self.keyspace.index.read().expect("lock not poisoned");
+            let history = self.keyspace.history.read().expect("lock not poisoned");
+            return self.read_as_of(&index, &history, &key, as_of, now_millis());
+        }
+
+        loop {
+            let location = match self
+                .keyspace
+                .index
+                .read()
+                .expect("lock not poisoned")
+                .get(&key)
+            {
+                Some(location) if !is_expired(location.3, now_millis()) => location.clone(),
+                _ => return Ok(None),
+            };
+
+            match self.readers.read_command(&location) {
+                Ok((_, LogCommand::Insert { value, .. })) => return Ok(Some(value)),
+                Ok((_, LogCommand::Merge { .. })) => return self.get_merged(&key),
+                Ok(_) => return Err(Error::KeyNotFound),
+                // the record could have been moved by a compaction in the meantime
+                Err(e) => match self
+                    .keyspace
+                    .index
+                    .read()
+                    .expect("lock not poisoned")
+                    .get(&key)
                 {
-                    return Ok(Some(value));
-                } else {
-                    return Err(Error::KeyNotFound);
+                    Some(moved) if *moved != location => continue,
+                    _ => return Err(e),
+                },
+            }
+        }
+    }
+
+    /// insert value at key
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.insert(key, value, None)
+    }
+
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.insert(key, value, Some(expires_at(ttl)))
+    }
+
+    /// a snapshot has the versions up to the one it reads only
+    fn get_versions(&self, key: Vec<u8>, n: usize) -> Result<Vec<(u64, Option<Vec<u8>>)>> {
+        // a compaction can't move the records while the index is locked
+        let index = self.keyspace.index.read().expect("lock not poisoned");
+        let history = self.keyspace.history.read().expect("lock not poisoned");
+
+        let as_of = self.as_of().unwrap_or(u64::MAX);
+        let locations = locations(&index, &history, &key);
+        let mut versions = Vec::new();
+        for (i, location) in locations.iter().enumerate().rev() {
+            if versions.len() == n {
+                break;
+            }
+            versions.push(match self.readers.read_command(location)? {
+                (version, _) if version > as_of => continue,
+                (version, LogCommand::Insert { value, .. }) => (version, Some(value)),
+                (version, LogCommand::Merge { .. }) => {
+                    let value = fold(
+                        &key,
+                        &locations[..=i],
+                        &self.operators,
+                        version_millis(version),
+                        |location| self.readers.read_command(location),
+                    )?;
+                    (version, value)
+                }
+                (version, _) => (version, None),
+            });
+        }
+
+        Ok(versions)
+    }
+
+    fn get_at(&self, key: Vec<u8>, version: u64) -> Result<Option<Vec<u8>>> {
+        let version = version.min(self.as_of().unwrap_or(u64::MAX));
+        let index = self.keyspace.index.read().expect("lock not poisoned");
+        let history = self.keyspace.history.read().expect("lock not poisoned");
+
+        let locations = locations(&index, &history, &key);
+        for (i, location) in locations.iter().enumerate().rev() {
+            match self.readers.read_command(location)? {
+                (written, _) if written > version => continue,
+                _ => {
+                    return fold(
+                        &key,
+                        &locations[..=i],
+                        &self.operators,
+                        version_millis(version),
+                        |location| self.readers.read_command(location),
+                    )
                 }
-            } else {
-                return Err(Error::LogReaderNotFound);
             }
-        } else {
-            return Ok(None);
-        };
+        }
 
-        return Ok(None);
+        Ok(None)
     }
 
-    /// insert value at key
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let command = LogCommand::Insert {
-            key: key.clone(),
-            value,
-        };
-        let mut pos = self.writer.pos;
-        serde_json::to_writer(&mut self.writer, &command)?;
-        self.writer.flush()?;
+    fn ttl(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        let now = now_millis();
+        if let Some(as_of) = self.as_of() {
+            let index = self.keyspace.index.read().expect("lock not poisoned");
+            let history = self.keyspace.history.read().expect("lock not poisoned");
+            let locations = locations(&index, &history, &key);
+            return match self.version_as_of(&locations, as_of)? {
+                Some((_, _, LogCommand::Insert { expires_at, .. }))
+                    if !is_expired(expires_at, now) =>
+                {
+                    Ok(expires_at.map(|expires_at| Duration::from_millis(expires_at - now)))
+                }
+                // an operand takes the expiry away like a write
+                Some((_, _, LogCommand::Merge { .. })) => Ok(None),
+                _ => Err(Error::KeyNotFound),
+            };
+        }
 
-        if let Some((_, _, len)) = self.store.insert(
-            key.clone(),
-            (
-                self.session_log_name.to_owned(),
-                pos as usize,
-                (self.writer.pos - pos) as usize,
-            ),
-        ) {
-            self.uncompacted += len;
+        match self
+            .keyspace
+            .index
+            .read()
+            .expect("lock not poisoned")
+            .get(&key)
+        {
+            Some(location) if !is_expired(location.3, now) => Ok(location
+                .3
+                .map(|expires_at| Duration::from_millis(expires_at - now))),
+            _ => Err(Error::KeyNotFound),
         }
+    }
+
+    /// remove value at key
+    fn remove(&self, key: Vec<u8>) -> Result<()> {
+        self.writer()?
+            .lock()
+            .expect("mutex not poisoned")
+            .remove(&self.keyspace, key)
+    }
+
+    /// writes the operand as it is, `get` folds it into the value
+    fn merge(&self, key: Vec<u8>, operator: &str, operand: Vec<u8>) -> Result<Vec<u8>> {
+        let merge_operator = self
+            .operators
+            .get(operator)
+            .ok_or(Error::UnknownMergeOperator)?;
+        // no other write can come in between while the writer is held, an operand
+        // which can't be folded into the value would break the reads of the key
+        let mut writer = self.writer()?.lock().expect("mutex not poisoned");
+        let existing = self.get(key.clone())?;
+        let value = merge_operator.merge(&key, existing.as_deref(), &operand)?;
+
+        let needs_compaction = writer.merge(&self.keyspace, key, operator, operand)?;
+        drop(writer);
+
+        self.compact_if(needs_compaction);
+
+        Ok(value)
+    }
+
+    /// writes a single range tombstone however many keys the range holds
+    fn delete_range(&self, start: Vec<u8>, end: Vec<u8>) -> Result<()> {
+        let needs_compaction = self
+            .writer()?
+            .lock()
+            .expect("mutex not poisoned")
+            .delete_range(&self.keyspace, start, end)?;
+
+        self.compact_if(needs_compaction);
+
+        Ok(())
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let needs_compaction = self
+            .writer()?
+            .lock()
+            .expect("mutex not poisoned")
+            .write_batch(&self.keyspace, batch.commands)?;
+
+        self.compact_if(needs_compaction);
+
+        Ok(())
+    }
 
-        if self.uncompacted > COMPACTION_THRESHOLD {
-            self.compact();
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()> {
+        // no other write can come in between while the writer is held
+        let mut writer = self.writer()?.lock().expect("mutex not poisoned");
+        let current = self.get(key.clone())?;
+        if current != expected {
+            return Err(Error::ConditionFailed { current });
         }
 
+        let needs_compaction = match (new, current) {
+            (Some(value), _) => writer.set(&self.keyspace, key, value, None)?,
+            (None, Some(_)) => match writer.remove(&self.keyspace, key) {
+                Ok(()) => false,
+                // its ttl ran out in the meantime
+                Err(Error::KeyNotFound) => return Err(Error::ConditionFailed { current: None }),
+                Err(e) => return Err(e),
+            },
+            (None, None) => false,
+        };
+        drop(writer);
+
+        self.compact_if(needs_compaction);
+
         Ok(())
     }
 
-    /// remove value at key
-    fn remove(&mut self, key: String) -> Result<()> {
-        if let None = self.store.get(&key) {
-            return Err(Error::KeyNotFound);
+    /// reads the data as of the last write through the versions of the keys, which outlive
+    /// the retention until it's dropped, the writes wait only for it to be taken,
+    /// a keyspace dropped meanwhile is gone from it too
+    fn snapshot(&self) -> Result<Self> {
+        if self.snapshot.is_some() {
+            return Ok(self.clone());
         }
 
-        let command = LogCommand::Remove { key: key.clone() };
-        serde_json::to_writer(&mut self.writer, &command)?;
-        self.writer.flush()?;
+        // pinned before the last write is read, a compaction finishing in between
+        // keeps its segments
+        let segments = SegmentPin::new(&self.retired);
+        let pin = match &self.writer {
+            Some(writer) => {
+                let writer = writer.lock().expect("mutex not poisoned");
+                SnapshotPin::new(writer.seq, &self.pinned, segments)
+            }
+            // nothing is written to a read-only store
+            None => SnapshotPin::new(u64::MAX, &self.pinned, segments),
+        };
+        // the keyspaces opened since are empty to it
+        let keyspaces = self.keyspaces.read().expect("lock not poisoned").clone();
+
+        Ok(KvStore {
+            keyspaces: Arc::new(RwLock::new(keyspaces)),
+            compactor: None,
+            syncer: None,
+            sweeper: None,
+            writer: None,
+            snapshot: Some(Arc::new(pin)),
+            ..self.clone()
+        })
+    }
+
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: Option<usize>) -> Result<ScanIter> {
+        Ok(Box::new(KvStoreScan {
+            store: self.clone(),
+            from: owned_bound(range.start_bound()),
+            to: owned_bound(range.end_bound()),
+            prefix: None,
+            remaining: limit.unwrap_or(usize::MAX),
+            batch: VecDeque::new(),
+        }))
+    }
+
+    fn scan_prefix(&self, prefix: Vec<u8>) -> Result<ScanIter> {
+        Ok(Box::new(KvStoreScan {
+            store: self.clone(),
+            from: Bound::Included(prefix.clone()),
+            to: Bound::Unbounded,
+            prefix: Some(prefix),
+            remaining: usize::MAX,
+            batch: VecDeque::new(),
+        }))
+    }
+
+    fn open_tree(&self, name: &str) -> Result<Self> {
+        check_keyspace_name(name)?;
+        let keyspace = Arc::clone(
+            self.keyspaces
+                .write()
+                .expect("lock not poisoned")
+                .entry(name.to_owned())
+                .or_insert_with(|| Arc::new(Keyspace::new(name))),
+        );
+
+        Ok(KvStore {
+            keyspace,
+            ..self.clone()
+        })
+    }
+
+    /// writes a single record, the records of the keyspace go with the next compaction
+    fn drop_tree(&self, name: &str) -> Result<()> {
+        check_keyspace_name(name)?;
+        let needs_compaction = self
+            .writer()?
+            .lock()
+            .expect("mutex not poisoned")
+            .drop_keyspace(name)?;
 
-        self.store.remove(&key);
+        self.compact_if(needs_compaction);
 
         Ok(())
     }
-}
 
-impl KvStore {
-    /// default factory
-    pub fn new(
-        session_log_writer: LogWriter<std::fs::File>,
-        session_log_name: String,
-        path: PathBuf,
-    ) -> Self {
-        KvStore {
-            store: HashMap::new(),
-            readers: HashMap::new(),
-            writer: session_log_writer,
-            session_log_name,
-            path,
-            uncompacted: 0,
+    fn tree_names(&self) -> Result<Vec<String>> {
+        let mut names: Vec<String> = self
+            .keyspaces
+            .read()
+            .expect("lock not poisoned")
+            .keys()
+            .filter(|name| !name.is_empty())
+            .cloned()
+            .collect();
+        names.sort_unstable();
+
+        Ok(names)
+    }
+
+    /// a read-only store or a snapshot has no writer and only counts the keys
+    fn stats(&self) -> Result<EngineStats> {
+        let mut stats = EngineStats {
+            engine: "kvs".to_owned(),
+            ..EngineStats::default()
+        };
+        if self.snapshot.is_some() {
+            // the keys it has are only found by reading them
+            for (name, keyspace) in self.keyspaces.read().expect("lock not poisoned").iter() {
+                let handle = KvStore {
+                    keyspace: Arc::clone(keyspace),
+                    ..self.clone()
+                };
+                for pair in handle.scan(.., None)? {
+                    pair?;
+                    stats.keys += 1;
+                }
+                if !name.is_empty() {
+                    stats.keyspaces += 1;
+                }
+            }
+
+            return Ok(stats);
+        }
+
+        // the keys are counted as of the same write as the segments
+        let writer = match &self.writer {
+            Some(writer) => Some(writer.lock().expect("mutex not poisoned")),
+            None => None,
+        };
+
+        let now = now_millis();
+        for (name, keyspace) in self.keyspaces.read().expect("lock not poisoned").iter() {
+            let index = keyspace.index.read().expect("lock not poisoned");
+            stats.keys += index
+                .values()
+                .filter(|location| !is_expired(location.3, now))
+                .count() as u64;
+            if !name.is_empty() {
+                stats.keyspaces += 1;
+            }
+        }
+
+        if let Some(writer) = writer {
+            writer.add_stats(&mut stats);
+        }
+
+        Ok(stats)
+    }
+
+    /// hard-links the sealed segments with their hints and copies the session log
+    /// up to the last write, the writer is only locked to flush it
+    fn checkpoint(&self, dest: &Path) -> Result<()> {
+        // a compaction finishing meanwhile leaves the segments of `manifest` in place
+        let _pin = SegmentPin::new(&self.retired);
+        let (manifest, session_log_name, session_len) = self
+            .writer()?
+            .lock()
+            .expect("mutex not poisoned")
+            .checkpoint_state()?;
+
+        std::fs::create_dir(dest)?;
+        let storage = self.readers.storage.as_ref();
+        for log_name in manifest.log_names() {
+            if log_name == session_log_name {
+                storage.export(&log_name, session_len, false, &dest.join(&log_name))?;
+                continue;
+            }
+
+            let len = storage.open(&log_name)?.len()?;
+            storage.export(&log_name, len, true, &dest.join(&log_name))?;
+
+            let hint_name = hint_file_name(&log_name);
+            match storage.open(&hint_name) {
+                Ok(hint) => {
+                    storage.export(&hint_name, hint.len()?, true, &dest.join(&hint_name))?
+                }
+                Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
         }
+
+        manifest.write(&DiskStorage::new(dest))
     }
+}
 
+impl KvStore {
     pub fn open(path: &Path) -> Result<Self> {
+        KvStore::open_with(path, &Options::default())
+    }
+
+    /// opens the store, `mode` decides what happens to a segment damaged in the middle
+    pub fn open_with_recovery(path: &Path, mode: RecoveryMode) -> Result<Self> {
+        KvStore::open_with(path, &Options::new().recovery_mode(mode))
+    }
+
+    pub fn open_with(path: &Path, options: &Options) -> Result<Self> {
         std::fs::create_dir_all(&path)?;
+        let lock = DirLock::exclusive(path)?;
+
+        KvStore::open_storage(Arc::new(DiskStorage::new(path)), options, Some(lock))
+    }
+
+    /// opens the store kept in `storage` instead of a directory on the disk,
+    /// nothing keeps another store from opening it at the same time
+    pub fn open_with_storage<S: Storage + 'static>(storage: S, options: &Options) -> Result<Self> {
+        KvStore::open_storage(Arc::new(storage), options, None)
+    }
 
-        let log_files_names = KvStore::get_log_files_names_by_path(read_dir(path)?)?;
-        let (session_log_file, log_file_name) = create_log_file(&log_files_names, path)?;
+    fn open_storage(
+        storage: Arc<dyn Storage>,
+        options: &Options,
+        lock: Option<DirLock>,
+    ) -> Result<Self> {
+        let log_files_names = KvStore::get_live_log_files_names(storage.as_ref())?;
+        Compactor::remove_unfinished(storage.as_ref(), &log_files_names)?;
 
-        let session_log_writer = LogWriter::new(
-            std::fs::OpenOptions::new()
-                .create(true)
-                .write(true)
-                .read(true)
-                .append(true)
-                .open(&path.join(log_file_name.clone()))?,
+        let mut replay = Replay::default();
+        replay.populate_store_from_log_files(
+            storage.as_ref(),
+            log_files_names.clone(),
+            options.recovery_mode,
         )?;
-        let mut store = Self::new(session_log_writer, log_file_name.clone(), path.into());
 
-        store.uncompacted = store.populate_store_from_log_files(&path, log_files_names)?;
-        store.readers.insert(
-            log_file_name.clone(),
-            LogReader::new(std::fs::File::open(path.join(&log_file_name))?)?,
+        let (session_log_file, log_file_name) =
+            create_log_file(&log_files_names, storage.as_ref())?;
+        let session_log_writer = LogWriter::new(session_log_file)?;
+        let manifest = Manifest::new(
+            log_files_names
+                .iter()
+                .filter(|log_name| !replay.recovery.quarantined.contains(log_name))
+                .chain(Some(&log_file_name))
+                .map(|log_name| log_gen(log_name)),
         );
+        manifest.write(storage.as_ref())?;
+        let last_gen = log_gen(&log_file_name);
+
+        let keyspaces = Arc::new(RwLock::new(replay.keyspaces()));
+        let keyspace = default_keyspace(&keyspaces);
+        let compactions = Arc::new(AtomicUsize::new(0));
+        let retired = Arc::new(RetiredSegments::default());
+        let pinned = Arc::new(PinnedVersions::default());
+        let operators = Arc::new(options.merge_operators.clone());
+        let writer = Arc::new(Mutex::new(KvStoreWriter {
+            writer: session_log_writer,
+            session_log_name: log_file_name,
+            session_hints: Vec::new(),
+            storage: Arc::clone(&storage),
+            manifest,
+            keyspaces: Arc::clone(&keyspaces),
+            seq: replay.seq,
+            last_gen,
+            usage: replay.usage,
+            expiries: replay.expiries,
+            compaction_threshold: options.compaction_threshold,
+            max_segment_size: options.max_segment_size,
+            garbage_ratio: options.garbage_ratio,
+            durability: options.durability,
+            unsynced: false,
+            compactions: 0,
+            last_compaction: None,
+        }));
+        let compactor = Arc::new(Compactor::start(
+            Arc::clone(&writer),
+            Arc::clone(&keyspaces),
+            KvStoreReaders::new(Arc::clone(&storage), Arc::clone(&compactions)),
+            Arc::clone(&retired),
+            Arc::clone(&pinned),
+            options.retention,
+            Arc::clone(&operators),
+        ));
+        let syncer = match options.durability {
+            Durability::EveryNMillis(millis) => {
+                let writer = Arc::clone(&writer);
+                let sync = move || {
+                    // a failed sync is retried on the next tick, the data stays in the OS buffers
+                    let _ = writer.lock().expect("mutex not poisoned").sync();
+                };
+
+                Some(Arc::new(Periodic::start(
+                    Duration::from_millis(millis),
+                    sync,
+                )))
+            }
+            _ => None,
+        };
+        let sweeper = {
+            let writer = Arc::clone(&writer);
+            let compactor = Arc::clone(&compactor);
+            let sweep = move || {
+                if writer.lock().expect("mutex not poisoned").sweep_expired() {
+                    compactor.trigger();
+                }
+            };
+
+            Periodic::start(options.sweep_interval, sweep)
+        };
 
-        Ok(store)
+        Ok(KvStore {
+            keyspace,
+            keyspaces,
+            readers: Arc::new(ReadersPool::new(storage, compactions)),
+            operators,
+            compactor: Some(compactor),
+            syncer,
+            sweeper: Some(Arc::new(sweeper)),
+            writer: Some(writer),
+            recovery: Arc::new(replay.recovery),
+            lock: lock.map(Arc::new),
+            retired,
+            pinned,
+            snapshot: None,
+        })
     }
 
-    fn populate_store_from_log_files(
-        &mut self,
-        base_path: &Path,
-        log_files_names: Vec<String>,
-    ) -> Result<usize> {
-        let mut uncompacted = 0;
-        for file_name in log_files_names.iter() {
-            let file = File::open(&base_path.join(file_name))?;
-            let s = std::fs::read_to_string(&base_path.join(file_name))?;
-
-            let start_pos = 0;
-            let mut reader = LogReader::new(file)?;
-            let mut stream = Deserializer::from_reader(&mut reader).into_iter::<LogCommand>();
-
-            let mut pos: usize = start_pos as usize;
-            while let Some(Ok(command)) = stream.next() {
-                let curr_pos = stream.byte_offset();
-
-                if let Some((_, _, len)) =
-                    self.exec_command(&command, file_name.to_owned(), pos, curr_pos - pos)
+    /// opens the store for inspection next to a live one,
+    /// it sees the data as of the moment it was opened and fails every write with `Error::ReadOnly`,
+    /// only the built-in merge operators fold the operands,
+    /// it takes no lock, so the reads of a segment the live writer compacted away fail
+    /// with `Error::Io` until it's opened again
+    pub fn open_read_only(path: &Path) -> Result<Self> {
+        let storage: Arc<dyn Storage> = Arc::new(DiskStorage::new(path));
+        let mut attempts = 0;
+        let mut replay = loop {
+            let log_files_names = KvStore::get_live_log_files_names(storage.as_ref())?;
+            let mut replay = Replay {
+                read_only: true,
+                ..Replay::default()
+            };
+            match replay.populate_store_from_log_files(
+                storage.as_ref(),
+                log_files_names,
+                RecoveryMode::Refuse,
+            ) {
+                Ok(()) => break replay,
+                // a compaction removed a segment of the manifest read before it
+                Err(Error::Io(ref e))
+                    if e.kind() == io::ErrorKind::NotFound
+                        && attempts < READ_ONLY_OPEN_ATTEMPTS =>
                 {
-                    uncompacted += len;
-                    if let &LogCommand::Remove { .. } = &command {
-                        uncompacted += curr_pos - pos;
-                    }
+                    attempts += 1
                 }
-                pos = curr_pos;
+                Err(e) => return Err(e),
             }
+        };
+        let keyspaces = Arc::new(RwLock::new(replay.keyspaces()));
+
+        Ok(KvStore {
+            keyspace: default_keyspace(&keyspaces),
+            keyspaces,
+            readers: Arc::new(ReadersPool::new(storage, Arc::new(AtomicUsize::new(0)))),
+            operators: Arc::new(builtin_operators()),
+            compactor: None,
+            syncer: None,
+            sweeper: None,
+            writer: None,
+            recovery: Arc::new(replay.recovery),
+            lock: None,
+            retired: Arc::new(RetiredSegments::default()),
+            pinned: Arc::new(PinnedVersions::default()),
+            snapshot: None,
+        })
+    }
+
+    /// what `open` had to do to get the log files into a consistent state
+    pub fn recovery_report(&self) -> &RecoveryReport {
+        &self.recovery
+    }
+
+    /// amount of stale bytes after which a background compaction starts
+    pub fn set_compaction_threshold(&self, threshold: usize) -> Result<()> {
+        self.writer()?
+            .lock()
+            .expect("mutex not poisoned")
+            .compaction_threshold = threshold;
+
+        Ok(())
+    }
 
-            self.readers.insert(file_name.to_owned(), reader);
+    /// runs a compaction on the background worker and waits for it to finish,
+    /// it leaves the segments below the garbage ratio alone just like a background one
+    pub fn compact_now(&self) -> Result<()> {
+        match &self.compactor {
+            Some(compactor) => compactor.run(),
+            None => Err(Error::ReadOnly),
         }
+    }
+
+    fn insert(&self, key: Vec<u8>, value: Vec<u8>, expires_at: ExpiresAt) -> Result<()> {
+        let needs_compaction = self.writer()?.lock().expect("mutex not poisoned").set(
+            &self.keyspace,
+            key,
+            value,
+            expires_at,
+        )?;
+
+        self.compact_if(needs_compaction);
 
-        Ok(uncompacted)
+        Ok(())
     }
 
-    fn exec_command(
-        &mut self,
-        command: &LogCommand,
-        log_name: String,
-        start: usize,
-        len: usize,
-    ) -> Option<KvStoreValue> {
-        match command {
-            LogCommand::Insert { key, .. } => {
-                self.store.insert(key.to_owned(), (log_name, start, len))
+    /// the current value of a key with operands merged into it
+    fn get_merged(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        // a compaction can't move the records while the index is locked
+        let index = self.keyspace.index.read().expect("lock not poisoned");
+        let history = self.keyspace.history.read().expect("lock not poisoned");
+        if !index.contains_key(key) {
+            return Ok(None);
+        }
+
+        fold(
+            key,
+            &locations(&index, &history, key),
+            &self.operators,
+            now_millis(),
+            |location| self.readers.read_command(location),
+        )
+    }
+
+    /// the sequence number of the last write a snapshot reads, `None` for a live store
+    fn as_of(&self) -> Option<u64> {
+        self.snapshot.as_ref().map(|pin| pin.seq)
+    }
+
+    /// the newest of the versions `locations` at or before `as_of`
+    /// with its position among them, `None` for a key written only since
+    fn version_as_of(
+        &self,
+        locations: &[KvStoreValue],
+        as_of: u64,
+    ) -> Result<Option<(usize, u64, LogCommand)>> {
+        for (i, location) in locations.iter().enumerate().rev() {
+            match self.readers.read_command(location)? {
+                (seq, _) if seq > as_of => continue,
+                (seq, command) => return Ok(Some((i, seq, command))),
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// the value a key had right after the write `as_of`, the index and the history
+    /// are locked so a compaction can't move the records
+    fn read_as_of(
+        &self,
+        index: &KeyDir,
+        history: &History,
+        key: &[u8],
+        as_of: u64,
+        now: u64,
+    ) -> Result<Option<Vec<u8>>> {
+        let locations = locations(index, history, key);
+        match self.version_as_of(&locations, as_of)? {
+            Some((
+                _,
+                _,
+                LogCommand::Insert {
+                    value, expires_at, ..
+                },
+            )) if !is_expired(expires_at, now) => Ok(Some(value)),
+            Some((i, _, LogCommand::Merge { .. })) => {
+                fold(key, &locations[..=i], &self.operators, now, |location| {
+                    self.readers.read_command(location)
+                })
             }
-            LogCommand::Remove { key, .. } => self.store.remove(&key.to_owned()),
+            _ => Ok(None),
+        }
+    }
+
+    fn compact_if(&self, needs_compaction: bool) {
+        if let (true, Some(compactor)) = (needs_compaction, &self.compactor) {
+            compactor.trigger();
         }
     }
 
-    fn get_log_files_names_by_path(dir: ReadDir) -> Result<Vec<String>> {
-        let mut files: Vec<String> = dir
-            .flat_map(|res| -> Result<_> { Ok(res?.path()) })
-            .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
-            .flat_map(|file| OsString::into_string(file.file_name().unwrap().into()).ok())
-            .filter(|file| match file.rfind(".log") {
-                Some(i) if i + 3 == file.len() - 1 => true,
-                _ => false,
+    fn writer(&self) -> Result<&Arc<Mutex<KvStoreWriter>>> {
+        self.writer.as_ref().ok_or(Error::ReadOnly)
+    }
+
+    /// every segment in the storage, oldest first
+    pub(crate) fn get_log_files_names(storage: &dyn Storage) -> Result<Vec<String>> {
+        let mut files: Vec<String> = storage
+            .list()?
+            .into_iter()
+            .filter(|file| {
+                file.ends_with(".log") && file.trim_end_matches(".log").parse::<usize>().is_ok()
             })
             .collect();
 
-        files.sort_unstable();
+        files.sort_unstable_by_key(|file| log_gen(file));
 
         Ok(files)
     }
 
-    fn compact(&mut self) -> Result<()> {
-        let curr_gen = self
-            .session_log_name
-            .trim_end_matches(".log")
-            .parse::<usize>()?;
+    /// the segments the manifest lists, oldest first,
+    /// a store written before there was a manifest has all of its segments live
+    pub(crate) fn get_live_log_files_names(storage: &dyn Storage) -> Result<Vec<String>> {
+        match Manifest::read(storage)? {
+            Some(manifest) => Ok(manifest.log_names()),
+            None => KvStore::get_log_files_names(storage),
+        }
+    }
+}
 
-        let new_gen = curr_gen + 2;
-        let comp_gen = curr_gen + 1;
+/// ordered scan over a `KvStore`,
+/// pairs are read in batches and a compaction can't move the records of a batch while it's read
+struct KvStoreScan {
+    store: KvStore,
+    /// moves past the last key of every batch
+    from: Bound<Vec<u8>>,
+    to: Bound<Vec<u8>>,
+    prefix: Option<Vec<u8>>,
+    remaining: usize,
+    batch: VecDeque<(Vec<u8>, Vec<u8>)>,
+}
 
-        let mut comp_writer = LogWriter::new(
-            std::fs::OpenOptions::new()
-                .create(true)
-                .write(true)
-                .read(true)
-                .append(true)
-                .open(self.path.join(format!("{}.{}", comp_gen, "log")))?,
-        )?;
-        self.writer = LogWriter::new(
-            std::fs::OpenOptions::new()
-                .create(true)
-                .write(true)
-                .read(true)
-                .append(true)
-                .open(self.path.join(format!("{}.{}", new_gen, "log")))?,
-        )?;
-        self.readers.insert(
-            format!("{}.{}", comp_gen, "log"),
-            LogReader::new(std::fs::File::open(
-                self.path.join(format!("{}.{}", comp_gen, "log")),
-            )?)?,
-        );
-        self.readers.insert(
-            format!("{}.{}", new_gen, "log"),
-            LogReader::new(std::fs::File::open(
-                self.path.join(format!("{}.{}", new_gen, "log")),
-            )?)?,
-        );
-        self.session_log_name = format!("{}.{}", new_gen, "log");
+impl KvStoreScan {
+    fn read_batch(&mut self) -> Result<()> {
+        if self.remaining == 0 || is_empty_range(&self.from, &self.to) {
+            self.remaining = 0;
+            return Ok(());
+        }
 
-        let mut pos = 0;
+        let batch_len = SCAN_BATCH_LEN.min(self.remaining);
+        let now = now_millis();
+        if let Some(as_of) = self.store.as_of() {
+            return self.read_batch_as_of(batch_len, as_of, now);
+        }
 
-        for (log_name, old_pos, offset) in self.store.values_mut() {
-            let r = self.readers.get_mut(log_name).expect(log_name);
+        let index = self.store.keyspace.index.read().expect("lock not poisoned");
+        let mut visited = 0;
+        let mut last_key = None;
+        for (key, location) in index
+            .range((self.from.clone(), self.to.clone()))
+            .take(batch_len)
+        {
+            if let Some(prefix) = &self.prefix {
+                if !key.starts_with(prefix) {
+                    break;
+                }
+            }
+            visited += 1;
+            last_key = Some(key);
 
-            r.seek(SeekFrom::Start(*old_pos as u64));
-            let mut record_reader = r.take(*offset as u64);
-            let len = std::io::copy(&mut record_reader, &mut comp_writer)? as usize;
-            *log_name = format!("{}.{}", comp_gen, "log");
-            *old_pos = pos;
-            *offset = len;
-            pos += len;
+            if is_expired(location.3, now) {
+                continue;
+            }
+            match self.store.readers.read_command(location)? {
+                (_, LogCommand::Insert { value, .. }) => {
+                    self.batch.push_back((key.to_owned(), value))
+                }
+                (_, LogCommand::Merge { .. }) => {
+                    let history = self
+                        .store
+                        .keyspace
+                        .history
+                        .read()
+                        .expect("lock not poisoned");
+                    let value = fold(
+                        key,
+                        &locations(&index, &history, key),
+                        &self.store.operators,
+                        now,
+                        |location| self.store.readers.read_command(location),
+                    )?;
+                    if let Some(value) = value {
+                        self.batch.push_back((key.to_owned(), value));
+                    }
+                }
+                _ => return Err(Error::KeyNotFound),
+            }
         }
-        comp_writer.flush()?;
 
-        let old_readers: Vec<_> = self
-            .readers
-            .keys()
-            .filter(|gen| gen.trim_end_matches(".log").parse::<usize>().unwrap() < comp_gen)
-            .cloned()
+        let last_key = last_key.cloned();
+        drop(index);
+        self.advance(last_key, visited == batch_len);
+
+        Ok(())
+    }
+
+    /// the keys removed since the snapshot was taken are left in the history only
+    fn read_batch_as_of(&mut self, batch_len: usize, as_of: u64, now: u64) -> Result<()> {
+        let index = self.store.keyspace.index.read().expect("lock not poisoned");
+        let history = self
+            .store
+            .keyspace
+            .history
+            .read()
+            .expect("lock not poisoned");
+        let range = (self.from.clone(), self.to.clone());
+        let keys: BTreeSet<&Vec<u8>> = index
+            .range(range.clone())
+            .map(|(key, _)| key)
+            .take(batch_len)
+            .chain(history.range(range).map(|(key, _)| key).take(batch_len))
             .collect();
 
-        for stale_gen in old_readers {
-            self.readers.remove(&stale_gen);
-            std::fs::remove_file(&self.path.join(stale_gen))?;
+        let mut visited = 0;
+        let mut last_key = None;
+        for key in keys.into_iter().take(batch_len) {
+            if let Some(prefix) = &self.prefix {
+                if !key.starts_with(prefix) {
+                    break;
+                }
+            }
+            visited += 1;
+            last_key = Some(key);
+
+            if let Some(value) = self.store.read_as_of(&index, &history, key, as_of, now)? {
+                self.batch.push_back((key.to_owned(), value));
+            }
         }
 
-        self.uncompacted = 0;
+        let last_key = last_key.cloned();
+        drop((index, history));
+        self.advance(last_key, visited == batch_len);
 
         Ok(())
     }
+
+    /// moves past the last key of a batch, a batch which isn't full ends the scan
+    fn advance(&mut self, last_key: Option<Vec<u8>>, full: bool) {
+        match last_key {
+            Some(last_key) if full => {
+                self.from = Bound::Excluded(last_key);
+                self.remaining -= self.batch.len();
+            }
+            // the range or the prefix is over
+            _ => self.remaining = 0,
+        }
+    }
+}
+
+impl Iterator for KvStoreScan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // a batch of expired keys only comes back empty
+        while self.batch.is_empty() && self.remaining > 0 {
+            if let Err(e) = self.read_batch() {
+                self.remaining = 0;
+                return Some(Err(e));
+            }
+        }
+
+        self.batch.pop_front().map(Ok)
+    }
+}
+
+pub(crate) fn owned_bound(bound: Bound<&Vec<u8>>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.to_owned()),
+        Bound::Excluded(key) => Bound::Excluded(key.to_owned()),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+/// `BTreeMap::range` panics on these instead of returning nothing
+pub(crate) fn is_empty_range(from: &Bound<Vec<u8>>, to: &Bound<Vec<u8>>) -> bool {
+    match (from, to) {
+        (Bound::Included(from), Bound::Included(to)) => from > to,
+        (Bound::Included(from), Bound::Excluded(to))
+        | (Bound::Excluded(from), Bound::Included(to))
+        | (Bound::Excluded(from), Bound::Excluded(to)) => from >= to,
+        _ => false,
+    }
+}
+
+fn default_keyspace(keyspaces: &RwLock<Keyspaces>) -> Arc<Keyspace> {
+    Arc::clone(
+        keyspaces
+            .read()
+            .expect("lock not poisoned")
+            .get("")
+            .expect("the default keyspace is always there"),
+    )
+}
+
+/// unix time in milliseconds
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+/// when a key written now with `ttl` expires, a ttl too long to count ends at the end of time
+pub(crate) fn expires_at(ttl: Duration) -> u64 {
+    now_millis().saturating_add(u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX))
+}
+
+pub(crate) fn now_micros() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since_epoch| since_epoch.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+pub(crate) fn is_expired(expires_at: ExpiresAt, now: u64) -> bool {
+    expires_at.map_or(false, |expires_at| expires_at <= now)
+}
+
+/// unix time in milliseconds a version was written at,
+/// versions of the stores which counted writes from 1 look ancient
+pub(crate) fn version_millis(version: u64) -> u64 {
+    version / 1000
+}
+
+/// generation number of a `<gen>.log` segment
+pub(crate) fn log_gen(log_name: &str) -> usize {
+    log_name
+        .trim_end_matches(".log")
+        .parse::<usize>()
+        .expect("log names are generation numbers")
+}
+
+/// the damage from the record at `pos` on, a record which can't be read is only
+/// a write which was cut off if no valid record comes after it, be it a short record,
+/// a garbled one or zeros the file system padded the segment with
+fn damage_at(reader: &mut LogReader<Box<dyn StorageFile>>, pos: u64) -> Result<SegmentDamage> {
+    reader.seek(SeekFrom::Start(pos))?;
+    let mut tail = Vec::new();
+    reader.read_to_end(&mut tail)?;
+    let scanned = scan_damaged_tail(&tail);
+
+    Ok(SegmentDamage {
+        pos,
+        torn: !scanned.resumes,
+        records: scanned.records,
+    })
+}
+
+/// state rebuilt from the log files on `open`
+#[derive(Default)]
+struct Replay {
+    keyspaces: BTreeMap<String, Keyspace>,
+    expiries: ExpiryQueue,
+    seq: u64,
+    usage: SegmentsUsage,
+    recovery: RecoveryReport,
+    /// records which expired before it count as removals
+    now: u64,
+    /// leave the files as they are, even a torn tail
+    read_only: bool,
+}
+
+impl Replay {
+    fn populate_store_from_log_files(
+        &mut self,
+        storage: &dyn Storage,
+        mut log_files_names: Vec<String>,
+        mode: RecoveryMode,
+    ) -> Result<()> {
+        // only the segment written last before a crash can have a torn tail
+        let newest = log_files_names.last().cloned();
+        self.now = now_millis();
+
+        'replay: loop {
+            for (i, file_name) in log_files_names.iter().enumerate() {
+                let file = storage.open(file_name)?;
+                let file_len = file.len()?;
+                let mut reader = LogReader::new(file)?;
+                self.usage.entry(file_name.to_owned()).or_default().len = file_len as usize;
+
+                if let Some(hints) = read_hint_file(storage, file_name, file_len)? {
+                    self.recovery.records_kept += hints.len();
+                    for hint in hints {
+                        self.replay_hint(hint, file_name);
+                    }
+                    continue;
+                }
+
+                let (kept, damage) = self.replay_segment(file_name, &mut reader)?;
+                self.recovery.records_kept += kept;
+
+                if let Some(SegmentDamage { pos, torn, records }) = damage {
+                    if torn && newest.as_ref() == Some(file_name) {
+                        if !self.read_only {
+                            storage.truncate(file_name, pos)?;
+                        }
+                        self.usage.entry(file_name.to_owned()).or_default().len = pos as usize;
+                        self.recovery.records_discarded += records;
+                        self.recovery.truncated_bytes += file_len - pos;
+                    } else if mode == RecoveryMode::Quarantine && !self.read_only {
+                        storage.rename(
+                            file_name,
+                            &format!("{}.{}", file_name, QUARANTINE_EXTENSION),
+                        )?;
+
+                        // the damaged segment could have shadowed older values, replay from scratch
+                        let quarantined = log_files_names.remove(i);
+                        let mut recovery = std::mem::take(&mut self.recovery);
+                        recovery.records_discarded += kept + records;
+                        recovery.records_kept = 0;
+                        recovery.quarantined.push(quarantined);
+
+                        self.keyspaces.clear();
+                        self.expiries.clear();
+                        self.usage.clear();
+                        self.seq = 0;
+                        self.recovery = recovery;
+
+                        continue 'replay;
+                    } else {
+                        return Err(Error::Corruption {
+                            segment: file_name.to_owned(),
+                            offset: pos,
+                        });
+                    }
+                }
+            }
+
+            return Ok(());
+        }
+    }
+
+    /// replays every valid record of a segment,
+    /// returns the number of kept records and where the valid data ends
+    fn replay_segment(
+        &mut self,
+        file_name: &str,
+        reader: &mut LogReader<Box<dyn StorageFile>>,
+    ) -> Result<(usize, Option<SegmentDamage>)> {
+        let mut kept = 0;
+
+        match reader.format {
+            LogFormat::Binary => {
+                // a magic which was cut off or zeroed is damage like any other
+                if !reader.has_magic()? {
+                    return Ok((kept, Some(damage_at(reader, 0)?)));
+                }
+                reader.seek(SeekFrom::Start(reader.start_pos()))?;
+                // records of the batch being read, they count only once its commit record is read
+                let mut batch: Vec<LogRecord> = Vec::new();
+                loop {
+                    let record = match reader.next_record() {
+                        Ok(Some(record)) => record,
+                        Ok(None) => {
+                            self.drop_batch(&mut batch, file_name);
+                            return Ok((kept, None));
+                        }
+                        Err(LogError::Truncated { pos }) | Err(LogError::Corrupted { pos }) => {
+                            self.drop_batch(&mut batch, file_name);
+                            return Ok((kept, Some(damage_at(reader, pos)?)));
+                        }
+                        Err(e) => return Err(Error::from_log(file_name, e)),
+                    };
+                    self.seq = self.seq.max(record.seq);
+
+                    // the writer takes the sequence numbers of a whole batch up front,
+                    // a record after a batch cut off by a failed write never follows it
+                    let follows = match batch.last() {
+                        Some(last) => record.seq == last.seq + 1,
+                        None => false,
+                    };
+                    match record.command {
+                        LogCommand::BatchBegin => {
+                            self.drop_batch(&mut batch, file_name);
+                            batch.push(record);
+                        }
+                        LogCommand::BatchCommit if follows => {
+                            batch.push(record);
+                            kept += batch.len();
+                            for record in batch.drain(..) {
+                                self.replay_command(&record, file_name);
+                            }
+                        }
+                        _ if follows => batch.push(record),
+                        _ => {
+                            self.drop_batch(&mut batch, file_name);
+                            self.replay_command(&record, file_name);
+                            kept += 1;
+                        }
+                    }
+                }
+            }
+            LogFormat::Json => {
+                let mut stream = Deserializer::from_reader(reader).into_iter::<JsonLogCommand>();
+
+                let mut pos: usize = 0;
+                loop {
+                    match stream.next() {
+                        Some(Ok(command)) => {
+                            let curr_pos = stream.byte_offset();
+                            let record = LogRecord {
+                                seq: 0,
+                                keyspace: String::new(),
+                                command: command.into(),
+                                pos: pos as u64,
+                                len: (curr_pos - pos) as u64,
+                            };
+                            self.replay_command(&record, file_name);
+                            kept += 1;
+                            pos = curr_pos;
+                        }
+                        None => return Ok((kept, None)),
+                        Some(Err(e)) if e.is_io() => return Err(e.into()),
+                        Some(Err(e)) => {
+                            let damage = SegmentDamage {
+                                pos: pos as u64,
+                                torn: e.is_eof(),
+                                records: 1,
+                            };
+                            return Ok((kept, Some(damage)));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// applies a replayed record of `log_name`
+    fn replay_command(&mut self, record: &LogRecord, log_name: &str) {
+        let (keyspace, start, len) = (&record.keyspace, record.pos as usize, record.len as usize);
+        match &record.command {
+            LogCommand::Insert {
+                key, expires_at, ..
+            } => self.replay_insert(keyspace, key.to_owned(), *expires_at, log_name, start, len),
+            LogCommand::Remove { key } => self.replay_remove(keyspace, key, log_name, start, len),
+            // an operand takes the place of the value in the index just like an insert
+            LogCommand::Merge { key, .. } => {
+                self.replay_insert(keyspace, key.to_owned(), None, log_name, start, len)
+            }
+            // the framing of a batch is stale as soon as the batch is applied
+            LogCommand::BatchBegin | LogCommand::BatchCommit => {
+                add_stale(&mut self.usage, log_name, len)
+            }
+            LogCommand::DropKeyspace => self.replay_drop(keyspace, record.seq, log_name, len),
+            LogCommand::DeleteRange {
+                start: range_start,
+                end: range_end,
+            } => self.replay_delete_range(
+                keyspace,
+                range_start.to_owned(),
+                range_end.to_owned(),
+                log_name,
+                start,
+                len,
+            ),
+        }
+    }
+
+    /// forgets a batch of `log_name` which was never committed
+    fn drop_batch(&mut self, batch: &mut Vec<LogRecord>, log_name: &str) {
+        self.recovery.records_discarded += batch.len();
+
+        for record in batch.drain(..) {
+            add_stale(&mut self.usage, log_name, record.len as usize);
+        }
+    }
+
+    /// the same as `replay_command` for a record known from a hint file
+    fn replay_hint(&mut self, hint: HintEntry, log_name: &str) {
+        self.seq = self.seq.max(hint.seq);
+
+        let (start, len) = (hint.pos as usize, hint.len as usize);
+        if hint.dropped {
+            self.replay_drop(&hint.keyspace, hint.seq, log_name, len);
+        } else if let Some(range_end) = hint.range_end {
+            let (keyspace, range_start) = (hint.keyspace, hint.key);
+            self.replay_delete_range(&keyspace, range_start, range_end, log_name, start, len);
+        } else if hint.removed {
+            self.replay_remove(&hint.keyspace, &hint.key, log_name, start, len);
+        } else {
+            let (keyspace, key) = (hint.keyspace, hint.key);
+            self.replay_insert(&keyspace, key, hint.expires_at, log_name, start, len);
+        }
+    }
+
+    fn replay_insert(
+        &mut self,
+        keyspace: &str,
+        key: Vec<u8>,
+        expires_at: ExpiresAt,
+        log_name: &str,
+        start: usize,
+        len: usize,
+    ) {
+        let location = (log_name.to_owned(), start, len, expires_at);
+        let expired = is_expired(expires_at, self.now);
+        if let (Some(expires_at), false) = (expires_at, expired) {
+            self.expiries
+                .insert((expires_at, keyspace.to_owned(), key.clone()));
+        }
+
+        let keyspace = replayed_keyspace(&mut self.keyspaces, keyspace);
+        let index = keyspace.index.get_mut().expect("lock not poisoned");
+        let history = keyspace.history.get_mut().expect("lock not poisoned");
+        if expired {
+            // an expired value hides the older ones just like a removal does
+            if let Some(old) = index.remove(&key) {
+                add_stale(&mut self.usage, &old.0, old.2);
+                supersede(history, &key, old);
+            }
+            add_stale(&mut self.usage, log_name, len);
+            supersede(history, &key, location);
+        } else if let Some(old) = index.insert(key.clone(), location) {
+            add_stale(&mut self.usage, &old.0, old.2);
+            supersede(history, &key, old);
+        }
+    }
+
+    fn replay_remove(
+        &mut self,
+        keyspace: &str,
+        key: &[u8],
+        log_name: &str,
+        start: usize,
+        len: usize,
+    ) {
+        let location = (log_name.to_owned(), start, len, None);
+
+        let keyspace = replayed_keyspace(&mut self.keyspaces, keyspace);
+        let index = keyspace.index.get_mut().expect("lock not poisoned");
+        let history = keyspace.history.get_mut().expect("lock not poisoned");
+        add_stale(&mut self.usage, log_name, len);
+        if let Some(old) = index.remove(key) {
+            add_stale(&mut self.usage, &old.0, old.2);
+            supersede(history, key, old);
+        }
+        supersede(history, key, location);
+    }
+
+    fn replay_delete_range(
+        &mut self,
+        keyspace: &str,
+        range_start: Vec<u8>,
+        range_end: Vec<u8>,
+        log_name: &str,
+        start: usize,
+        len: usize,
+    ) {
+        let location = (log_name.to_owned(), start, len, None);
+
+        let keyspace = replayed_keyspace(&mut self.keyspaces, keyspace);
+        let index = keyspace.index.get_mut().expect("lock not poisoned");
+        let history = keyspace.history.get_mut().expect("lock not poisoned");
+        remove_range(
+            index,
+            history,
+            &mut self.usage,
+            range_start,
+            range_end,
+            &location,
+        );
+        add_stale(&mut self.usage, log_name, len);
+    }
+
+    /// forgets a keyspace dropped by a record of `log_name`
+    fn replay_drop(&mut self, keyspace: &str, seq: u64, log_name: &str, len: usize) {
+        if let Some(dropped) = self.keyspaces.remove(keyspace) {
+            dropped.mark_dropped(&mut self.usage);
+        }
+        add_drop(&mut self.usage, log_name, keyspace, seq, len);
+    }
+
+    fn keyspace(&mut self, name: &str) -> &mut Keyspace {
+        replayed_keyspace(&mut self.keyspaces, name)
+    }
+
+    /// the replayed keyspaces, the default one is there even if it's empty
+    fn keyspaces(&mut self) -> Keyspaces {
+        self.keyspace("");
+
+        std::mem::take(&mut self.keyspaces)
+            .into_iter()
+            .map(|(name, keyspace)| (name, Arc::new(keyspace)))
+            .collect()
+    }
+}
+
+fn replayed_keyspace<'a>(
+    keyspaces: &'a mut BTreeMap<String, Keyspace>,
+    name: &str,
+) -> &'a mut Keyspace {
+    keyspaces
+        .entry(name.to_owned())
+        .or_insert_with(|| Keyspace::new(name))
+}
+
+fn supersede(history: &mut History, key: &[u8], location: KvStoreValue) {
+    history
+        .entry(key.to_owned())
+        .or_insert_with(Vec::new)
+        .push(location);
+}
+
+/// every version of a key, oldest first
+fn locations(index: &KeyDir, history: &History, key: &[u8]) -> Vec<KvStoreValue> {
+    history
+        .get(key)
+        .into_iter()
+        .flatten()
+        .chain(index.get(key))
+        .cloned()
+        .collect()
+}
+
+/// the value of a key right after the newest of its versions `locations`, oldest first,
+/// with the operands merged since it was last written folded in,
+/// the value written last counts as expired if it was at `now` or at the first operand after it
+pub(crate) fn fold<F>(
+    key: &[u8],
+    locations: &[KvStoreValue],
+    operators: &MergeOperators,
+    now: u64,
+    mut read: F,
+) -> Result<Option<Vec<u8>>>
+where
+    F: FnMut(&KvStoreValue) -> Result<(u64, LogCommand)>,
+{
+    let mut now = now;
+    let mut operands = Vec::new();
+    let mut value = None;
+    for location in locations.iter().rev() {
+        match read(location)? {
+            (
+                seq,
+                LogCommand::Merge {
+                    operator, operand, ..
+                },
+            ) => {
+                operands.push((operator, operand));
+                now = version_millis(seq);
+            }
+            (
+                _,
+                LogCommand::Insert {
+                    value: written,
+                    expires_at,
+                    ..
+                },
+            ) => {
+                if !is_expired(expires_at, now) {
+                    value = Some(written);
+                }
+                break;
+            }
+            _ => break,
+        }
+    }
+
+    for (operator, operand) in operands.into_iter().rev() {
+        let operator = operators
+            .get(&operator)
+            .ok_or(Error::UnknownMergeOperator)?;
+        let existing = value.as_ref().map(|value| value.as_slice());
+        value = Some(operator.merge(key, existing, &operand)?);
+    }
+
+    Ok(value)
+}
+
+/// removes the keys in the range of the tombstone at `location` from the index,
+/// the tombstone becomes the newest version of each of them and their records are stale
+fn remove_range(
+    index: &mut KeyDir,
+    history: &mut History,
+    usage: &mut SegmentsUsage,
+    start: Vec<u8>,
+    end: Vec<u8>,
+    location: &KvStoreValue,
+) {
+    let (from, to) = delete_range_bounds(start, end);
+    if is_empty_range(&from, &to) {
+        return;
+    }
+
+    let keys: Vec<Vec<u8>> = index
+        .range((from, to))
+        .map(|(key, _)| key.clone())
+        .collect();
+    for key in keys {
+        if let Some(old) = index.remove(&key) {
+            add_stale(usage, &old.0, old.2);
+            supersede(history, &key, old);
+            supersede(history, &key, location.clone());
+        }
+    }
+}
+
+/// readers of concurrent `get`s, every thread takes a set of its own
+#[derive(Debug)]
+struct ReadersPool {
+    storage: Arc<dyn Storage>,
+    compactions: Arc<AtomicUsize>,
+    idle: Mutex<Vec<KvStoreReaders>>,
+}
+
+impl ReadersPool {
+    fn new(storage: Arc<dyn Storage>, compactions: Arc<AtomicUsize>) -> Self {
+        ReadersPool {
+            storage,
+            compactions,
+            idle: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn read_command(&self, location: &KvStoreValue) -> Result<(u64, LogCommand)> {
+        let popped = self.idle.lock().expect("mutex not poisoned").pop();
+        let mut readers = popped.unwrap_or_else(|| {
+            KvStoreReaders::new(Arc::clone(&self.storage), Arc::clone(&self.compactions))
+        });
+
+        let result = readers.read_command(location);
+        self.idle.lock().expect("mutex not poisoned").push(readers);
+
+        result
+    }
+}
+
+/// readers of a single thread, opened lazily
+#[derive(Debug)]
+pub(crate) struct KvStoreReaders {
+    storage: Arc<dyn Storage>,
+    /// compactions so far, each of them removes segments
+    compactions: Arc<AtomicUsize>,
+    /// compactions the open readers know about
+    seen: usize,
+    readers: HashMap<String, LogReader<Box<dyn StorageFile>>>,
+}
+
+impl KvStoreReaders {
+    pub(crate) fn new(storage: Arc<dyn Storage>, compactions: Arc<AtomicUsize>) -> Self {
+        KvStoreReaders {
+            storage,
+            seen: compactions.load(Ordering::SeqCst),
+            compactions,
+            readers: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn read_command(
+        &mut self,
+        (log_name, pos, len, _): &KvStoreValue,
+    ) -> Result<(u64, LogCommand)> {
+        // the segments a compaction left behind are reopened if a snapshot still reads them
+        let compactions = self.compactions.load(Ordering::SeqCst);
+        if compactions != self.seen {
+            self.readers.clear();
+            self.seen = compactions;
+        }
+
+        if !self.readers.contains_key(log_name) {
+            let reader = LogReader::new(self.storage.open(log_name)?)?;
+            self.readers.insert(log_name.to_owned(), reader);
+        }
+
+        self.readers
+            .get_mut(log_name)
+            .expect("reader was just opened")
+            .read_command(*pos as u64, *len as u64)
+            .map_err(|e| Error::from_log(log_name, e))
+    }
+
+    /// called by the compactor once the segments it compacted are gone
+    pub(crate) fn forget_compacted(&self) {
+        self.compactions.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// the single writer of a store, all handles and the compactor share it
+#[derive(Debug)]
+pub(crate) struct KvStoreWriter {
+    writer: LogWriter<Box<dyn StorageFile>>,
+    session_log_name: String,
+    session_hints: Vec<HintEntry>,
+    storage: Arc<dyn Storage>,
+    /// the segments which survive a crash, the session log is always one of them
+    manifest: Manifest,
+    keyspaces: Arc<RwLock<Keyspaces>>,
+    /// the version of the last write
+    seq: u64,
+    /// the newest generation of a segment, the compactor takes new ones from here too
+    last_gen: usize,
+    usage: SegmentsUsage,
+    expiries: ExpiryQueue,
+    compaction_threshold: usize,
+    max_segment_size: usize,
+    garbage_ratio: f64,
+    durability: Durability,
+    /// the session log has writes which were not synced yet
+    unsynced: bool,
+    /// compactions finished since `open` and the duration of the last one
+    compactions: u64,
+    last_compaction: Option<Duration>,
+}
+
+impl KvStoreWriter {
+    /// returns whether enough stale data piled up for a compaction
+    fn set(
+        &mut self,
+        keyspace: &Keyspace,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: ExpiresAt,
+    ) -> Result<bool> {
+        self.check_live(keyspace)?;
+        self.rotate_if_full()?;
+
+        let command = LogCommand::Insert {
+            key,
+            value,
+            expires_at,
+        };
+        let pos = self.writer.pos;
+        let seq = self.next_seq(1);
+        let len = write_record(&mut self.writer, seq, &keyspace.name, &command)?;
+        self.commit()?;
+
+        let mut index = keyspace.index.write().expect("lock not poisoned");
+        let mut history = keyspace.history.write().expect("lock not poisoned");
+        self.apply(keyspace, &mut index, &mut history, command, seq, pos, len);
+
+        Ok(self.needs_compaction())
+    }
+
+    fn remove(&mut self, keyspace: &Keyspace, key: Vec<u8>) -> Result<()> {
+        self.check_live(keyspace)?;
+        self.rotate_if_full()?;
+        match keyspace.index.read().expect("lock not poisoned").get(&key) {
+            Some(location) if !is_expired(location.3, now_millis()) => {}
+            _ => return Err(Error::KeyNotFound),
+        }
+
+        let command = LogCommand::Remove { key };
+        let pos = self.writer.pos;
+        let seq = self.next_seq(1);
+        let len = write_record(&mut self.writer, seq, &keyspace.name, &command)?;
+        self.commit()?;
+
+        let mut index = keyspace.index.write().expect("lock not poisoned");
+        let mut history = keyspace.history.write().expect("lock not poisoned");
+        self.apply(keyspace, &mut index, &mut history, command, seq, pos, len);
+
+        Ok(())
+    }
+
+    /// writes the commands between a begin and a commit record and applies them
+    /// under a single lock of the index, returns whether a compaction is due
+    fn write_batch(&mut self, keyspace: &Keyspace, commands: Vec<LogCommand>) -> Result<bool> {
+        self.check_live(keyspace)?;
+        if commands.is_empty() {
+            return Ok(false);
+        }
+        self.rotate_if_full()?;
+
+        // the whole batch takes its sequence numbers even if the write fails,
+        // so whatever made it to the log can't pass for a part of a later batch
+        let first_seq = self.next_seq(commands.len() as u64 + 2);
+
+        let start = self.writer.pos;
+        let mut buf = Vec::new();
+        let mut records = Vec::with_capacity(commands.len() + 2);
+        let framed = once(LogCommand::BatchBegin)
+            .chain(commands)
+            .chain(once(LogCommand::BatchCommit));
+        for (seq, command) in (first_seq..).zip(framed) {
+            let pos = start + buf.len() as u64;
+            let len = write_record(&mut buf, seq, &keyspace.name, &command)?;
+            records.push((command, seq, pos, len));
+        }
+        self.writer.write_all(&buf)?;
+        self.commit()?;
+
+        let mut index = keyspace.index.write().expect("lock not poisoned");
+        let mut history = keyspace.history.write().expect("lock not poisoned");
+        for (command, seq, pos, len) in records {
+            self.apply(keyspace, &mut index, &mut history, command, seq, pos, len);
+        }
+
+        Ok(self.needs_compaction())
+    }
+
+    /// writes a merge operand, returns whether a compaction is due
+    fn merge(
+        &mut self,
+        keyspace: &Keyspace,
+        key: Vec<u8>,
+        operator: &str,
+        operand: Vec<u8>,
+    ) -> Result<bool> {
+        self.check_live(keyspace)?;
+        self.rotate_if_full()?;
+
+        let command = LogCommand::Merge {
+            key,
+            operator: operator.to_owned(),
+            operand,
+        };
+        let pos = self.writer.pos;
+        let seq = self.next_seq(1);
+        let len = write_record(&mut self.writer, seq, &keyspace.name, &command)?;
+        self.commit()?;
+
+        let mut index = keyspace.index.write().expect("lock not poisoned");
+        let mut history = keyspace.history.write().expect("lock not poisoned");
+        self.apply(keyspace, &mut index, &mut history, command, seq, pos, len);
+
+        Ok(self.needs_compaction())
+    }
+
+    /// writes a range tombstone and removes the keys in the range from the index,
+    /// returns whether a compaction is due
+    fn delete_range(&mut self, keyspace: &Keyspace, start: Vec<u8>, end: Vec<u8>) -> Result<bool> {
+        self.check_live(keyspace)?;
+        self.rotate_if_full()?;
+
+        let command = LogCommand::DeleteRange { start, end };
+        let pos = self.writer.pos;
+        let seq = self.next_seq(1);
+        let len = write_record(&mut self.writer, seq, &keyspace.name, &command)?;
+        self.commit()?;
+
+        let mut index = keyspace.index.write().expect("lock not poisoned");
+        let mut history = keyspace.history.write().expect("lock not poisoned");
+        self.apply(keyspace, &mut index, &mut history, command, seq, pos, len);
+
+        Ok(self.needs_compaction())
+    }
+
+    /// writes a drop record and forgets the keyspace, its handles see it empty from now on,
+    /// returns whether a compaction is due
+    fn drop_keyspace(&mut self, name: &str) -> Result<bool> {
+        let dropped = match self.keyspaces.read().expect("lock not poisoned").get(name) {
+            Some(keyspace) => Arc::clone(keyspace),
+            None => return Ok(false),
+        };
+        self.rotate_if_full()?;
+
+        let pos = self.writer.pos;
+        let seq = self.next_seq(1);
+        let len = write_record(&mut self.writer, seq, name, &LogCommand::DropKeyspace)?;
+        self.commit()?;
+
+        self.keyspaces
+            .write()
+            .expect("lock not poisoned")
+            .remove(name);
+        self.session_hints.push(HintEntry {
+            keyspace: name.to_owned(),
+            key: Vec::new(),
+            seq,
+            pos,
+            len,
+            expires_at: None,
+            removed: false,
+            dropped: true,
+            range_end: None,
+        });
+        dropped.mark_dropped(&mut self.usage);
+        add_drop(
+            &mut self.usage,
+            &self.session_log_name,
+            name,
+            seq,
+            len as usize,
+        );
+        dropped.index.write().expect("lock not poisoned").clear();
+        dropped.history.write().expect("lock not poisoned").clear();
+
+        Ok(self.needs_compaction())
+    }
+
+    /// fails the writes to a handle of a dropped keyspace
+    fn check_live(&self, keyspace: &Keyspace) -> Result<()> {
+        match self
+            .keyspaces
+            .read()
+            .expect("lock not poisoned")
+            .get(&keyspace.name)
+        {
+            Some(live) if std::ptr::eq(&**live, keyspace) => Ok(()),
+            _ => Err(Error::KeyspaceDropped),
+        }
+    }
+
+    /// whether the segments worth compacting have enough stale bytes together
+    fn needs_compaction(&self) -> bool {
+        self.compaction_run()
+            .map_or(false, |(_, stale)| stale > self.compaction_threshold)
+    }
+
+    /// the consecutive segments with the most stale bytes among the ones
+    /// which are at least `garbage_ratio` stale, with their stale bytes
+    fn compaction_run(&self) -> Option<(Vec<String>, usize)> {
+        let mut best: Option<(Vec<String>, usize)> = None;
+        let mut run = (Vec::new(), 0);
+        let log_names = self.manifest.log_names();
+        // the `None` past the last segment ends the last run
+        for log_name in log_names.into_iter().map(Some).chain(once(None)) {
+            let usage = log_name
+                .as_ref()
+                .and_then(|log_name| self.usage.get(log_name));
+            let len = match &log_name {
+                Some(log_name) if *log_name == self.session_log_name => self.writer.pos as usize,
+                _ => usage.map_or(0, |usage| usage.len),
+            };
+            let stale = usage.map_or(0, |usage| usage.stale);
+
+            if stale > 0 && stale as f64 >= self.garbage_ratio * len as f64 {
+                run.0.extend(log_name);
+                run.1 += stale;
+            } else if !run.0.is_empty() {
+                let run = std::mem::take(&mut run);
+                if best.as_ref().map_or(true, |best| run.1 > best.1) {
+                    best = Some(run);
+                }
+            }
+        }
+
+        best
+    }
+
+    /// takes `count` sequence numbers in a row and returns the first one,
+    /// they are the write time in microseconds unless the clock is behind the last one
+    fn next_seq(&mut self, count: u64) -> u64 {
+        let first = (self.seq + 1).max(now_micros());
+        self.seq = first + count - 1;
+
+        first
+    }
+
+    /// puts a command written at `pos` of the session log into the index of its keyspace
+    fn apply(
+        &mut self,
+        keyspace: &Keyspace,
+        index: &mut KeyDir,
+        history: &mut History,
+        command: LogCommand,
+        seq: u64,
+        pos: u64,
+        len: u64,
+    ) {
+        match command {
+            LogCommand::Insert {
+                key, expires_at, ..
+            } => self.apply_value(keyspace, index, history, key, expires_at, seq, pos, len),
+            // the versions before an operand stay in the history, it's folded into them
+            LogCommand::Merge { key, .. } => {
+                self.apply_value(keyspace, index, history, key, None, seq, pos, len)
+            }
+            LogCommand::Remove { key } => {
+                self.session_hints.push(HintEntry {
+                    keyspace: keyspace.name.clone(),
+                    key: key.clone(),
+                    seq,
+                    pos,
+                    len,
+                    expires_at: None,
+                    removed: true,
+                    dropped: false,
+                    range_end: None,
+                });
+                let location = (
+                    self.session_log_name.to_owned(),
+                    pos as usize,
+                    len as usize,
+                    None,
+                );
+                let superseded = history.entry(key.clone()).or_insert_with(Vec::new);
+                // a batch can delete a key which isn't there
+                if let Some(old) = index.remove(&key) {
+                    add_stale(&mut self.usage, &old.0, old.2);
+                    superseded.push(old);
+                }
+                add_stale(&mut self.usage, &self.session_log_name, len as usize);
+                superseded.push(location);
+            }
+            LogCommand::DeleteRange { start, end } => {
+                self.session_hints.push(HintEntry {
+                    keyspace: keyspace.name.clone(),
+                    key: start.clone(),
+                    seq,
+                    pos,
+                    len,
+                    expires_at: None,
+                    removed: false,
+                    dropped: false,
+                    range_end: Some(end.clone()),
+                });
+                let location = (
+                    self.session_log_name.to_owned(),
+                    pos as usize,
+                    len as usize,
+                    None,
+                );
+                remove_range(index, history, &mut self.usage, start, end, &location);
+                add_stale(&mut self.usage, &self.session_log_name, len as usize);
+            }
+            LogCommand::BatchBegin | LogCommand::BatchCommit | LogCommand::DropKeyspace => {
+                add_stale(&mut self.usage, &self.session_log_name, len as usize)
+            }
+        }
+    }
+
+    /// puts an insert or a merge operand into the index, the value it replaces becomes history
+    fn apply_value(
+        &mut self,
+        keyspace: &Keyspace,
+        index: &mut KeyDir,
+        history: &mut History,
+        key: Vec<u8>,
+        expires_at: ExpiresAt,
+        seq: u64,
+        pos: u64,
+        len: u64,
+    ) {
+        self.session_hints.push(HintEntry {
+            keyspace: keyspace.name.clone(),
+            key: key.clone(),
+            seq,
+            pos,
+            len,
+            expires_at,
+            removed: false,
+            dropped: false,
+            range_end: None,
+        });
+        if let Some(expires_at) = expires_at {
+            self.expiries
+                .insert((expires_at, keyspace.name.clone(), key.clone()));
+        }
+        let location = (
+            self.session_log_name.to_owned(),
+            pos as usize,
+            len as usize,
+            expires_at,
+        );
+        if let Some(old) = index.insert(key.clone(), location) {
+            add_stale(&mut self.usage, &old.0, old.2);
+            history.entry(key).or_insert_with(Vec::new).push(old);
+        }
+    }
+
+    /// hands the written records to the OS and syncs them if the durability asks for it
+    fn commit(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.unsynced = true;
+
+        match self.durability {
+            Durability::Always => self.sync(),
+            Durability::EveryNMillis(_) | Durability::OsBuffered => Ok(()),
+        }
+    }
+
+    /// syncs the session log to the disk if it has unsynced writes
+    pub(crate) fn sync(&mut self) -> Result<()> {
+        if self.unsynced {
+            self.writer.flush()?;
+            self.writer.get_ref().sync()?;
+            self.unsynced = false;
+        }
+
+        Ok(())
+    }
+
+    /// drops the keys whose time ran out from the index, their records go with the next compaction,
+    /// returns whether enough stale data piled up for a compaction
+    pub(crate) fn sweep_expired(&mut self) -> bool {
+        let now = now_millis();
+        let keyspaces = Arc::clone(&self.keyspaces);
+        let keyspaces = keyspaces.read().expect("lock not poisoned");
+
+        while let Some((expires_at, name, key)) = self.expiries.iter().next().cloned() {
+            if expires_at > now {
+                break;
+            }
+            self.expiries
+                .remove(&(expires_at, name.clone(), key.clone()));
+
+            // gone along with its keyspace
+            let keyspace = match keyspaces.get(&name) {
+                Some(keyspace) => keyspace,
+                None => continue,
+            };
+            let mut index = keyspace.index.write().expect("lock not poisoned");
+            let mut history = keyspace.history.write().expect("lock not poisoned");
+
+            // the key could have been written again since
+            let current = index.get(&key).map(|location| location.3);
+            if current == Some(Some(expires_at)) {
+                if let Some(old) = index.remove(&key) {
+                    add_stale(&mut self.usage, &old.0, old.2);
+                    // the expired record stays the newest version, older ones can't come back
+                    history.entry(key).or_insert_with(Vec::new).push(old);
+                }
+            }
+        }
+        drop(keyspaces);
+
+        self.needs_compaction()
+    }
+
+    pub(crate) fn storage(&self) -> Arc<dyn Storage> {
+        Arc::clone(&self.storage)
+    }
+
+    /// a generation no segment had before
+    fn next_gen(&mut self) -> usize {
+        self.last_gen += 1;
+        self.last_gen
+    }
+
+    /// seals the session log once it reached the maximum size
+    fn rotate_if_full(&mut self) -> Result<()> {
+        if self.writer.pos as usize >= self.max_segment_size {
+            self.rotate()?;
+        }
+
+        Ok(())
+    }
+
+    /// seals the session log and moves the writes to a fresh segment
+    fn rotate(&mut self) -> Result<()> {
+        self.seal_session_log()?;
+
+        let gen = self.next_gen();
+        let log_name = format!("{}.{}", gen, "log");
+        let writer = LogWriter::new(self.storage.create(&log_name)?)?;
+        let manifest = self.manifest.with_segment(gen);
+        manifest.write(self.storage.as_ref())?;
+
+        let sealed = std::mem::replace(&mut self.session_log_name, log_name);
+        self.usage.entry(sealed).or_default().len = self.writer.pos as usize;
+        self.manifest = manifest;
+        self.writer = writer;
+        self.session_hints.clear();
+
+        Ok(())
+    }
+
+    /// picks the segments a compaction rewrites and seals the session log if it's among them
+    pub(crate) fn plan_compaction(&mut self) -> Result<Option<CompactionPlan>> {
+        let (run, _) = match self.compaction_run() {
+            Some(run) => run,
+            None => return Ok(None),
+        };
+
+        let comp_gen = self.next_gen();
+        if run.contains(&self.session_log_name) {
+            self.rotate()?;
+        }
+
+        let older: HashSet<String> = self
+            .manifest
+            .log_names()
+            .into_iter()
+            .take_while(|log_name| !run.contains(log_name))
+            .collect();
+        // with nothing before them, the drops have no records left to hide
+        let mut drops = Vec::new();
+        if !older.is_empty() {
+            for log_name in &run {
+                if let Some(usage) = self.usage.get(log_name) {
+                    drops.extend(usage.drops.iter().cloned());
+                }
+            }
+        }
+
+        Ok(Some(CompactionPlan {
+            run: run.into_iter().collect(),
+            older,
+            drops,
+            comp_gen,
+        }))
+    }
+
+    /// puts the compacted segment in the manifest in place of the ones it was written from
+    pub(crate) fn install_compacted(&mut self, plan: &CompactionPlan) -> Result<()> {
+        let replaced: Vec<usize> = plan.run.iter().map(|log_name| log_gen(log_name)).collect();
+        let manifest = self.manifest.with_replaced(&replaced, plan.comp_gen);
+        manifest.write(self.storage.as_ref())?;
+        self.manifest = manifest;
+
+        Ok(())
+    }
+
+    /// accounts for the compacted segment of `len` bytes, `stale` of them in its records,
+    /// in place of the ones it was written from
+    pub(crate) fn record_compacted(
+        &mut self,
+        plan: &CompactionPlan,
+        len: usize,
+        stale: usize,
+        took: Duration,
+    ) {
+        self.compactions += 1;
+        self.last_compaction = Some(took);
+        for log_name in &plan.run {
+            self.usage.remove(log_name);
+        }
+
+        let usage = self
+            .usage
+            .entry(format!("{}.{}", plan.comp_gen, "log"))
+            .or_default();
+        usage.len = len;
+        usage.stale += stale;
+        usage.drops = plan.drops.clone();
+    }
+
+    /// flushes the session log and hands out the live segments with the length of the session log
+    fn checkpoint_state(&mut self) -> Result<(Manifest, String, u64)> {
+        self.writer.flush()?;
+
+        Ok((
+            self.manifest.clone(),
+            self.session_log_name.clone(),
+            self.writer.pos,
+        ))
+    }
+
+    /// adds the sizes of the live segments and the compactions to `stats`
+    fn add_stats(&self, stats: &mut EngineStats) {
+        for log_name in self.manifest.log_names() {
+            let usage = self.usage.get(&log_name);
+            let len = if log_name == self.session_log_name {
+                self.writer.pos
+            } else {
+                usage.map_or(0, |usage| usage.len as u64)
+            };
+            let stale = usage.map_or(0, |usage| usage.stale as u64).min(len);
+
+            stats.disk_bytes += len;
+            stats.live_bytes += len - stale;
+            stats.dead_bytes += stale;
+            stats.segments.push(SegmentStats {
+                name: log_name,
+                bytes: len,
+                dead_bytes: stale,
+            });
+        }
+
+        stats.compactions = self.compactions;
+        stats.last_compaction_millis = self.last_compaction.map(|took| took.as_millis() as u64);
+    }
+
+    /// flushes the session log and writes a hint file for it, so the next `open` can skip it
+    fn seal_session_log(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        if self.durability != Durability::OsBuffered {
+            self.sync()?;
+        }
+
+        if self.session_hints.is_empty() {
+            return Ok(());
+        }
+
+        write_hint_file(
+            self.storage.as_ref(),
+            &self.session_log_name,
+            self.writer.pos,
+            &self.session_hints,
+        )
+    }
+}
+
+impl Drop for KvStoreWriter {
+    fn drop(&mut self) {
+        let _ = self.seal_session_log();
+    }
 }
 
 // #[cfg(test)]
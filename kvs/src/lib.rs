@@ -4,12 +4,32 @@
 
 // #![deny(missing_docs)]
 
+pub use crate::batch::WriteBatch;
+pub use crate::dump::{export, import};
 pub use crate::engine::KvsEngine;
-pub use crate::kvs::{KvStore, Result};
+pub use crate::error::Error;
+pub use crate::kvs::{KvStore, RecoveryMode, RecoveryReport, Result};
+pub use crate::memory::MemoryEngine;
+pub use crate::merge::{Add, Append, JsonSetUnion, MergeOperator};
+pub use crate::options::{Durability, Options, Retention};
 pub use crate::sled_engine::SledKvsEngine;
+pub use crate::stats::{EngineStats, SegmentStats};
+pub use crate::storage::{DiskStorage, MemoryStorage, Storage, StorageFile};
 
+mod batch;
+mod compaction;
+mod dump;
 mod engine;
 mod error;
+mod hint;
 mod kvs;
+mod lock;
 mod log;
+mod manifest;
+mod memory;
+mod merge;
+mod options;
+mod periodic;
 mod sled_engine;
+mod stats;
+mod storage;
@@ -0,0 +1,65 @@
+use fs2::FileExt;
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::error::Error;
+
+const LOCK_FILE_NAME: &'static str = "LOCK";
+
+/// advisory lock of the writer of a data directory, released when dropped,
+/// read-only stores don't take it and never keep a writer out
+#[derive(Debug)]
+pub struct DirLock {
+    file: File,
+}
+
+impl DirLock {
+    /// the only way to get a store which writes, the owner's pid is left in the lock file
+    pub fn exclusive(path: &Path) -> Result<Self, Error> {
+        let mut file = DirLock::open_lock_file(path)?;
+        if let Err(e) = FileExt::try_lock_exclusive(&file) {
+            if !DirLock::is_contended(&e) {
+                return Err(e.into());
+            }
+            return Err(Error::Locked {
+                pid: DirLock::read_owner(&mut file),
+            });
+        }
+
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        write!(file, "{}", std::process::id())?;
+        file.sync_all()?;
+
+        Ok(DirLock { file })
+    }
+
+    fn open_lock_file(path: &Path) -> Result<File, Error> {
+        Ok(OpenOptions::new()
+            .create(true)
+            .read(true)
+            .write(true)
+            .open(path.join(LOCK_FILE_NAME))?)
+    }
+
+    /// only a lock held by someone else means the store is locked, any other failure is an io error
+    fn is_contended(e: &io::Error) -> bool {
+        e.kind() == io::ErrorKind::WouldBlock
+            || e.raw_os_error() == fs2::lock_contended_error().raw_os_error()
+    }
+
+    fn read_owner(file: &mut File) -> Option<u32> {
+        let mut pid = String::new();
+        file.read_to_string(&mut pid).ok()?;
+
+        pid.trim().parse().ok()
+    }
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        let _ = FileExt::unlock(&self.file);
+    }
+}
@@ -1,50 +1,514 @@
 use serde::{Deserialize, Serialize};
 
 use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::iter::once;
 
 use crate::error::Error;
+use crate::storage::{Storage, StorageFile};
 
 const LOG_FILE_EXTENSION_NAME: &'static str = "log";
 const DEFAULT_LOG_NAME: &'static str = "1.log";
 
-enum LogError {}
+/// every binary segment starts with this magic, json segments never do
+pub const SEGMENT_MAGIC: &'static [u8; 8] = b"KVSLOG01";
 
-#[derive(Deserialize, Serialize, Debug)]
+// crc32 | seq | kind | key len | value len
+const RECORD_HEADER_LEN: usize = 4 + 8 + 1 + 4 + 4;
+
+const RECORD_KIND_INSERT: u8 = 0;
+const RECORD_KIND_REMOVE: u8 = 1;
+/// an insert whose payload starts with the expiry time
+const RECORD_KIND_INSERT_EXPIRING: u8 = 2;
+const EXPIRY_LEN: usize = 8;
+/// opens a write batch, its records have the sequence numbers right after this one
+const RECORD_KIND_BATCH_BEGIN: u8 = 3;
+/// closes a write batch, a batch without it was never acknowledged
+const RECORD_KIND_BATCH_COMMIT: u8 = 4;
+/// drops every record of its keyspace written before it
+const RECORD_KIND_KEYSPACE_DROP: u8 = 5;
+/// removes the keys of its keyspace in a range, the key is the start and the value the end
+const RECORD_KIND_DELETE_RANGE: u8 = 6;
+/// a merge operand, the value starts with the length of the operator name and the name itself
+const RECORD_KIND_MERGE: u8 = 7;
+/// set on the kind of a record of a named keyspace,
+/// its key starts with the length of the keyspace name and the name itself
+const RECORD_KEYSPACE_FLAG: u8 = 0x80;
+
+#[derive(Debug)]
+pub enum LogError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    /// the record at `pos` does not match its checksum
+    Corrupted {
+        pos: u64,
+    },
+    /// the segment ends in the middle of the record at `pos`
+    Truncated {
+        pos: u64,
+    },
+}
+
+impl From<io::Error> for LogError {
+    fn from(e: io::Error) -> Self {
+        LogError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for LogError {
+    fn from(e: serde_json::Error) -> Self {
+        LogError::Json(e)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum LogCommand {
+    Insert {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        /// unix time in milliseconds after which the key is gone
+        expires_at: Option<u64>,
+    },
+    Remove {
+        key: Vec<u8>,
+    },
+    BatchBegin,
+    BatchCommit,
+    DropKeyspace,
+    /// a range tombstone, an empty `end` leaves the range open
+    DeleteRange {
+        start: Vec<u8>,
+        end: Vec<u8>,
+    },
+    /// an operand folded into the value before it by the merge operator named `operator`
+    Merge {
+        key: Vec<u8>,
+        operator: String,
+        operand: Vec<u8>,
+    },
+}
+
+/// a command of a legacy json segment, those could only hold strings
+#[derive(Deserialize, Serialize, Debug)]
+pub enum JsonLogCommand {
     Insert { key: String, value: String },
     Remove { key: String },
 }
 
+impl From<JsonLogCommand> for LogCommand {
+    fn from(command: JsonLogCommand) -> Self {
+        match command {
+            JsonLogCommand::Insert { key, value } => LogCommand::Insert {
+                key: key.into_bytes(),
+                value: value.into_bytes(),
+                expires_at: None,
+            },
+            JsonLogCommand::Remove { key } => LogCommand::Remove {
+                key: key.into_bytes(),
+            },
+        }
+    }
+}
+
+/// on-disk encoding of a segment
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// legacy segments: a stream of json encoded `LogCommand`s
+    Json,
+    /// length-prefixed, checksummed records after `SEGMENT_MAGIC`
+    Binary,
+}
+
+/// a decoded binary record
+#[derive(Debug)]
+pub struct LogRecord {
+    pub seq: u64,
+    /// empty for the default keyspace
+    pub keyspace: String,
+    pub command: LogCommand,
+    pub pos: u64,
+    pub len: u64,
+}
+
 pub fn create_log_file(
     existed_log_files_names: &Vec<String>,
-    path: &std::path::Path,
-) -> Result<(std::fs::File, String), Error> {
+    storage: &dyn Storage,
+) -> Result<(Box<dyn StorageFile>, String), Error> {
+    // segments are replayed in the order of the manifest, the newest generation can be anywhere
     let last_i = existed_log_files_names
-        .last()
-        .unwrap_or(&DEFAULT_LOG_NAME.to_string())
-        .trim_end_matches(".log")
-        .parse::<usize>()
-        .expect("error while trying to parse log's name number part");
+        .iter()
+        .chain(once(&DEFAULT_LOG_NAME.to_string()))
+        .map(|name| {
+            name.trim_end_matches(".log")
+                .parse::<usize>()
+                .expect("error while trying to parse log's name number part")
+        })
+        .max()
+        .expect("the default log name is there");
 
     let log_name = format!("{}.{}", last_i + 1, LOG_FILE_EXTENSION_NAME);
-    let file = std::fs::File::create(path.join(&log_name))?;
+    let file = storage.create(&log_name)?;
 
     Ok((file, log_name))
 }
 
+/// serializes `command` of `keyspace` as a binary record and returns its length in bytes
+pub fn write_record<W: Write>(
+    writer: &mut W,
+    seq: u64,
+    keyspace: &str,
+    command: &LogCommand,
+) -> io::Result<u64> {
+    let merged;
+    let (kind, key, value, expires_at) = match command {
+        LogCommand::Insert {
+            key,
+            value,
+            expires_at: None,
+        } => (RECORD_KIND_INSERT, key.as_slice(), value.as_slice(), None),
+        LogCommand::Insert {
+            key,
+            value,
+            expires_at: Some(expires_at),
+        } => (
+            RECORD_KIND_INSERT_EXPIRING,
+            key.as_slice(),
+            value.as_slice(),
+            Some(expires_at),
+        ),
+        LogCommand::Remove { key } => (RECORD_KIND_REMOVE, key.as_slice(), &[][..], None),
+        LogCommand::BatchBegin => (RECORD_KIND_BATCH_BEGIN, &[][..], &[][..], None),
+        LogCommand::BatchCommit => (RECORD_KIND_BATCH_COMMIT, &[][..], &[][..], None),
+        LogCommand::DropKeyspace => (RECORD_KIND_KEYSPACE_DROP, &[][..], &[][..], None),
+        LogCommand::DeleteRange { start, end } => (
+            RECORD_KIND_DELETE_RANGE,
+            start.as_slice(),
+            end.as_slice(),
+            None,
+        ),
+        LogCommand::Merge {
+            key,
+            operator,
+            operand,
+        } => {
+            let mut value = Vec::with_capacity(1 + operator.len() + operand.len());
+            value.push(operator.len() as u8);
+            value.extend_from_slice(operator.as_bytes());
+            value.extend_from_slice(operand);
+            merged = value;
+
+            (RECORD_KIND_MERGE, key.as_slice(), merged.as_slice(), None)
+        }
+    };
+    let (kind, keyspace_len) = match keyspace.len() {
+        0 => (kind, 0),
+        len => (kind | RECORD_KEYSPACE_FLAG, 1 + len),
+    };
+
+    let mut body = Vec::with_capacity(
+        RECORD_HEADER_LEN - 4 + EXPIRY_LEN + keyspace_len + key.len() + value.len(),
+    );
+    body.extend_from_slice(&seq.to_le_bytes());
+    body.push(kind);
+    body.extend_from_slice(&((keyspace_len + key.len()) as u32).to_le_bytes());
+    body.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    if let Some(expires_at) = expires_at {
+        body.extend_from_slice(&expires_at.to_le_bytes());
+    }
+    if keyspace_len > 0 {
+        body.push(keyspace.len() as u8);
+        body.extend_from_slice(keyspace.as_bytes());
+    }
+    body.extend_from_slice(key);
+    body.extend_from_slice(value);
+
+    writer.write_all(&crc32fast::hash(&body).to_le_bytes())?;
+    writer.write_all(&body)?;
+
+    Ok(4 + body.len() as u64)
+}
+
+/// length of the part of a record's payload which comes before the key
+fn payload_prefix_len(kind: u8) -> usize {
+    match kind & !RECORD_KEYSPACE_FLAG {
+        RECORD_KIND_INSERT_EXPIRING => EXPIRY_LEN,
+        _ => 0,
+    }
+}
+
+fn decode_record_body(body: &[u8], key_len: usize, kind: u8) -> Option<(String, LogCommand)> {
+    let (prefix, body) = body.split_at(payload_prefix_len(kind));
+    let (keyspace, body, key_len) = match kind & RECORD_KEYSPACE_FLAG {
+        0 => (String::new(), body, key_len),
+        _ => {
+            let keyspace_len = 1 + *body.first()? as usize;
+            if keyspace_len > key_len {
+                return None;
+            }
+            let keyspace = String::from_utf8(body[1..keyspace_len].to_vec()).ok()?;
+            (keyspace, &body[keyspace_len..], key_len - keyspace_len)
+        }
+    };
+    let key = body[..key_len].to_vec();
+    let value = body[key_len..].to_vec();
+
+    let command = match kind & !RECORD_KEYSPACE_FLAG {
+        RECORD_KIND_INSERT => Some(LogCommand::Insert {
+            key,
+            value,
+            expires_at: None,
+        }),
+        RECORD_KIND_INSERT_EXPIRING => {
+            let mut expires_at = [0u8; EXPIRY_LEN];
+            expires_at.copy_from_slice(prefix);
+
+            Some(LogCommand::Insert {
+                key,
+                value,
+                expires_at: Some(u64::from_le_bytes(expires_at)),
+            })
+        }
+        RECORD_KIND_REMOVE => Some(LogCommand::Remove { key }),
+        RECORD_KIND_BATCH_BEGIN => Some(LogCommand::BatchBegin),
+        RECORD_KIND_BATCH_COMMIT => Some(LogCommand::BatchCommit),
+        RECORD_KIND_KEYSPACE_DROP => Some(LogCommand::DropKeyspace),
+        RECORD_KIND_DELETE_RANGE => Some(LogCommand::DeleteRange {
+            start: key,
+            end: value,
+        }),
+        RECORD_KIND_MERGE => {
+            let operator_len = 1 + *value.first()? as usize;
+            if operator_len > value.len() {
+                return None;
+            }
+
+            Some(LogCommand::Merge {
+                key,
+                operator: String::from_utf8(value[1..operator_len].to_vec()).ok()?,
+                operand: value[operator_len..].to_vec(),
+            })
+        }
+        _ => None,
+    };
+
+    command.map(|command| (keyspace, command))
+}
+
+/// crc | seq | kind | key len | value len of a record header
+fn decode_header(header: &[u8; RECORD_HEADER_LEN]) -> (u32, u64, u8, usize, usize) {
+    let mut u32_buf = [0u8; 4];
+    let mut u64_buf = [0u8; 8];
+
+    u32_buf.copy_from_slice(&header[0..4]);
+    let crc = u32::from_le_bytes(u32_buf);
+    u64_buf.copy_from_slice(&header[4..12]);
+    let seq = u64::from_le_bytes(u64_buf);
+    let kind = header[12];
+    u32_buf.copy_from_slice(&header[13..17]);
+    let key_len = u32::from_le_bytes(u32_buf) as usize;
+    u32_buf.copy_from_slice(&header[17..21]);
+    let value_len = u32::from_le_bytes(u32_buf) as usize;
+
+    (crc, seq, kind, key_len, value_len)
+}
+
+/// the checksum covers everything after itself
+fn checksum(header: &[u8; RECORD_HEADER_LEN], payload: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(&header[4..]);
+    hasher.update(payload);
+    hasher.finalize()
+}
+
+/// length of the valid record `buf` starts with, if it does
+fn valid_record_len(buf: &[u8]) -> Option<usize> {
+    if buf.len() < RECORD_HEADER_LEN {
+        return None;
+    }
+    let mut header = [0u8; RECORD_HEADER_LEN];
+    header.copy_from_slice(&buf[..RECORD_HEADER_LEN]);
+    let (crc, _, kind, key_len, value_len) = decode_header(&header);
+
+    let payload_len = payload_prefix_len(kind)
+        .checked_add(key_len)?
+        .checked_add(value_len)?;
+    let payload = buf[RECORD_HEADER_LEN..].get(..payload_len)?;
+    if checksum(&header, payload) != crc {
+        return None;
+    }
+    decode_record_body(payload, key_len, kind)?;
+
+    Some(RECORD_HEADER_LEN + payload_len)
+}
+
+/// what follows a record which can't be read, up to the end of the segment
+#[derive(Debug, Default, PartialEq)]
+pub struct DamagedTail {
+    /// the records which can't be read, runs of zeros aside, and the valid ones after them
+    pub records: usize,
+    /// a valid record follows the damage, so it isn't a write which was cut off
+    pub resumes: bool,
+}
+
+/// looks for valid records at every offset of `tail`, which starts with a damaged record
+pub fn scan_damaged_tail(tail: &[u8]) -> DamagedTail {
+    let mut scanned = DamagedTail::default();
+    let mut damaged_from = Some(0);
+    let mut pos = 1;
+    while pos < tail.len() {
+        match valid_record_len(&tail[pos..]) {
+            Some(len) => {
+                if let Some(from) = damaged_from.take() {
+                    scanned.records += count_damaged(&tail[from..pos]);
+                }
+                scanned.records += 1;
+                scanned.resumes = true;
+                pos += len;
+            }
+            None => {
+                damaged_from.get_or_insert(pos);
+                pos += 1;
+            }
+        }
+    }
+    if let Some(from) = damaged_from {
+        scanned.records += count_damaged(&tail[from..]);
+    }
+
+    scanned
+}
+
+/// a run of zeros is padding the file system left, anything else is a damaged record
+fn count_damaged(bytes: &[u8]) -> usize {
+    match bytes.iter().all(|&byte| byte == 0) {
+        true => 0,
+        false => 1,
+    }
+}
+
 #[derive(Debug)]
 pub struct LogReader<T: Read + Seek> {
     reader: std::io::BufReader<T>,
     pos: u64,
+    pub format: LogFormat,
 }
 
 impl<T: Read + Seek> LogReader<T> {
     // -------------------------------------------Log error
     pub fn new(mut reader: T) -> io::Result<Self> {
         let pos = reader.seek(SeekFrom::Current(0))?;
+        let format = detect_format(&mut reader)?;
+        reader.seek(SeekFrom::Start(pos))?;
         let reader = BufReader::new(reader);
 
-        Ok(LogReader { reader, pos })
+        Ok(LogReader {
+            reader,
+            pos,
+            format,
+        })
+    }
+
+    pub fn pos(&self) -> u64 {
+        self.pos
+    }
+
+    /// position of the first record in the segment
+    pub fn start_pos(&self) -> u64 {
+        match self.format {
+            LogFormat::Json => 0,
+            LogFormat::Binary => SEGMENT_MAGIC.len() as u64,
+        }
+    }
+
+    /// whether a binary segment starts with `SEGMENT_MAGIC`, an empty one is fine as it is
+    pub fn has_magic(&mut self) -> io::Result<bool> {
+        self.seek(SeekFrom::Start(0))?;
+        let mut magic = Vec::with_capacity(SEGMENT_MAGIC.len());
+        self.take(SEGMENT_MAGIC.len() as u64)
+            .read_to_end(&mut magic)?;
+
+        Ok(magic.is_empty() || &magic[..] == &SEGMENT_MAGIC[..])
+    }
+
+    /// reads the binary record at the current position,
+    /// `None` means a clean end of the segment
+    pub fn next_record(&mut self) -> Result<Option<LogRecord>, LogError> {
+        let pos = self.pos;
+
+        let mut header = [0u8; RECORD_HEADER_LEN];
+        let mut read = 0;
+        while read < RECORD_HEADER_LEN {
+            match self.read(&mut header[read..]) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        if read == 0 {
+            return Ok(None);
+        }
+        if read < RECORD_HEADER_LEN {
+            return Err(LogError::Truncated { pos });
+        }
+
+        let (crc, seq, kind, key_len, value_len) = decode_header(&header);
+
+        let payload_len = (payload_prefix_len(kind) + key_len + value_len) as u64;
+        let mut payload = Vec::new();
+        if self.by_ref().take(payload_len).read_to_end(&mut payload)? as u64 != payload_len {
+            return Err(LogError::Truncated { pos });
+        }
+
+        if checksum(&header, &payload) != crc {
+            return Err(LogError::Corrupted { pos });
+        }
+
+        let (keyspace, command) =
+            decode_record_body(&payload, key_len, kind).ok_or(LogError::Corrupted { pos })?;
+
+        Ok(Some(LogRecord {
+            seq,
+            keyspace,
+            command,
+            pos,
+            len: self.pos - pos,
+        }))
+    }
+
+    /// reads a single command stored at `pos`, json commands have no sequence number
+    pub fn read_command(&mut self, pos: u64, len: u64) -> Result<(u64, LogCommand), LogError> {
+        self.seek(SeekFrom::Start(pos))?;
+
+        match self.format {
+            LogFormat::Binary => match self.next_record()? {
+                Some(LogRecord { seq, command, .. }) => Ok((seq, command)),
+                None => Err(LogError::Truncated { pos }),
+            },
+            LogFormat::Json => {
+                let command: JsonLogCommand = serde_json::from_reader(self.take(len))?;
+                Ok((0, command.into()))
+            }
+        }
+    }
+}
+
+/// a json segment starts with a whole command, so a header shorter than the magic
+/// or made of zeros is a binary segment whose magic was cut off
+fn detect_format<T: Read + Seek>(reader: &mut T) -> io::Result<LogFormat> {
+    reader.seek(SeekFrom::Start(0))?;
+
+    let mut magic = Vec::with_capacity(SEGMENT_MAGIC.len());
+    reader
+        .take(SEGMENT_MAGIC.len() as u64)
+        .read_to_end(&mut magic)?;
+
+    if magic.len() < SEGMENT_MAGIC.len()
+        || &magic[..] == &SEGMENT_MAGIC[..]
+        || magic.iter().all(|&byte| byte == 0)
+    {
+        Ok(LogFormat::Binary)
+    } else {
+        Ok(LogFormat::Json)
     }
 }
 
@@ -74,11 +538,24 @@ pub struct LogWriter<T: Write + Seek> {
 }
 
 impl<T: Write + Seek> LogWriter<T> {
+    /// a fresh segment gets `SEGMENT_MAGIC` written at its beginning
     pub fn new(mut writer: T) -> io::Result<Self> {
-        let pos = writer.seek(SeekFrom::Start(0))?;
-        let writer = BufWriter::new(writer);
+        let pos = writer.seek(SeekFrom::End(0))?;
+        let mut writer = LogWriter {
+            writer: BufWriter::new(writer),
+            pos,
+        };
+
+        if pos == 0 {
+            writer.write_all(SEGMENT_MAGIC)?;
+            writer.flush()?;
+        }
+
+        Ok(writer)
+    }
 
-        Ok(LogWriter { writer, pos })
+    pub fn get_ref(&self) -> &T {
+        self.writer.get_ref()
     }
 }
 
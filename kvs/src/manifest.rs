@@ -0,0 +1,116 @@
+use crate::error::Error;
+use crate::hint::{read_u32, read_u64};
+use crate::storage::Storage;
+use std::io::{self, Read, Write};
+
+pub const MANIFEST_FILE_NAME: &'static str = "MANIFEST";
+const MANIFEST_MAGIC: &'static [u8; 8] = b"KVSMANI1";
+
+// magic | generations count
+const MANIFEST_HEADER_LEN: usize = 8 + 8;
+
+/// the segments which make up the store in the order they are replayed,
+/// a segment it doesn't list is a leftover
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Manifest {
+    generations: Vec<usize>,
+}
+
+impl Manifest {
+    pub fn new<I: IntoIterator<Item = usize>>(generations: I) -> Self {
+        Manifest {
+            generations: generations.into_iter().collect(),
+        }
+    }
+
+    /// names of the live segments in replay order
+    pub fn log_names(&self) -> Vec<String> {
+        self.generations
+            .iter()
+            .map(|gen| format!("{}.log", gen))
+            .collect()
+    }
+
+    /// the manifest with a new segment replayed after all the others
+    pub fn with_segment(&self, gen: usize) -> Self {
+        let mut manifest = self.clone();
+        manifest.generations.push(gen);
+
+        manifest
+    }
+
+    /// the manifest once the consecutive segments `replaced` were compacted into `gen`,
+    /// which is replayed in their place
+    pub fn with_replaced(&self, replaced: &[usize], gen: usize) -> Self {
+        let at = self
+            .generations
+            .iter()
+            .position(|old| replaced.contains(old))
+            .unwrap_or(self.generations.len());
+
+        let mut manifest = self.clone();
+        manifest.generations.retain(|old| !replaced.contains(old));
+        manifest.generations.insert(at, gen);
+
+        manifest
+    }
+
+    /// atomically replaces the manifest of `storage`
+    pub fn write(&self, storage: &dyn Storage) -> Result<(), Error> {
+        let mut buf = Vec::with_capacity(MANIFEST_HEADER_LEN + self.generations.len() * 8 + 4);
+        buf.extend_from_slice(MANIFEST_MAGIC);
+        buf.extend_from_slice(&(self.generations.len() as u64).to_le_bytes());
+        for gen in &self.generations {
+            buf.extend_from_slice(&(*gen as u64).to_le_bytes());
+        }
+
+        let crc = crc32fast::hash(&buf);
+        buf.extend_from_slice(&crc.to_le_bytes());
+
+        let tmp_name = format!("{}.tmp", MANIFEST_FILE_NAME);
+        let mut file = storage.create(&tmp_name)?;
+        file.write_all(&buf)?;
+        file.sync()?;
+        storage.rename(&tmp_name, MANIFEST_FILE_NAME)?;
+        storage.sync()?;
+
+        Ok(())
+    }
+
+    /// loads the manifest of `storage`, `None` for a store written before there was one
+    pub fn read(storage: &dyn Storage) -> Result<Option<Self>, Error> {
+        let mut buf = Vec::new();
+        match storage.open(MANIFEST_FILE_NAME) {
+            Ok(mut file) => file.read_to_end(&mut buf)?,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+
+        // unlike a hint file, the manifest can't be rebuilt from the segments
+        decode_manifest(&buf).map(Some).ok_or(Error::Corruption {
+            segment: MANIFEST_FILE_NAME.to_owned(),
+            offset: 0,
+        })
+    }
+}
+
+fn decode_manifest(buf: &[u8]) -> Option<Manifest> {
+    if buf.len() < MANIFEST_HEADER_LEN + 4 || &buf[..8] != &MANIFEST_MAGIC[..] {
+        return None;
+    }
+
+    let (content, crc) = buf.split_at(buf.len() - 4);
+    if crc32fast::hash(content) != read_u32(crc, 0) {
+        return None;
+    }
+
+    let count = read_u64(content, 8) as usize;
+    if content.len() != MANIFEST_HEADER_LEN + count * 8 {
+        return None;
+    }
+    let generations = (0..count)
+        .map(|i| read_u64(content, MANIFEST_HEADER_LEN + i * 8) as usize)
+        .collect();
+
+    Some(Manifest { generations })
+}
@@ -0,0 +1,678 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ops::{Bound, RangeBounds};
+use std::path::Path;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+
+use crate::{
+    engine::{check_keyspace_name, delete_range_bounds, ScanIter},
+    error::Error,
+    kvs::{
+        expires_at, is_empty_range, is_expired, now_micros, now_millis, owned_bound, version_millis,
+    },
+    log::LogCommand,
+    merge::MergeOperators,
+    options::{Options, Retention},
+    periodic::Periodic,
+    EngineStats, KvsEngine, Result, WriteBatch,
+};
+
+/// the current value of a key
+#[derive(Debug, Clone)]
+struct Entry {
+    value: Vec<u8>,
+    expires_at: Option<u64>,
+    version: u64,
+    /// the tick of the last use of the key, its place in the eviction order
+    used: u64,
+}
+
+/// a version older than the current value, `None` for a removal
+#[derive(Debug, Clone)]
+struct Version {
+    version: u64,
+    value: Option<Vec<u8>>,
+    expires_at: Option<u64>,
+}
+
+impl Version {
+    /// the value the version holds at `now`
+    fn value_at(&self, now: u64) -> Option<Vec<u8>> {
+        match self.expires_at {
+            Some(expires_at) if expires_at <= now => None,
+            _ => self.value.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct MemoryKeyspace {
+    /// tells the keyspace apart from one opened under the same name after it was dropped
+    id: u64,
+    entries: BTreeMap<Vec<u8>, Entry>,
+    /// the superseded versions of the keys the retention keeps, oldest first
+    history: HashMap<Vec<u8>, Vec<Version>>,
+}
+
+impl MemoryKeyspace {
+    fn new(id: u64) -> Self {
+        MemoryKeyspace {
+            id,
+            entries: BTreeMap::new(),
+            history: HashMap::new(),
+        }
+    }
+
+    fn live_value(&self, key: &[u8], now: u64) -> Option<&Vec<u8>> {
+        match self.entries.get(key) {
+            Some(entry) if !is_expired(entry.expires_at, now) => Some(&entry.value),
+            _ => None,
+        }
+    }
+
+    /// every version of `key`, oldest first
+    fn versions(&self, key: &[u8]) -> Vec<Version> {
+        let mut versions = self.history.get(key).cloned().unwrap_or_default();
+        if let Some(entry) = self.entries.get(key) {
+            versions.push(Version {
+                version: entry.version,
+                value: Some(entry.value.clone()),
+                expires_at: entry.expires_at,
+            });
+        }
+        versions
+    }
+}
+
+/// everything the handles of an engine share
+#[derive(Debug, Clone)]
+struct MemoryState {
+    keyspaces: BTreeMap<String, MemoryKeyspace>,
+    /// keyspace and key of every current value by the tick of its last use,
+    /// the first one is evicted first
+    lru: BTreeMap<u64, (String, Vec<u8>)>,
+    tick: u64,
+    /// keys written with a ttl by expiry time, the sweeper clears them out
+    expiries: BTreeSet<(u64, String, Vec<u8>)>,
+    /// bytes of the keys and the current values
+    size: usize,
+    limit: Option<usize>,
+    retention: Retention,
+    operators: MergeOperators,
+    /// the version of the last write
+    last_version: u64,
+    last_keyspace_id: u64,
+}
+
+impl MemoryState {
+    fn keyspace(&self, name: &str, id: u64) -> Option<&MemoryKeyspace> {
+        self.keyspaces
+            .get(name)
+            .filter(|keyspace| keyspace.id == id)
+    }
+
+    fn check_live(&self, name: &str, id: u64) -> Result<()> {
+        match self.keyspace(name, id) {
+            Some(_) => Ok(()),
+            None => Err(Error::KeyspaceDropped),
+        }
+    }
+
+    /// a version is the unix time in microseconds unless writes come in faster
+    fn next_version(&mut self) -> u64 {
+        self.last_version = (self.last_version + 1).max(now_micros());
+        self.last_version
+    }
+
+    /// moves `key` to the end of the eviction order
+    fn touch(&mut self, name: &str, key: &[u8]) {
+        let keyspace = match self.keyspaces.get_mut(name) {
+            Some(keyspace) => keyspace,
+            None => return,
+        };
+        if let Some(entry) = keyspace.entries.get_mut(key) {
+            self.tick += 1;
+            if let Some(used) = self.lru.remove(&entry.used) {
+                self.lru.insert(self.tick, used);
+            }
+            entry.used = self.tick;
+        }
+    }
+
+    /// takes the current value of `key` out, into the history
+    fn take_entry(&mut self, name: &str, key: &[u8]) -> Option<Entry> {
+        let keyspace = self.keyspaces.get_mut(name)?;
+        let entry = keyspace.entries.remove(key)?;
+        self.lru.remove(&entry.used);
+        self.size -= key.len() + entry.value.len();
+        keyspace
+            .history
+            .entry(key.to_vec())
+            .or_default()
+            .push(Version {
+                version: entry.version,
+                value: Some(entry.value.clone()),
+                expires_at: entry.expires_at,
+            });
+
+        Some(entry)
+    }
+
+    fn insert(
+        &mut self,
+        name: &str,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: Option<u64>,
+        version: u64,
+    ) {
+        self.take_entry(name, &key);
+        self.tick += 1;
+        self.size += key.len() + value.len();
+        self.lru.insert(self.tick, (name.to_owned(), key.clone()));
+        if let Some(expires_at) = expires_at {
+            self.expiries
+                .insert((expires_at, name.to_owned(), key.clone()));
+        }
+        if let Some(keyspace) = self.keyspaces.get_mut(name) {
+            let entry = Entry {
+                value,
+                expires_at,
+                version,
+                used: self.tick,
+            };
+            keyspace.entries.insert(key.clone(), entry);
+        }
+        self.prune(name, &key);
+    }
+
+    /// records a removal of `key` if it has a value
+    fn delete(&mut self, name: &str, key: &[u8], version: u64) {
+        if self.take_entry(name, key).is_none() {
+            return;
+        }
+        if let Some(keyspace) = self.keyspaces.get_mut(name) {
+            keyspace
+                .history
+                .entry(key.to_vec())
+                .or_default()
+                .push(Version {
+                    version,
+                    value: None,
+                    expires_at: None,
+                });
+        }
+        self.prune(name, key);
+    }
+
+    /// drops the versions of `key` the retention doesn't keep,
+    /// a removal or an expired value left on its own goes as well
+    fn prune(&mut self, name: &str, key: &[u8]) {
+        let now = now_millis();
+        let retention = self.retention;
+        let keyspace = match self.keyspaces.get_mut(name) {
+            Some(keyspace) => keyspace,
+            None => return,
+        };
+        let current = keyspace.entries.contains_key(key) as usize;
+        let history = match keyspace.history.get_mut(key) {
+            Some(history) => history,
+            None => return,
+        };
+
+        let mut nth = history.len() + current;
+        history.retain(|version| {
+            nth -= 1;
+            retention.keeps(nth, version_millis(version.version), now)
+        });
+        if current == 0 && history.len() == 1 && history[0].value_at(now).is_none() {
+            history.clear();
+        }
+        if history.is_empty() {
+            keyspace.history.remove(key);
+        }
+    }
+
+    /// evicts the least recently used keys until the data fits the limit again
+    fn evict(&mut self) {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return,
+        };
+        while self.size > limit {
+            let (name, key) = match self.lru.keys().next().cloned() {
+                Some(tick) => self.lru.remove(&tick).expect("the tick was just found"),
+                None => return,
+            };
+            if let Some(keyspace) = self.keyspaces.get_mut(&name) {
+                if let Some(entry) = keyspace.entries.remove(&key) {
+                    self.size -= key.len() + entry.value.len();
+                }
+                keyspace.history.remove(&key);
+            }
+        }
+    }
+
+    /// removes the expired keys for good, their values stay in the history
+    fn sweep_expired(&mut self) {
+        let now = now_millis();
+        while let Some((expires_at, name, key)) = self.expiries.iter().next().cloned() {
+            if expires_at > now {
+                return;
+            }
+            self.expiries
+                .remove(&(expires_at, name.clone(), key.clone()));
+
+            // the key could have been written again since
+            let expired = self
+                .keyspaces
+                .get(&name)
+                .and_then(|keyspace| keyspace.entries.get(&key))
+                .map_or(false, |entry| entry.expires_at == Some(expires_at));
+            if expired {
+                self.take_entry(&name, &key);
+                self.prune(&name, &key);
+            }
+        }
+    }
+}
+
+/// an engine which keeps everything in memory and nothing on disk,
+/// with `Options::memory_limit` it evicts the least recently used keys
+/// once the keys and values outgrow the limit
+#[derive(Debug, Clone)]
+pub struct MemoryEngine {
+    state: Arc<Mutex<MemoryState>>,
+    /// empty for the default keyspace
+    keyspace: String,
+    keyspace_id: u64,
+    /// `None` for a snapshot
+    sweeper: Option<Arc<Periodic>>,
+    read_only: bool,
+}
+
+impl MemoryEngine {
+    pub fn new() -> Self {
+        MemoryEngine::with_options(&Options::default())
+    }
+
+    pub fn with_options(options: &Options) -> Self {
+        let mut keyspaces = BTreeMap::new();
+        keyspaces.insert(String::new(), MemoryKeyspace::new(0));
+        let state = Arc::new(Mutex::new(MemoryState {
+            keyspaces,
+            lru: BTreeMap::new(),
+            tick: 0,
+            expiries: BTreeSet::new(),
+            size: 0,
+            limit: options.memory_limit,
+            retention: options.retention,
+            operators: options.merge_operators.clone(),
+            last_version: 0,
+            last_keyspace_id: 0,
+        }));
+
+        let sweeper = {
+            let state = Arc::clone(&state);
+            let sweep = move || {
+                state.lock().expect("mutex not poisoned").sweep_expired();
+            };
+
+            Periodic::start(options.sweep_interval, sweep)
+        };
+
+        MemoryEngine {
+            state,
+            keyspace: String::new(),
+            keyspace_id: 0,
+            sweeper: Some(Arc::new(sweeper)),
+            read_only: false,
+        }
+    }
+
+    fn state(&self) -> MutexGuard<MemoryState> {
+        self.state.lock().expect("mutex not poisoned")
+    }
+
+    fn writing(&self) -> Result<MutexGuard<MemoryState>> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+
+        let state = self.state();
+        state.check_live(&self.keyspace, self.keyspace_id)?;
+
+        Ok(state)
+    }
+
+    fn insert(&self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        let mut state = self.writing()?;
+        let version = state.next_version();
+        state.insert(&self.keyspace, key, value, expires_at, version);
+        state.evict();
+
+        Ok(())
+    }
+}
+
+impl Default for MemoryEngine {
+    fn default() -> Self {
+        MemoryEngine::new()
+    }
+}
+
+impl KvsEngine for MemoryEngine {
+    /// counts as a use of the key, unlike a scan
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let mut state = self.state();
+        let value = match state.keyspace(&self.keyspace, self.keyspace_id) {
+            Some(keyspace) => keyspace.live_value(&key, now_millis()).cloned(),
+            None => return Ok(None),
+        };
+        if value.is_some() {
+            state.touch(&self.keyspace, &key);
+        }
+
+        Ok(value)
+    }
+
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.insert(key, value, None)
+    }
+
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.insert(key, value, Some(expires_at(ttl)))
+    }
+
+    fn remove(&self, key: Vec<u8>) -> Result<()> {
+        let mut state = self.writing()?;
+        let live = state
+            .keyspace(&self.keyspace, self.keyspace_id)
+            .and_then(|keyspace| keyspace.live_value(&key, now_millis()))
+            .is_some();
+        if !live {
+            return Err(Error::KeyNotFound);
+        }
+
+        let version = state.next_version();
+        state.delete(&self.keyspace, &key, version);
+
+        Ok(())
+    }
+
+    /// folds the operand into the value right away
+    fn merge(&self, key: Vec<u8>, operator: &str, operand: Vec<u8>) -> Result<Vec<u8>> {
+        let mut state = self.writing()?;
+        let operator = Arc::clone(
+            state
+                .operators
+                .get(operator)
+                .ok_or(Error::UnknownMergeOperator)?,
+        );
+        let existing = state
+            .keyspace(&self.keyspace, self.keyspace_id)
+            .and_then(|keyspace| keyspace.live_value(&key, now_millis()))
+            .cloned();
+        let value = operator.merge(
+            &key,
+            existing.as_ref().map(|value| value.as_slice()),
+            &operand,
+        )?;
+
+        let version = state.next_version();
+        state.insert(&self.keyspace, key, value.clone(), None, version);
+        state.evict();
+
+        Ok(value)
+    }
+
+    fn delete_range(&self, start: Vec<u8>, end: Vec<u8>) -> Result<()> {
+        let mut state = self.writing()?;
+        let (from, to) = delete_range_bounds(start, end);
+        if is_empty_range(&from, &to) {
+            return Ok(());
+        }
+
+        let keys: Vec<Vec<u8>> = match state.keyspace(&self.keyspace, self.keyspace_id) {
+            Some(keyspace) => keyspace
+                .entries
+                .range((from, to))
+                .map(|(key, _)| key.clone())
+                .collect(),
+            None => return Ok(()),
+        };
+        let version = state.next_version();
+        for key in keys {
+            state.delete(&self.keyspace, &key, version);
+        }
+
+        Ok(())
+    }
+
+    fn get_versions(&self, key: Vec<u8>, n: usize) -> Result<Vec<(u64, Option<Vec<u8>>)>> {
+        let state = self.state();
+        let versions = match state.keyspace(&self.keyspace, self.keyspace_id) {
+            Some(keyspace) => keyspace.versions(&key),
+            None => return Ok(Vec::new()),
+        };
+
+        Ok(versions
+            .into_iter()
+            .rev()
+            .take(n)
+            .map(|version| (version.version, version.value))
+            .collect())
+    }
+
+    fn get_at(&self, key: Vec<u8>, version: u64) -> Result<Option<Vec<u8>>> {
+        let state = self.state();
+        let versions = match state.keyspace(&self.keyspace, self.keyspace_id) {
+            Some(keyspace) => keyspace.versions(&key),
+            None => return Ok(None),
+        };
+
+        Ok(versions
+            .iter()
+            .rev()
+            .find(|written| written.version <= version)
+            .and_then(|written| written.value_at(version_millis(version))))
+    }
+
+    fn ttl(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        let state = self.state();
+        let now = now_millis();
+        let entry = state
+            .keyspace(&self.keyspace, self.keyspace_id)
+            .and_then(|keyspace| keyspace.entries.get(&key))
+            .filter(|entry| !is_expired(entry.expires_at, now));
+
+        match entry {
+            Some(entry) => Ok(entry
+                .expires_at
+                .map(|expires_at| Duration::from_millis(expires_at - now))),
+            None => Err(Error::KeyNotFound),
+        }
+    }
+
+    /// every key the batch puts without a ttl loses its expiry along the way
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let mut state = self.writing()?;
+        for command in batch.commands {
+            let version = state.next_version();
+            match command {
+                LogCommand::Insert {
+                    key,
+                    value,
+                    expires_at,
+                } => {
+                    state.insert(&self.keyspace, key, value, expires_at, version);
+                }
+                LogCommand::Remove { key } => {
+                    state.delete(&self.keyspace, &key, version);
+                }
+                _ => {}
+            }
+        }
+        state.evict();
+
+        Ok(())
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()> {
+        let mut state = self.writing()?;
+        let current = state
+            .keyspace(&self.keyspace, self.keyspace_id)
+            .and_then(|keyspace| keyspace.live_value(&key, now_millis()))
+            .cloned();
+        if current != expected {
+            return Err(Error::ConditionFailed { current });
+        }
+
+        let version = state.next_version();
+        match new {
+            Some(value) => state.insert(&self.keyspace, key, value, None, version),
+            None => state.delete(&self.keyspace, &key, version),
+        }
+        state.evict();
+
+        Ok(())
+    }
+
+    /// copies the data, writes wait for the copy to finish
+    fn snapshot(&self) -> Result<Self> {
+        let state = self.state().clone();
+
+        Ok(MemoryEngine {
+            state: Arc::new(Mutex::new(state)),
+            keyspace: self.keyspace.clone(),
+            keyspace_id: self.keyspace_id,
+            sweeper: None,
+            read_only: true,
+        })
+    }
+
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: Option<usize>) -> Result<ScanIter> {
+        let (from, to) = (
+            owned_bound(range.start_bound()),
+            owned_bound(range.end_bound()),
+        );
+        if is_empty_range(&from, &to) {
+            return Ok(Box::new(std::iter::empty()));
+        }
+
+        let state = self.state();
+        let keyspace = match state.keyspace(&self.keyspace, self.keyspace_id) {
+            Some(keyspace) => keyspace,
+            None => return Ok(Box::new(std::iter::empty())),
+        };
+        let now = now_millis();
+        let pairs = keyspace
+            .entries
+            .range((from, to))
+            .filter(|(_, entry)| !is_expired(entry.expires_at, now))
+            .map(|(key, entry)| Ok((key.clone(), entry.value.clone())));
+        let pairs: Vec<_> = match limit {
+            Some(limit) => pairs.take(limit).collect(),
+            None => pairs.collect(),
+        };
+
+        Ok(Box::new(pairs.into_iter()))
+    }
+
+    fn scan_prefix(&self, prefix: Vec<u8>) -> Result<ScanIter> {
+        let pairs = self.scan((Bound::Included(prefix.clone()), Bound::Unbounded), None)?;
+
+        Ok(Box::new(pairs.take_while(move |pair| match pair {
+            Ok((key, _)) => key.starts_with(&prefix),
+            Err(_) => true,
+        })))
+    }
+
+    fn open_tree(&self, name: &str) -> Result<Self> {
+        check_keyspace_name(name)?;
+        let mut state = self.state();
+        let id = match state.keyspaces.get(name) {
+            Some(keyspace) => keyspace.id,
+            None => {
+                state.last_keyspace_id += 1;
+                let id = state.last_keyspace_id;
+                state
+                    .keyspaces
+                    .insert(name.to_owned(), MemoryKeyspace::new(id));
+                id
+            }
+        };
+
+        Ok(MemoryEngine {
+            keyspace: name.to_owned(),
+            keyspace_id: id,
+            ..self.clone()
+        })
+    }
+
+    fn drop_tree(&self, name: &str) -> Result<()> {
+        check_keyspace_name(name)?;
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+
+        let mut state = self.state();
+        if let Some(keyspace) = state.keyspaces.remove(name) {
+            for (key, entry) in keyspace.entries {
+                state.lru.remove(&entry.used);
+                state.size -= key.len() + entry.value.len();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// the bytes are the ones of the keys and the current values the limit counts
+    fn stats(&self) -> Result<EngineStats> {
+        let state = self.state();
+        let now = now_millis();
+
+        Ok(EngineStats {
+            engine: "memory".to_owned(),
+            keys: state
+                .keyspaces
+                .values()
+                .map(|keyspace| {
+                    keyspace
+                        .entries
+                        .values()
+                        .filter(|entry| !is_expired(entry.expires_at, now))
+                        .count() as u64
+                })
+                .sum(),
+            keyspaces: state
+                .keyspaces
+                .keys()
+                .filter(|name| !name.is_empty())
+                .count() as u64,
+            disk_bytes: state.size as u64,
+            live_bytes: state.size as u64,
+            ..EngineStats::default()
+        })
+    }
+
+    fn tree_names(&self) -> Result<Vec<String>> {
+        Ok(self
+            .state()
+            .keyspaces
+            .keys()
+            .filter(|name| !name.is_empty())
+            .cloned()
+            .collect())
+    }
+
+    /// there is nothing on the disk to restore from
+    fn checkpoint(&self, _dest: &Path) -> Result<()> {
+        Err(Error::Unsupported)
+    }
+}
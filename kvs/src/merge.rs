@@ -0,0 +1,103 @@
+use serde_json::Value;
+
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use crate::{Error, Result};
+
+/// merge operators by the name `KvsEngine::merge` refers to them with
+pub(crate) type MergeOperators = BTreeMap<String, Arc<dyn MergeOperator>>;
+
+/// combines an operand `KvsEngine::merge` got with the value of the key,
+/// an engine can fold the operands of a key any time later, so the result can't depend on anything else
+pub trait MergeOperator: Debug + Send + Sync {
+    /// the value after `operand` is merged into `existing`, `None` for a missing key,
+    /// fails with `Error::MergeFailed` if they can't be combined
+    fn merge(&self, key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// adds integers written as decimal strings, a missing key counts as 0
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Add;
+
+impl MergeOperator for Add {
+    fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Result<Vec<u8>> {
+        let existing = match existing {
+            Some(existing) => parse_integer(existing)?,
+            None => 0,
+        };
+        let sum = existing
+            .checked_add(parse_integer(operand)?)
+            .ok_or(Error::MergeFailed)?;
+
+        Ok(sum.to_string().into_bytes())
+    }
+}
+
+fn parse_integer(bytes: &[u8]) -> Result<i64> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .and_then(|integer| integer.parse::<i64>().ok())
+        .ok_or(Error::MergeFailed)
+}
+
+/// appends the operand to the value
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Append;
+
+impl MergeOperator for Append {
+    fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Result<Vec<u8>> {
+        let mut value = existing.unwrap_or_default().to_vec();
+        value.extend_from_slice(operand);
+
+        Ok(value)
+    }
+}
+
+/// adds the elements of a json array to the json array of the value which it doesn't hold yet,
+/// a missing key counts as an empty array
+#[derive(Debug, Clone, Copy, Default)]
+pub struct JsonSetUnion;
+
+impl MergeOperator for JsonSetUnion {
+    fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Result<Vec<u8>> {
+        let mut union = match existing {
+            Some(existing) => parse_array(existing)?,
+            None => Vec::new(),
+        };
+        for element in parse_array(operand)? {
+            if !union.contains(&element) {
+                union.push(element);
+            }
+        }
+
+        serde_json::to_vec(&union).map_err(|_| Error::MergeFailed)
+    }
+}
+
+fn parse_array(bytes: &[u8]) -> Result<Vec<Value>> {
+    match serde_json::from_slice(bytes) {
+        Ok(Value::Array(elements)) => Ok(elements),
+        _ => Err(Error::MergeFailed),
+    }
+}
+
+/// the log keeps the length of the name of the operator of an operand in a byte
+pub(crate) fn check_operator_name(name: &str) -> Result<()> {
+    if name.len() > 255 {
+        return Err(Error::InvalidMergeOperator);
+    }
+
+    Ok(())
+}
+
+/// `add`, `append` and `union`
+pub(crate) fn builtin_operators() -> MergeOperators {
+    let mut operators: MergeOperators = BTreeMap::new();
+    operators.insert("add".to_owned(), Arc::new(Add));
+    operators.insert("append".to_owned(), Arc::new(Append));
+    operators.insert("union".to_owned(), Arc::new(JsonSetUnion));
+
+    operators
+}
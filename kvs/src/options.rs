@@ -0,0 +1,174 @@
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::kvs::{RecoveryMode, COMPACTION_THRESHOLD, GARBAGE_RATIO, MAX_SEGMENT_SIZE};
+use crate::merge::{builtin_operators, check_operator_name, MergeOperator, MergeOperators};
+use crate::Result;
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// the point at which a write is acknowledged
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Durability {
+    /// every write is synced to the disk before it's acknowledged
+    Always,
+    /// writes are acknowledged once the OS has them and synced in the background,
+    /// a crash of the machine loses at most the last N milliseconds
+    EveryNMillis(u64),
+    /// writes are acknowledged once the OS has them, syncing is up to the OS
+    OsBuffered,
+}
+
+impl Default for Durability {
+    fn default() -> Self {
+        Durability::OsBuffered
+    }
+}
+
+/// `always`, `os` or `<N>ms`
+impl FromStr for Durability {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "always" => Ok(Durability::Always),
+            "os" => Ok(Durability::OsBuffered),
+            _ if s.ends_with("ms") => s[..s.len() - 2]
+                .parse::<u64>()
+                .ok()
+                .filter(|&millis| millis > 0)
+                .map(Durability::EveryNMillis)
+                .ok_or_else(|| format!("invalid durability interval: {}", s)),
+            _ => Err(format!(
+                "unknown durability: {}, expected always, os or <N>ms",
+                s
+            )),
+        }
+    }
+}
+
+/// which versions of a key outlive a compaction, the newest one always does
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Retention {
+    /// the newest N versions, 1 keeps nothing but the current value
+    LastVersions(usize),
+    /// the versions written within the duration
+    NewerThan(Duration),
+}
+
+impl Default for Retention {
+    fn default() -> Self {
+        Retention::LastVersions(1)
+    }
+}
+
+impl Retention {
+    /// whether the version `nth` from the newest one, written at `written_at`, is kept
+    pub(crate) fn keeps(&self, nth: usize, written_at: u64, now: u64) -> bool {
+        match *self {
+            _ if nth == 0 => true,
+            Retention::LastVersions(versions) => nth < versions,
+            Retention::NewerThan(age) => written_at + age.as_millis() as u64 > now,
+        }
+    }
+}
+
+/// settings an engine is opened with, engines ignore the ones they have no use for
+#[derive(Debug, Clone)]
+pub struct Options {
+    pub(crate) durability: Durability,
+    pub(crate) recovery_mode: RecoveryMode,
+    pub(crate) compaction_threshold: usize,
+    pub(crate) max_segment_size: usize,
+    pub(crate) garbage_ratio: f64,
+    pub(crate) sweep_interval: Duration,
+    pub(crate) retention: Retention,
+    pub(crate) merge_operators: MergeOperators,
+    pub(crate) memory_limit: Option<usize>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            durability: Durability::default(),
+            recovery_mode: RecoveryMode::Refuse,
+            compaction_threshold: COMPACTION_THRESHOLD,
+            max_segment_size: MAX_SEGMENT_SIZE,
+            garbage_ratio: GARBAGE_RATIO,
+            sweep_interval: SWEEP_INTERVAL,
+            retention: Retention::default(),
+            merge_operators: builtin_operators(),
+            memory_limit: None,
+        }
+    }
+}
+
+impl Options {
+    pub fn new() -> Self {
+        Options::default()
+    }
+
+    pub fn durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// what `KvStore` does with a segment damaged in the middle
+    pub fn recovery_mode(mut self, mode: RecoveryMode) -> Self {
+        self.recovery_mode = mode;
+        self
+    }
+
+    /// amount of stale bytes after which `KvStore` starts a background compaction
+    pub fn compaction_threshold(mut self, threshold: usize) -> Self {
+        self.compaction_threshold = threshold;
+        self
+    }
+
+    /// size in bytes after which `KvStore` seals the segment it writes to and starts a new one
+    pub fn max_segment_size(mut self, size: usize) -> Self {
+        self.max_segment_size = size;
+        self
+    }
+
+    /// share of stale bytes from which a segment is worth compacting,
+    /// segments below it are left alone however much data they hold
+    pub fn garbage_ratio(mut self, ratio: f64) -> Self {
+        self.garbage_ratio = ratio;
+        self
+    }
+
+    /// how often expired keys are cleared out in the background,
+    /// they are invisible from the moment they expire either way
+    pub fn sweep_interval(mut self, interval: Duration) -> Self {
+        self.sweep_interval = interval;
+        self
+    }
+
+    /// how much of the history of a key is kept
+    pub fn retention(mut self, retention: Retention) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// makes `operator` available to `KvsEngine::merge` under `name` of at most 255 bytes,
+    /// next to the built-in `add`, `append` and `union`
+    pub fn merge_operator<O: MergeOperator + 'static>(
+        mut self,
+        name: &str,
+        operator: O,
+    ) -> Result<Self> {
+        check_operator_name(name)?;
+        self.merge_operators
+            .insert(name.to_owned(), Arc::new(operator));
+        Ok(self)
+    }
+
+    /// bytes of keys and values after which `MemoryEngine` evicts the least recently used keys,
+    /// the versions the retention keeps don't count
+    pub fn memory_limit(mut self, limit: usize) -> Self {
+        self.memory_limit = Some(limit);
+        self
+    }
+}
@@ -0,0 +1,43 @@
+use std::sync::mpsc::{channel, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// background worker which runs a task every `interval` until it's dropped,
+/// syncs the session log for `Durability::EveryNMillis` and sweeps expired keys
+#[derive(Debug)]
+pub struct Periodic {
+    shutdown: Mutex<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Periodic {
+    pub fn start<F>(interval: Duration, mut task: F) -> Self
+    where
+        F: FnMut() + Send + 'static,
+    {
+        let (shutdown, receiver) = channel();
+
+        let handle = thread::spawn(move || loop {
+            match receiver.recv_timeout(interval) {
+                Err(RecvTimeoutError::Timeout) => task(),
+                _ => break,
+            }
+        });
+
+        Periodic {
+            shutdown: Mutex::new(shutdown),
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for Periodic {
+    fn drop(&mut self) {
+        let _ = self.shutdown.lock().expect("mutex not poisoned").send(());
+
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
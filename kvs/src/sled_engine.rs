@@ -1,58 +1,1092 @@
-use sled;
+use fs2::FileExt;
+use sled::{self, Transactional};
 
-use std::collections::HashMap;
+use std::collections::{btree_map::Entry, BTreeMap, HashMap};
 use std::ffi::OsString;
 use std::fs::{read_dir, File, ReadDir};
 use std::io::{copy, Read, Seek, SeekFrom, Write};
+use std::ops::{Bound, RangeBounds};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard};
+use std::time::Duration;
 
 use crate::{
+    engine::{check_keyspace_name, delete_range_bounds, ScanIter},
     error::Error,
-    kvs::KvStoreValue,
+    kvs::{expires_at, now_micros, now_millis, version_millis, KvStoreValue},
     log::{create_log_file, LogCommand, LogReader, LogWriter},
-    KvStore, KvsEngine, Result,
+    merge::MergeOperators,
+    options::{Durability, Options, Retention},
+    periodic::Periodic,
+    EngineStats, KvStore, KvsEngine, Result, WriteBatch,
 };
 
+/// expiry times of the keys written with a ttl, big-endian unix time in milliseconds,
+/// the trees of a named keyspace get `/<name>` appended
+const EXPIRIES_TREE: &'static str = "__kvs_expiries";
+/// the versions of every key the retention keeps under `key length | key | version`,
+/// all big-endian, a value is a presence flag, the expiry and the value itself
+const VERSIONS_TREE: &'static str = "__kvs_versions";
+/// the keys written while a snapshot is live under `version | keyspace length | keyspace | key`,
+/// so a snapshot finds the keys removed since it was taken
+const CHANGES_TREE: &'static str = "__kvs_changes";
+/// the file of a sled database which holds its lock
+const SLED_DB_FILE_NAME: &'static str = "db";
+/// how long a checkpoint waits for sled to unlock the copy it wrote
+const RELEASE_TIMEOUT: Duration = Duration::from_secs(10);
+const RELEASE_POLL: Duration = Duration::from_millis(10);
+
+#[derive(Clone)]
 pub struct SledKvsEngine {
-    store: sled::Db,
+    db: sled::Db,
+    /// empty for the default keyspace
+    keyspace: String,
+    store: sled::Tree,
+    expiries: sled::Tree,
+    versions: sled::Tree,
+    retention: Retention,
+    operators: MergeOperators,
+    /// the version of the last write
+    last_version: Arc<Mutex<u64>>,
+    durability: Durability,
+    /// `None` for a snapshot
+    sweeper: Option<Arc<Periodic>>,
+    changes: sled::Tree,
+    snapshots: SnapshotVersions,
+    /// `Some` for a snapshot
+    snapshot: Option<Arc<SnapshotPin>>,
+    /// every write holds it for reading,
+    /// a snapshot or a keyspace drop holds it for writing
+    writes: Arc<RwLock<()>>,
+    /// cleared when the keyspace is dropped, all handles to it share it
+    live: Arc<AtomicBool>,
+    /// the `live` flags of the keyspaces by name
+    keyspaces: Arc<Mutex<HashMap<String, Arc<AtomicBool>>>>,
+    read_only: bool,
+}
+
+/// the versions the live snapshots read as of, with how many of them read as of each
+type SnapshotVersions = Arc<Mutex<BTreeMap<u64, usize>>>;
+
+/// the version a snapshot reads as of, the versions of the keys written since
+/// outlive the retention until it's dropped
+struct SnapshotPin {
+    version: u64,
+    db: sled::Db,
+    changes: sled::Tree,
+    snapshots: SnapshotVersions,
+    retention: Retention,
+    writes: Arc<RwLock<()>>,
+}
+
+impl Drop for SnapshotPin {
+    /// the versions no other snapshot reads go the way the retention says
+    fn drop(&mut self) {
+        // a snapshot taken meanwhile could need the versions just pruned
+        let _writing = self.writes.read().expect("lock not poisoned");
+        let pinned = {
+            let mut snapshots = self.snapshots.lock().expect("mutex not poisoned");
+            if let Entry::Occupied(mut count) = snapshots.entry(self.version) {
+                *count.get_mut() -= 1;
+                if *count.get() == 0 {
+                    count.remove();
+                }
+            }
+            snapshots.keys().cloned().collect::<Vec<_>>()
+        };
+
+        let _ = release_changes(&self.db, &self.changes, self.retention, &pinned);
+    }
 }
 
 impl SledKvsEngine {
     pub fn new(path: &PathBuf) -> Self {
-        SledKvsEngine {
-            store: sled::open(path).unwrap(),
+        SledKvsEngine::open_with(path, &Options::default()).unwrap()
+    }
+
+    /// `Durability::OsBuffered` leaves flushing to sled's own background flusher
+    pub fn open_with(path: &Path, options: &Options) -> Result<Self> {
+        let config = sled::Config::new().path(path);
+        let config = match options.durability {
+            Durability::Always => config.flush_every_ms(None),
+            Durability::EveryNMillis(millis) => config.flush_every_ms(Some(millis)),
+            Durability::OsBuffered => config,
+        };
+        let db = config.open()?;
+        let (store, expiries, versions) = keyspace_trees(&db, "")?;
+        // no snapshot outlives the engine
+        let changes = db.open_tree(CHANGES_TREE)?;
+        changes.clear()?;
+        let writes = Arc::new(RwLock::new(()));
+        let live = Arc::new(AtomicBool::new(true));
+
+        let sweeper = {
+            let db = db.clone();
+            let writes = Arc::clone(&writes);
+            let sweep = move || {
+                let _writing = writes.read().expect("lock not poisoned");
+                let _ = sweep_keyspaces(&db);
+            };
+
+            Periodic::start(options.sweep_interval, sweep)
+        };
+
+        let mut keyspaces = HashMap::new();
+        keyspaces.insert(String::new(), Arc::clone(&live));
+
+        Ok(SledKvsEngine {
+            db,
+            keyspace: String::new(),
+            store,
+            expiries,
+            versions,
+            retention: options.retention,
+            operators: options.merge_operators.clone(),
+            last_version: Arc::new(Mutex::new(0)),
+            durability: options.durability,
+            sweeper: Some(Arc::new(sweeper)),
+            changes,
+            snapshots: Arc::new(Mutex::new(BTreeMap::new())),
+            snapshot: None,
+            writes,
+            live,
+            keyspaces: Arc::new(Mutex::new(keyspaces)),
+            read_only: false,
+        })
+    }
+
+    /// keeps snapshots from being taken and the keyspace from being dropped until the write is done
+    fn writing(&self) -> Result<RwLockReadGuard<()>> {
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+
+        let writing = self.writes.read().expect("lock not poisoned");
+        if !self.live.load(Ordering::SeqCst) {
+            return Err(Error::KeyspaceDropped);
         }
+
+        Ok(writing)
+    }
+
+    /// keeps the keyspace from being dropped while it's read, `None` once it's gone
+    fn reading(&self) -> Option<RwLockReadGuard<()>> {
+        let reading = self.writes.read().expect("lock not poisoned");
+        if self.live.load(Ordering::SeqCst) {
+            Some(reading)
+        } else {
+            None
+        }
+    }
+
+    fn commit(&self) -> Result<()> {
+        if self.durability == Durability::Always {
+            self.store.flush()?;
+        }
+
+        Ok(())
+    }
+
+    /// reserves `count` versions and returns the first one,
+    /// a version is the unix time in microseconds unless writes come in faster
+    fn next_versions(&self, count: u64) -> u64 {
+        let mut last_version = self.last_version.lock().expect("mutex not poisoned");
+        let first = (*last_version + 1).max(now_micros());
+        *last_version = first + count - 1;
+        first
+    }
+
+    /// drops the versions of `key` neither the retention nor a snapshot keeps
+    fn prune_versions(&self, key: &[u8]) -> Result<()> {
+        let pinned: Vec<u64> = self
+            .snapshots
+            .lock()
+            .expect("mutex not poisoned")
+            .keys()
+            .cloned()
+            .collect();
+
+        prune_versions(&self.versions, key, self.retention, &pinned)
+    }
+
+    /// notes the keys a write is about to change while snapshots are live,
+    /// a key written before the versions were kept gets its current value
+    /// as a version of the oldest snapshot first
+    fn track_changes<'a>(
+        &self,
+        keys: impl IntoIterator<Item = &'a [u8]>,
+        version: u64,
+    ) -> Result<()> {
+        let oldest = match self
+            .snapshots
+            .lock()
+            .expect("mutex not poisoned")
+            .keys()
+            .next()
+        {
+            Some(oldest) => *oldest,
+            None => return Ok(()),
+        };
+
+        for key in keys {
+            if self
+                .versions
+                .scan_prefix(version_prefix(key))
+                .next()
+                .is_none()
+            {
+                if let Some(value) = self.store.get(key)? {
+                    let expires_at = self.expiries.get(key)?.map(|bytes| decode_expiry(&bytes));
+                    self.versions.insert(
+                        version_key(key, oldest),
+                        encode_version(Some(&value), expires_at),
+                    )?;
+                }
+            }
+            self.changes
+                .insert(change_key(version, &self.keyspace, key), &[][..])?;
+        }
+
+        Ok(())
+    }
+
+    /// the value and the expiry `key` had as of the snapshot, `current` is what the store
+    /// held before the versions were read, `None` for a key which wasn't there or expired since
+    fn read_as_of(
+        &self,
+        as_of: u64,
+        key: &[u8],
+        current: Option<(Vec<u8>, Option<u64>)>,
+    ) -> Result<Option<(Vec<u8>, Option<u64>)>> {
+        let now = now_millis();
+        let newer = self
+            .versions
+            .range(version_key(key, as_of + 1)..=version_key(key, u64::MAX))
+            .next()
+            .is_some();
+        let pair = match self
+            .versions
+            .range(version_prefix(key)..=version_key(key, as_of))
+            .next_back()
+        {
+            Some(entry) => {
+                let (expires_at, value) = decode_version_entry(&entry?.1);
+                value.map(|value| (value, expires_at))
+            }
+            // a key written since the snapshot wasn't there before, the others are as they were
+            None if newer => None,
+            None => current,
+        };
+
+        Ok(pair.filter(|(_, expires_at)| expires_at.map_or(true, |expires_at| expires_at > now)))
+    }
+
+    /// the current value and expiry of `key`
+    fn read_current(&self, key: &[u8]) -> Result<Option<(Vec<u8>, Option<u64>)>> {
+        match self.store.get(key)? {
+            Some(value) => {
+                let expires_at = self.expiries.get(key)?.map(|bytes| decode_expiry(&bytes));
+                Ok(Some((value.to_vec(), expires_at)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// the pairs of the snapshot within the bounds, read at once in key order
+    fn scan_as_of(
+        &self,
+        as_of: u64,
+        range: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+        prefix: Option<Vec<u8>>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let mut pairs = BTreeMap::new();
+        let iter = match &prefix {
+            Some(prefix) => self.store.scan_prefix(prefix),
+            None => self.store.range(range.clone()),
+        };
+        for pair in iter {
+            let (key, value) = pair?;
+            let expires_at = self.expiries.get(&key)?.map(|bytes| decode_expiry(&bytes));
+            if let Some((value, _)) =
+                self.read_as_of(as_of, &key, Some((value.to_vec(), expires_at)))?
+            {
+                pairs.insert(key.to_vec(), value);
+            }
+        }
+
+        // the keys removed since the snapshot are left in the versions only,
+        // the changes are read after the store so a removal in between is among them
+        for entry in self.changes.range((as_of + 1).to_be_bytes()..) {
+            let (change, _) = entry?;
+            let (keyspace, key) = decode_change(&change);
+            let key = key.to_vec();
+            let in_bounds = match &prefix {
+                Some(prefix) => key.starts_with(prefix),
+                None => range.contains(&key),
+            };
+            if keyspace != self.keyspace.as_bytes() || !in_bounds || pairs.contains_key(&key) {
+                continue;
+            }
+            if let Some((value, _)) = self.read_as_of(as_of, &key, None)? {
+                pairs.insert(key, value);
+            }
+        }
+
+        Ok(pairs.into_iter().collect())
+    }
+
+    fn as_of(&self) -> Option<u64> {
+        self.snapshot.as_ref().map(|pin| pin.version)
+    }
+
+    /// writes the value and its expiry, or the lack of one, at once
+    fn insert(&self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        let _writing = self.writing()?;
+        let version = self.next_versions(1);
+        self.track_changes(Some(key.as_slice()), version)?;
+        let trees = (&self.store, &self.expiries, &self.versions);
+        trees.transaction(|(store, expiries, versions)| {
+            store.insert(key.as_slice(), value.as_slice())?;
+            versions.insert(
+                version_key(&key, version),
+                encode_version(Some(&value), expires_at),
+            )?;
+            match expires_at {
+                Some(expires_at) => {
+                    expiries.insert(key.as_slice(), &expires_at.to_be_bytes()[..])?;
+                }
+                None => {
+                    expiries.remove(key.as_slice())?;
+                }
+            }
+
+            Ok(())
+        })?;
+        self.prune_versions(&key)?;
+
+        self.commit()
     }
 }
 
 impl KvsEngine for SledKvsEngine {
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        if let Ok(Some(s)) = self.store.get(key) {
-            let s = std::str::from_utf8(&s[..])
-                .expect("sled stores a correct utf8 string")
-                .to_string();
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let _reading = self.reading().ok_or(Error::KeyspaceDropped)?;
+        if let Some(as_of) = self.as_of() {
+            let current = self.read_current(&key)?;
+            return Ok(self
+                .read_as_of(as_of, &key, current)?
+                .map(|(value, _)| value));
+        }
+
+        match self.store.get(&key)? {
+            Some(value) if !is_expired(&self.expiries, &key, now_millis())? => {
+                Ok(Some(value.to_vec()))
+            }
+            // missing or expired
+            _ => Ok(None),
+        }
+    }
+
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.insert(key, value, None)
+    }
+
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.insert(key, value, Some(expires_at(ttl)))
+    }
+
+    fn remove(&self, key: Vec<u8>) -> Result<()> {
+        let _writing = self.writing()?;
+        let now = now_millis();
+        let version = self.next_versions(1);
+        self.track_changes(Some(key.as_slice()), version)?;
+        let trees = (&self.store, &self.expiries, &self.versions);
+        let removed = trees.transaction(|(store, expiries, versions)| {
+            let expired = match expiries.remove(key.as_slice())? {
+                Some(expires_at) => decode_expiry(&expires_at) <= now,
+                None => false,
+            };
+
+            let removed = store.remove(key.as_slice())?.is_some() && !expired;
+            if removed {
+                versions.insert(version_key(&key, version), encode_version(None, None))?;
+            }
+
+            Ok(removed)
+        });
 
-            Ok(Some(s))
+        if removed? {
+            self.prune_versions(&key)?;
+            self.commit()
         } else {
             Err(Error::KeyNotFound)
         }
     }
 
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        if let Ok(_) = self.store.set(key.as_bytes(), value.as_bytes()) {
-            self.store.flush();
-            Ok(())
-        } else {
-            Err(Error::InsertError)
+    /// folds the operand into the value right away
+    fn merge(&self, key: Vec<u8>, operator: &str, operand: Vec<u8>) -> Result<Vec<u8>> {
+        let operator = self
+            .operators
+            .get(operator)
+            .ok_or(Error::UnknownMergeOperator)?;
+        let _writing = self.writing()?;
+        let now = now_millis();
+        let version = self.next_versions(1);
+        self.track_changes(Some(key.as_slice()), version)?;
+        let trees = (&self.store, &self.expiries, &self.versions);
+        let merged: sled::TransactionResult<Result<Vec<u8>>, ()> =
+            trees.transaction(|(store, expiries, versions)| {
+                let expired = match expiries.get(key.as_slice())? {
+                    Some(expires_at) => decode_expiry(&expires_at) <= now,
+                    None => false,
+                };
+                let existing = match store.get(key.as_slice())? {
+                    Some(value) if !expired => Some(value),
+                    _ => None,
+                };
+                let value = match operator.merge(
+                    &key,
+                    existing.as_ref().map(|value| value.as_ref()),
+                    &operand,
+                ) {
+                    Ok(value) => value,
+                    // nothing was written, the key keeps its expiry
+                    Err(e) => return Ok(Err(e)),
+                };
+
+                expiries.remove(key.as_slice())?;
+                store.insert(key.as_slice(), value.as_slice())?;
+                versions.insert(
+                    version_key(&key, version),
+                    encode_version(Some(&value), None),
+                )?;
+
+                Ok(Ok(value))
+            });
+
+        let value = merged??;
+        self.prune_versions(&key)?;
+        self.commit()?;
+
+        Ok(value)
+    }
+
+    /// sled has no range deletes, the keys in the range go one by one in a single transaction
+    fn delete_range(&self, start: Vec<u8>, end: Vec<u8>) -> Result<()> {
+        let _writing = self.writing()?;
+        let version = self.next_versions(1);
+        let mut removals = sled::Batch::default();
+        let mut versions = sled::Batch::default();
+        let mut keys = Vec::new();
+        for pair in self.store.range(delete_range_bounds(start, end)) {
+            let (key, _) = pair?;
+            removals.remove(key.clone());
+            versions.insert(version_key(&key, version), encode_version(None, None));
+            keys.push(key);
+        }
+        self.track_changes(keys.iter().map(|key| key.as_ref()), version)?;
+
+        (&self.store, &self.expiries, &self.versions)
+            .transaction(|(store, expiries, versions_tree)| {
+                store.apply_batch(removals.clone())?;
+                expiries.apply_batch(removals.clone())?;
+                versions_tree.apply_batch(versions.clone())?;
+
+                Ok(())
+            })
+            .map_err(Error::from)?;
+        for key in keys {
+            self.prune_versions(&key)?;
         }
+
+        self.commit()
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        if let Ok(Some(_)) = self.store.remove(key.as_bytes()) {
-            self.store.flush();
-            Ok(())
+    fn get_versions(&self, key: Vec<u8>, n: usize) -> Result<Vec<(u64, Option<Vec<u8>>)>> {
+        let mut versions = Vec::new();
+        let _reading = match self.reading() {
+            Some(reading) => reading,
+            None => return Ok(versions),
+        };
+        let newest = self.as_of().unwrap_or(u64::MAX);
+        for entry in self
+            .versions
+            .range(version_prefix(&key)..=version_key(&key, newest))
+            .rev()
+            .take(n)
+        {
+            let (version_key, version) = entry?;
+            let (_, value) = decode_version_entry(&version);
+            versions.push((decode_version_number(&version_key), value));
+        }
+
+        Ok(versions)
+    }
+
+    fn get_at(&self, key: Vec<u8>, version: u64) -> Result<Option<Vec<u8>>> {
+        let _reading = match self.reading() {
+            Some(reading) => reading,
+            None => return Ok(None),
+        };
+        let version = version.min(self.as_of().unwrap_or(u64::MAX));
+        let range = version_prefix(&key)..=version_key(&key, version);
+        match self.versions.range(range).next_back() {
+            Some(entry) => Ok(decode_version(&entry?.1, version_millis(version))),
+            None => Ok(None),
+        }
+    }
+
+    fn ttl(&self, key: Vec<u8>) -> Result<Option<Duration>> {
+        let _reading = self.reading().ok_or(Error::KeyspaceDropped)?;
+        if let Some(as_of) = self.as_of() {
+            let current = self.read_current(&key)?;
+            return match self.read_as_of(as_of, &key, current)? {
+                Some((_, expires_at)) => Ok(expires_at.map(|expires_at| {
+                    Duration::from_millis(expires_at.saturating_sub(now_millis()))
+                })),
+                None => Err(Error::KeyNotFound),
+            };
+        }
+        if !self.store.contains_key(&key)? {
+            return Err(Error::KeyNotFound);
+        }
+
+        let now = now_millis();
+        match self.expiries.get(&key)? {
+            Some(expires_at) if decode_expiry(&expires_at) <= now => Err(Error::KeyNotFound),
+            Some(expires_at) => Ok(Some(Duration::from_millis(
+                decode_expiry(&expires_at) - now,
+            ))),
+            None => Ok(None),
+        }
+    }
+
+    /// every key the batch puts without a ttl loses its expiry along the way
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let _writing = self.writing()?;
+        let mut writes = sled::Batch::default();
+        let mut expiries = sled::Batch::default();
+        let mut versions = sled::Batch::default();
+        let mut keys = Vec::new();
+        let mut version = self.next_versions(batch.len() as u64);
+        let batch_keys = batch.commands.iter().filter_map(|command| match command {
+            LogCommand::Insert { key, .. } | LogCommand::Remove { key } => Some(key.as_slice()),
+            _ => None,
+        });
+        self.track_changes(batch_keys, version)?;
+        for command in batch.commands {
+            match command {
+                LogCommand::Insert {
+                    key,
+                    value,
+                    expires_at,
+                } => {
+                    match expires_at {
+                        Some(expires_at) => {
+                            expiries.insert(key.as_slice(), &expires_at.to_be_bytes()[..])
+                        }
+                        None => expiries.remove(key.as_slice()),
+                    }
+                    versions.insert(
+                        version_key(&key, version),
+                        encode_version(Some(&value), expires_at),
+                    );
+                    writes.insert(key.as_slice(), value);
+                    keys.push(key);
+                }
+                LogCommand::Remove { key } => {
+                    expiries.remove(key.as_slice());
+                    versions.insert(version_key(&key, version), encode_version(None, None));
+                    writes.remove(key.as_slice());
+                    keys.push(key);
+                }
+                _ => {}
+            }
+            version += 1;
+        }
+
+        (&self.store, &self.expiries, &self.versions)
+            .transaction(|(store, expiries_tree, versions_tree)| {
+                store.apply_batch(writes.clone())?;
+                expiries_tree.apply_batch(expiries.clone())?;
+                versions_tree.apply_batch(versions.clone())?;
+
+                Ok(())
+            })
+            .map_err(Error::from)?;
+        for key in keys {
+            self.prune_versions(&key)?;
+        }
+
+        self.commit()
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<()> {
+        let _writing = self.writing()?;
+        let now = now_millis();
+        let version = self.next_versions(1);
+        self.track_changes(Some(key.as_slice()), version)?;
+        let trees = (&self.store, &self.expiries, &self.versions);
+        let swapped: sled::TransactionResult<std::result::Result<(), Option<Vec<u8>>>, ()> = trees
+            .transaction(|(store, expiries, versions)| {
+                let expired = match expiries.get(key.as_slice())? {
+                    Some(expires_at) => decode_expiry(&expires_at) <= now,
+                    None => false,
+                };
+                let current = match store.get(key.as_slice())? {
+                    Some(value) if !expired => Some(value.to_vec()),
+                    _ => None,
+                };
+                if current != expected {
+                    return Ok(Err(current));
+                }
+
+                match &new {
+                    Some(value) => store.insert(key.as_slice(), value.as_slice())?,
+                    None => store.remove(key.as_slice())?,
+                };
+                expiries.remove(key.as_slice())?;
+                if current.is_some() || new.is_some() {
+                    versions.insert(
+                        version_key(&key, version),
+                        encode_version(new.as_ref().map(|value| value.as_slice()), None),
+                    )?;
+                }
+
+                Ok(Ok(()))
+            });
+
+        match swapped? {
+            Ok(()) => {
+                self.prune_versions(&key)?;
+                self.commit()
+            }
+            Err(current) => Err(Error::ConditionFailed { current }),
+        }
+    }
+
+    /// reads the data as of the last write through the versions of the keys,
+    /// the writes wait only for it to be taken, a keyspace dropped meanwhile is gone from it too
+    fn snapshot(&self) -> Result<Self> {
+        if self.snapshot.is_some() {
+            return Ok(self.clone());
+        }
+
+        let version = {
+            let _frozen = self.writes.write().expect("lock not poisoned");
+            let version = *self.last_version.lock().expect("mutex not poisoned");
+            *self
+                .snapshots
+                .lock()
+                .expect("mutex not poisoned")
+                .entry(version)
+                .or_insert(0) += 1;
+            version
+        };
+        let pin = SnapshotPin {
+            version,
+            db: self.db.clone(),
+            changes: self.changes.clone(),
+            snapshots: Arc::clone(&self.snapshots),
+            retention: self.retention,
+            writes: Arc::clone(&self.writes),
+        };
+
+        Ok(SledKvsEngine {
+            sweeper: None,
+            snapshot: Some(Arc::new(pin)),
+            read_only: true,
+            ..self.clone()
+        })
+    }
+
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: Option<usize>) -> Result<ScanIter> {
+        let range = (
+            owned_bound(range.start_bound()),
+            owned_bound(range.end_bound()),
+        );
+        if self.reading().is_none() {
+            return Ok(Box::new(std::iter::empty()));
+        }
+        if let Some(as_of) = self.as_of() {
+            let pairs = self.scan_as_of(as_of, range, None)?.into_iter().map(Ok);
+            return Ok(Box::new(pairs.take(limit.unwrap_or(usize::MAX))));
+        }
+        let pairs = live_pairs(self.store.range(range), self.expiries.clone());
+
+        match limit {
+            Some(limit) => Ok(Box::new(pairs.take(limit))),
+            None => Ok(Box::new(pairs)),
+        }
+    }
+
+    fn scan_prefix(&self, prefix: Vec<u8>) -> Result<ScanIter> {
+        if self.reading().is_none() {
+            return Ok(Box::new(std::iter::empty()));
+        }
+        if let Some(as_of) = self.as_of() {
+            let pairs =
+                self.scan_as_of(as_of, (Bound::Unbounded, Bound::Unbounded), Some(prefix))?;
+            return Ok(Box::new(pairs.into_iter().map(Ok)));
+        }
+        Ok(Box::new(live_pairs(
+            self.store.scan_prefix(prefix),
+            self.expiries.clone(),
+        )))
+    }
+
+    fn open_tree(&self, name: &str) -> Result<Self> {
+        check_keyspace_name(name)?;
+        let live = Arc::clone(
+            self.keyspaces
+                .lock()
+                .expect("mutex not poisoned")
+                .entry(name.to_owned())
+                .or_insert_with(|| Arc::new(AtomicBool::new(true))),
+        );
+        let (store, expiries, versions) = keyspace_trees(&self.db, name)?;
+
+        Ok(SledKvsEngine {
+            keyspace: name.to_owned(),
+            store,
+            expiries,
+            versions,
+            live,
+            ..self.clone()
+        })
+    }
+
+    /// drops the trees of the keyspace once the writes in progress are done
+    fn drop_tree(&self, name: &str) -> Result<()> {
+        check_keyspace_name(name)?;
+        if self.read_only {
+            return Err(Error::ReadOnly);
+        }
+
+        let _frozen = self.writes.write().expect("lock not poisoned");
+        let dropped = self
+            .keyspaces
+            .lock()
+            .expect("mutex not poisoned")
+            .remove(name);
+        if let Some(live) = dropped {
+            live.store(false, Ordering::SeqCst);
+        }
+        for tree in &[
+            name.to_owned(),
+            format!("{}/{}", EXPIRIES_TREE, name),
+            format!("{}/{}", VERSIONS_TREE, name),
+        ] {
+            self.db.drop_tree(tree.as_bytes())?;
+        }
+
+        self.commit()
+    }
+
+    fn tree_names(&self) -> Result<Vec<String>> {
+        let mut names = Vec::new();
+        // the engine's own trees and sled's default one start with `__`
+        for name in self.db.tree_names() {
+            if !name.starts_with(b"__") {
+                names.push(std::str::from_utf8(&name)?.to_owned());
+            }
+        }
+        names.sort_unstable();
+
+        Ok(names)
+    }
+
+    /// sled compacts on its own and keeps no figures of it, the keys include
+    /// the expired ones the sweeper is yet to remove
+    fn stats(&self) -> Result<EngineStats> {
+        let mut stats = EngineStats {
+            engine: "sled".to_owned(),
+            keys: self.db.len() as u64,
+            disk_bytes: self.db.size_on_disk()?,
+            ..EngineStats::default()
+        };
+
+        // the engine's own trees and sled's default one start with `__`
+        for name in self.db.tree_names() {
+            if name.starts_with(b"__") {
+                continue;
+            }
+
+            stats.keys += self.db.open_tree(&name)?.len() as u64;
+            stats.keyspaces += 1;
+        }
+
+        Ok(stats)
+    }
+
+    /// exports every tree into a new sled database while the writes wait,
+    /// it returns once sled let go of the copy, so the checkpoint opens right away,
+    /// a snapshot can't be exported as of its version
+    fn checkpoint(&self, dest: &Path) -> Result<()> {
+        if self.snapshot.is_some() {
+            return Err(Error::Unsupported);
+        }
+        std::fs::create_dir(dest)?;
+        let copy = sled::Config::new().path(dest).open()?;
+
+        let frozen = self.writes.write().expect("lock not poisoned");
+        copy.import(self.db.export());
+        copy.flush()?;
+        drop(frozen);
+        drop(copy);
+
+        wait_for_release(&dest.join(SLED_DB_FILE_NAME))
+    }
+}
+
+/// sled's background threads keep the file of a dropped database locked for a moment
+fn wait_for_release(db_file: &Path) -> Result<()> {
+    let file = File::open(db_file)?;
+    let mut waited = Duration::from_millis(0);
+    loop {
+        match FileExt::try_lock_exclusive(&file) {
+            Ok(()) => {
+                FileExt::unlock(&file)?;
+                return Ok(());
+            }
+            Err(e) if waited >= RELEASE_TIMEOUT => return Err(e.into()),
+            Err(_) => {
+                std::thread::sleep(RELEASE_POLL);
+                waited += RELEASE_POLL;
+            }
+        }
+    }
+}
+
+/// drops the versions of `key` neither the retention nor a snapshot as of one of
+/// the `pinned` versions keeps, a removal or an expired value left on its own goes as well
+fn prune_versions(
+    versions: &sled::Tree,
+    key: &[u8],
+    retention: Retention,
+    pinned: &[u64],
+) -> Result<()> {
+    let now = now_millis();
+    // the pinned versions older than the versions seen so far
+    let mut pinned = pinned.to_vec();
+    pinned.sort_unstable();
+    let mut kept = Vec::new();
+    for (nth, entry) in versions.scan_prefix(version_prefix(key)).rev().enumerate() {
+        let (version_key, version) = entry?;
+        let number = decode_version_number(&version_key);
+        // the newest version at or before a snapshot is the one it reads
+        let mut read = false;
+        while pinned.last().map_or(false, |pinned| *pinned >= number) {
+            pinned.pop();
+            read = true;
+        }
+
+        if read || retention.keeps(nth, version_millis(number), now) {
+            kept.push((version_key, version));
         } else {
-            Err(Error::RemoveError)
+            versions.remove(version_key)?;
+        }
+    }
+
+    if let [(version_key, version)] = kept.as_slice() {
+        if decode_version(version, now).is_none() {
+            versions.remove(version_key)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// forgets the changes older than every live snapshot, pruning the versions kept for them
+fn release_changes(
+    db: &sled::Db,
+    changes: &sled::Tree,
+    retention: Retention,
+    pinned: &[u64],
+) -> Result<()> {
+    let oldest = pinned.iter().min().cloned().unwrap_or(u64::MAX);
+    let names = db.tree_names();
+    for entry in changes.iter() {
+        let (change, _) = entry?;
+        if decode_expiry(&change) > oldest {
+            break;
+        }
+
+        let (keyspace, key) = decode_change(&change);
+        let versions = match keyspace {
+            b"" => VERSIONS_TREE.as_bytes().to_vec(),
+            name => [VERSIONS_TREE.as_bytes(), b"/", name].concat(),
+        };
+        // the keyspace could have been dropped since
+        if names
+            .iter()
+            .any(|name| name.as_ref() == versions.as_slice())
+        {
+            prune_versions(&db.open_tree(&versions)?, key, retention, pinned)?;
         }
+        changes.remove(change)?;
+    }
+
+    Ok(())
+}
+
+/// `version | keyspace length | keyspace | key`
+fn change_key(version: u64, keyspace: &str, key: &[u8]) -> Vec<u8> {
+    let mut change = Vec::with_capacity(9 + keyspace.len() + key.len());
+    change.extend_from_slice(&version.to_be_bytes());
+    change.push(keyspace.len() as u8);
+    change.extend_from_slice(keyspace.as_bytes());
+    change.extend_from_slice(key);
+    change
+}
+
+/// the keyspace and the key of a change
+fn decode_change(change: &[u8]) -> (&[u8], &[u8]) {
+    let keyspace_len = change[8] as usize;
+    change[9..].split_at(keyspace_len)
+}
+
+fn owned_bound(bound: Bound<&Vec<u8>>) -> Bound<Vec<u8>> {
+    match bound {
+        Bound::Included(key) => Bound::Included(key.to_owned()),
+        Bound::Excluded(key) => Bound::Excluded(key.to_owned()),
+        Bound::Unbounded => Bound::Unbounded,
     }
 }
+
+/// decodes the pairs of `iter` leaving out the expired ones
+fn live_pairs(
+    iter: sled::Iter,
+    expiries: sled::Tree,
+) -> impl Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> {
+    let now = now_millis();
+
+    iter.filter_map(move |pair| match pair {
+        Ok((key, value)) => match is_expired(&expiries, &key, now) {
+            Ok(false) => Some(Ok((key.to_vec(), value.to_vec()))),
+            Ok(true) => None,
+            Err(e) => Some(Err(e)),
+        },
+        Err(e) => Some(Err(e.into())),
+    })
+}
+
+fn decode_expiry(bytes: &[u8]) -> u64 {
+    let mut expires_at = [0u8; 8];
+    expires_at.copy_from_slice(&bytes[..8]);
+    u64::from_be_bytes(expires_at)
+}
+
+fn version_prefix(key: &[u8]) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(4 + key.len());
+    prefix.extend_from_slice(&(key.len() as u32).to_be_bytes());
+    prefix.extend_from_slice(key);
+    prefix
+}
+
+fn version_key(key: &[u8], version: u64) -> Vec<u8> {
+    let mut version_key = version_prefix(key);
+    version_key.extend_from_slice(&version.to_be_bytes());
+    version_key
+}
+
+fn decode_version_number(version_key: &[u8]) -> u64 {
+    decode_expiry(&version_key[version_key.len() - 8..])
+}
+
+/// `None` for a removal, no expiry is stored as 0
+fn encode_version(value: Option<&[u8]>, expires_at: Option<u64>) -> Vec<u8> {
+    let mut version = Vec::with_capacity(9 + value.map_or(0, |value| value.len()));
+    version.push(value.is_some() as u8);
+    version.extend_from_slice(&expires_at.unwrap_or(0).to_be_bytes());
+    version.extend_from_slice(value.unwrap_or_default());
+    version
+}
+
+fn decode_version_entry(version: &[u8]) -> (Option<u64>, Option<Vec<u8>>) {
+    let expires_at = match decode_expiry(&version[1..9]) {
+        0 => None,
+        expires_at => Some(expires_at),
+    };
+
+    match version[0] {
+        0 => (expires_at, None),
+        _ => (expires_at, Some(version[9..].to_vec())),
+    }
+}
+
+/// the value a version holds at `now`
+fn decode_version(version: &[u8], now: u64) -> Option<Vec<u8>> {
+    match decode_version_entry(version) {
+        (Some(expires_at), _) if expires_at <= now => None,
+        (_, value) => value,
+    }
+}
+
+fn is_expired(expiries: &sled::Tree, key: &[u8], now: u64) -> Result<bool> {
+    match expiries.get(key)? {
+        Some(expires_at) => Ok(decode_expiry(&expires_at) <= now),
+        None => Ok(false),
+    }
+}
+
+/// the data, expiries and versions trees of a keyspace
+fn keyspace_trees(db: &sled::Db, keyspace: &str) -> Result<(sled::Tree, sled::Tree, sled::Tree)> {
+    match keyspace {
+        "" => Ok((
+            (**db).clone(),
+            db.open_tree(EXPIRIES_TREE)?,
+            db.open_tree(VERSIONS_TREE)?,
+        )),
+        name => Ok((
+            db.open_tree(name)?,
+            db.open_tree(format!("{}/{}", EXPIRIES_TREE, name))?,
+            db.open_tree(format!("{}/{}", VERSIONS_TREE, name))?,
+        )),
+    }
+}
+
+/// sweeps the expired keys of every keyspace
+fn sweep_keyspaces(db: &sled::Db) -> Result<()> {
+    for name in db.tree_names() {
+        if !name.starts_with(EXPIRIES_TREE.as_bytes()) {
+            continue;
+        }
+
+        let keyspace = std::str::from_utf8(&name[EXPIRIES_TREE.len()..])?;
+        let (store, expiries, _) = keyspace_trees(db, keyspace.trim_start_matches('/'))?;
+        sweep_expired(&store, &expiries)?;
+    }
+
+    Ok(())
+}
+
+/// removes the expired keys for good
+fn sweep_expired(store: &sled::Tree, expiries: &sled::Tree) -> Result<()> {
+    let now = now_millis();
+
+    for entry in expiries.iter() {
+        let (key, expires_at) = entry?;
+        if decode_expiry(&expires_at) > now {
+            continue;
+        }
+
+        // the key could have been written again since
+        (store, expiries).transaction(|(store, expiries)| {
+            if expiries.get(&key)?.as_ref() == Some(&expires_at) {
+                store.remove(&key)?;
+                expiries.remove(&key)?;
+            }
+
+            Ok(())
+        })?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,32 @@
+use serde::Serialize;
+
+/// what an engine holds and how much of its storage is garbage,
+/// the figures an engine can't tell are zero
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct EngineStats {
+    /// `kvs`, `sled` or `memory`
+    pub engine: String,
+    /// current keys of every keyspace
+    pub keys: u64,
+    /// keyspaces besides the default one
+    pub keyspaces: u64,
+    /// bytes the engine takes on the disk, or in memory for the memory engine
+    pub disk_bytes: u64,
+    /// bytes of the records a compaction keeps
+    pub live_bytes: u64,
+    /// bytes of the superseded records a compaction would reclaim
+    pub dead_bytes: u64,
+    /// the live segments in replay order
+    pub segments: Vec<SegmentStats>,
+    /// compactions finished since the store was opened
+    pub compactions: u64,
+    /// how long the last of them took, `None` before the first one
+    pub last_compaction_millis: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize)]
+pub struct SegmentStats {
+    pub name: String,
+    pub bytes: u64,
+    pub dead_bytes: u64,
+}
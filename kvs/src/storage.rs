@@ -0,0 +1,430 @@
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::fs::{read_dir, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// a file of a `Storage`
+pub trait StorageFile: Read + Write + Seek + Debug + Send + Sync {
+    /// makes the data written so far survive a crash
+    fn sync(&self) -> io::Result<()>;
+
+    fn len(&self) -> io::Result<u64>;
+}
+
+/// the flat directory `KvStore` keeps its segments and hint files in,
+/// files are only ever written right after they are created
+pub trait Storage: Debug + Send + Sync {
+    /// creates an empty file open for reading and writing, replacing one of the same name
+    fn create(&self, name: &str) -> io::Result<Box<dyn StorageFile>>;
+
+    /// opens a file for reading, fails with `io::ErrorKind::NotFound` if it's missing
+    fn open(&self, name: &str) -> io::Result<Box<dyn StorageFile>>;
+
+    /// names of the files, in no particular order
+    fn list(&self) -> io::Result<Vec<String>>;
+
+    fn remove(&self, name: &str) -> io::Result<()>;
+
+    /// replaces `to` with `from` at once
+    fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+
+    /// cuts a file off at `len`
+    fn truncate(&self, name: &str, len: u64) -> io::Result<()>;
+
+    /// makes the creates, removes and renames so far survive a crash
+    fn sync(&self) -> io::Result<()>;
+
+    /// puts the first `len` bytes of the file `name` into the new file `dest` on the disk,
+    /// a file which is never written again can be shared with `dest` instead of copied
+    fn export(&self, name: &str, len: u64, _sealed: bool, dest: &Path) -> io::Result<()> {
+        copy_to_disk(self.open(name)?, len, dest)
+    }
+}
+
+fn copy_to_disk(file: Box<dyn StorageFile>, len: u64, dest: &Path) -> io::Result<()> {
+    let mut dest = OpenOptions::new().write(true).create_new(true).open(dest)?;
+    let copied = io::copy(&mut file.take(len), &mut dest)?;
+    if copied < len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+
+    dest.sync_data()
+}
+
+impl StorageFile for File {
+    fn sync(&self) -> io::Result<()> {
+        self.sync_data()
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.metadata()?.len())
+    }
+}
+
+/// the files of a directory on the disk
+#[derive(Debug, Clone)]
+pub struct DiskStorage {
+    path: PathBuf,
+}
+
+impl DiskStorage {
+    pub fn new(path: &Path) -> Self {
+        DiskStorage {
+            path: path.to_path_buf(),
+        }
+    }
+}
+
+impl Storage for DiskStorage {
+    fn create(&self, name: &str) -> io::Result<Box<dyn StorageFile>> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .read(true)
+            .write(true)
+            .open(self.path.join(name))?;
+
+        Ok(Box::new(file))
+    }
+
+    fn open(&self, name: &str) -> io::Result<Box<dyn StorageFile>> {
+        Ok(Box::new(File::open(self.path.join(name))?))
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        let mut names = Vec::new();
+        for entry in read_dir(&self.path)? {
+            let entry = entry?;
+            if !entry.file_type()?.is_file() {
+                continue;
+            }
+            if let Ok(name) = entry.file_name().into_string() {
+                names.push(name);
+            }
+        }
+
+        Ok(names)
+    }
+
+    fn remove(&self, name: &str) -> io::Result<()> {
+        std::fs::remove_file(self.path.join(name))
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        std::fs::rename(self.path.join(from), self.path.join(to))
+    }
+
+    fn truncate(&self, name: &str, len: u64) -> io::Result<()> {
+        OpenOptions::new()
+            .write(true)
+            .open(self.path.join(name))?
+            .set_len(len)
+    }
+
+    /// only directories on unix can be synced
+    fn sync(&self) -> io::Result<()> {
+        if cfg!(unix) {
+            File::open(&self.path)?.sync_all()?;
+        }
+
+        Ok(())
+    }
+
+    /// a sealed file is hard-linked unless `dest` is on another file system
+    fn export(&self, name: &str, len: u64, sealed: bool, dest: &Path) -> io::Result<()> {
+        if sealed && std::fs::hard_link(self.path.join(name), dest).is_ok() {
+            return Ok(());
+        }
+
+        copy_to_disk(self.open(name)?, len, dest)
+    }
+}
+
+/// a `Storage` in memory which can be made to fail for crash tests, clones share the files,
+/// creates, removes and renames survive a crash right away, written data once it's synced
+#[derive(Debug, Clone, Default)]
+pub struct MemoryStorage {
+    state: Arc<Mutex<MemoryStorageState>>,
+}
+
+#[derive(Debug, Default)]
+struct MemoryStorageState {
+    files: BTreeMap<String, Arc<Mutex<MemoryFileData>>>,
+    /// writes, syncs, creates, removes, renames and truncates so far
+    ops: usize,
+    /// every op past this one fails as if the process had died
+    crash_at: Option<usize>,
+    fail_syncs: bool,
+    /// the next write gets this many of its bytes in and fails
+    short_write: Option<usize>,
+}
+
+impl MemoryStorageState {
+    /// counts an op which changes the files, fails once the storage crashed
+    fn op(&mut self) -> io::Result<()> {
+        self.ops += 1;
+        match self.crash_at {
+            Some(crash_at) if self.ops > crash_at => {
+                Err(io::Error::new(io::ErrorKind::Other, "the storage crashed"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn file(&self, name: &str) -> io::Result<Arc<Mutex<MemoryFileData>>> {
+        self.files
+            .get(name)
+            .cloned()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, name.to_owned()))
+    }
+}
+
+#[derive(Debug, Default)]
+struct MemoryFileData {
+    data: Vec<u8>,
+    /// the data as of the last sync
+    synced: Vec<u8>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        MemoryStorage::default()
+    }
+
+    fn state(&self) -> MutexGuard<MemoryStorageState> {
+        self.state.lock().expect("mutex not poisoned")
+    }
+
+    /// number of writes, syncs, creates, removes, renames and truncates so far
+    pub fn ops(&self) -> usize {
+        self.state().ops
+    }
+
+    /// lets the next `ops` ops through and fails every one after them
+    pub fn crash_after(&self, ops: usize) {
+        let mut state = self.state();
+        state.crash_at = Some(state.ops + ops);
+    }
+
+    /// whether every sync fails from now on
+    pub fn fail_syncs(&self, fail: bool) {
+        self.state().fail_syncs = fail;
+    }
+
+    /// the next write gets only its first `len` bytes in and fails
+    pub fn short_write(&self, len: usize) {
+        self.state().short_write = Some(len);
+    }
+
+    /// the files as the next process finds them once this one died, nothing written is lost
+    pub fn restart(&self) -> MemoryStorage {
+        self.copy(|file| file.data.clone())
+    }
+
+    /// the files as they are after the machine lost power, only synced data is left
+    pub fn lose_power(&self) -> MemoryStorage {
+        self.copy(|file| file.synced.clone())
+    }
+
+    /// a storage without faults holding what `content` leaves of every file
+    fn copy<F>(&self, content: F) -> MemoryStorage
+    where
+        F: Fn(&MemoryFileData) -> Vec<u8>,
+    {
+        let files = self
+            .state()
+            .files
+            .iter()
+            .map(|(name, file)| {
+                let data = content(&file.lock().expect("mutex not poisoned"));
+                let file = MemoryFileData {
+                    synced: data.clone(),
+                    data,
+                };
+
+                (name.clone(), Arc::new(Mutex::new(file)))
+            })
+            .collect();
+
+        MemoryStorage {
+            state: Arc::new(Mutex::new(MemoryStorageState {
+                files,
+                ..MemoryStorageState::default()
+            })),
+        }
+    }
+
+    /// the current content of a file
+    pub fn read(&self, name: &str) -> io::Result<Vec<u8>> {
+        let file = self.state().file(name)?;
+        let data = file.lock().expect("mutex not poisoned").data.clone();
+
+        Ok(data)
+    }
+
+    fn open_file(&self, file: Arc<Mutex<MemoryFileData>>, writable: bool) -> Box<dyn StorageFile> {
+        Box::new(MemoryFile {
+            storage: Arc::clone(&self.state),
+            file,
+            pos: 0,
+            writable,
+        })
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn create(&self, name: &str) -> io::Result<Box<dyn StorageFile>> {
+        let mut state = self.state();
+        state.op()?;
+        let file = Arc::new(Mutex::new(MemoryFileData::default()));
+        state.files.insert(name.to_owned(), Arc::clone(&file));
+        drop(state);
+
+        Ok(self.open_file(file, true))
+    }
+
+    fn open(&self, name: &str) -> io::Result<Box<dyn StorageFile>> {
+        let file = self.state().file(name)?;
+
+        Ok(self.open_file(file, false))
+    }
+
+    fn list(&self) -> io::Result<Vec<String>> {
+        Ok(self.state().files.keys().cloned().collect())
+    }
+
+    fn remove(&self, name: &str) -> io::Result<()> {
+        let mut state = self.state();
+        state.op()?;
+        state.file(name)?;
+        state.files.remove(name);
+
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let mut state = self.state();
+        state.op()?;
+        let file = state.file(from)?;
+        state.files.remove(from);
+        state.files.insert(to.to_owned(), file);
+
+        Ok(())
+    }
+
+    fn truncate(&self, name: &str, len: u64) -> io::Result<()> {
+        let mut state = self.state();
+        state.op()?;
+        let file = state.file(name)?;
+        let mut file = file.lock().expect("mutex not poisoned");
+        let len = len as usize;
+        file.data.truncate(len);
+        file.synced.truncate(len);
+
+        Ok(())
+    }
+
+    fn sync(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// an open file of a `MemoryStorage`, the files stay readable through it after a remove
+#[derive(Debug)]
+struct MemoryFile {
+    storage: Arc<Mutex<MemoryStorageState>>,
+    file: Arc<Mutex<MemoryFileData>>,
+    pos: u64,
+    writable: bool,
+}
+
+impl Read for MemoryFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let file = self.file.lock().expect("mutex not poisoned");
+        let start = (self.pos as usize).min(file.data.len());
+        let len = buf.len().min(file.data.len() - start);
+        buf[..len].copy_from_slice(&file.data[start..start + len]);
+        self.pos += len as u64;
+
+        Ok(len)
+    }
+}
+
+impl Write for MemoryFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.writable {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "the file was opened for reading",
+            ));
+        }
+
+        let mut storage = self.storage.lock().expect("mutex not poisoned");
+        storage.op()?;
+        let (len, short) = match storage.short_write.take() {
+            Some(len) => (len.min(buf.len()), true),
+            None => (buf.len(), false),
+        };
+        drop(storage);
+
+        let mut file = self.file.lock().expect("mutex not poisoned");
+        let start = self.pos as usize;
+        if file.data.len() < start + len {
+            file.data.resize(start + len, 0);
+        }
+        file.data[start..start + len].copy_from_slice(&buf[..len]);
+        self.pos += len as u64;
+
+        if short {
+            Err(io::Error::new(io::ErrorKind::Other, "short write"))
+        } else {
+            Ok(len)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for MemoryFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let len = self.file.lock().expect("mutex not poisoned").data.len() as i64;
+        let pos = match pos {
+            SeekFrom::Start(pos) => pos as i64,
+            SeekFrom::End(offset) => len + offset,
+            SeekFrom::Current(offset) => self.pos as i64 + offset,
+        };
+        if pos < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "seek before the start of the file",
+            ));
+        }
+        self.pos = pos as u64;
+
+        Ok(self.pos)
+    }
+}
+
+impl StorageFile for MemoryFile {
+    fn sync(&self) -> io::Result<()> {
+        let mut storage = self.storage.lock().expect("mutex not poisoned");
+        storage.op()?;
+        if storage.fail_syncs {
+            return Err(io::Error::new(io::ErrorKind::Other, "sync failed"));
+        }
+        drop(storage);
+
+        let mut file = self.file.lock().expect("mutex not poisoned");
+        file.synced = file.data.clone();
+
+        Ok(())
+    }
+
+    fn len(&self) -> io::Result<u64> {
+        Ok(self.file.lock().expect("mutex not poisoned").data.len() as u64)
+    }
+}
@@ -0,0 +1,40 @@
+use clap::ArgMatches;
+
+use std::error::Error;
+
+/// how KEY and VALUE are written on the command line and how a fetched value is printed
+#[derive(Debug, Clone, Copy)]
+pub enum Encoding {
+    Utf8,
+    Hex,
+    Base64,
+}
+
+impl Encoding {
+    pub fn from_matches(matches: &ArgMatches) -> Self {
+        if matches.is_present("hex") {
+            Encoding::Hex
+        } else if matches.is_present("base64") {
+            Encoding::Base64
+        } else {
+            Encoding::Utf8
+        }
+    }
+
+    pub fn decode(self, input: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        match self {
+            Encoding::Utf8 => Ok(input.as_bytes().to_vec()),
+            Encoding::Hex => Ok(hex::decode(input)?),
+            Encoding::Base64 => Ok(base64::decode(input)?),
+        }
+    }
+
+    pub fn encode(self, bytes: Vec<u8>) -> Result<String, Box<dyn Error>> {
+        match self {
+            Encoding::Utf8 => String::from_utf8(bytes)
+                .map_err(|_| Box::<dyn Error>::from("value is not UTF-8, use --hex or --base64")),
+            Encoding::Hex => Ok(hex::encode(bytes)),
+            Encoding::Base64 => Ok(base64::encode(&bytes)),
+        }
+    }
+}
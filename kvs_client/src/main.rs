@@ -5,8 +5,12 @@ use clap::{load_yaml, App};
 use log::{debug, info, LevelFilter};
 
 use std::env::current_dir;
+use std::io::{self, Read, Write};
 use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use std::{collections::HashMap, process::exit};
+use tonic::Code;
 
 // use grpc::client_server::kvs_command_client::KvsCommandClient;
 // use grpc::client_server::kvs_command_request::Cmd;
@@ -17,12 +21,21 @@ use grpc::client_server::{
     kvs_command_request::Cmd,
     kvs_command_response::Status as ServerResponseStatus,
     kvs_command_server::{KvsCommand, KvsCommandServer},
-    {Error, Get, KvsCommandRequest, KvsCommandResponse, Ok as ServerOk, Remove, Set},
+    {
+        Append, BackupRequest, DeletePrefix, DumpChunk, DumpRequest, Error, Get, Incr,
+        KvsCommandRequest, KvsCommandResponse, Ok as ServerOk, Remove, Set, StatsRequest,
+    },
 };
 
-use kvs::KvStore;
+use kvs::{EngineStats, KvStore, SegmentStats};
 
-const DEFAULT_ADDR: &'static str = "http://127.0.0.1:4000";
+mod encoding;
+
+use encoding::Encoding;
+
+const DEFAULT_ADDR: &'static str = "127.0.0.1:4000";
+/// bytes of a dump read from stdin for every chunk sent to the server
+const RESTORE_CHUNK_LEN: usize = 64 * 1024;
 
 // fn build_command(matches: clap::ArgMatches) -> KvsCommandRequest {
 //     match matches {
@@ -71,240 +84,342 @@ async fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
 
     // let mut client = KvsCommandClient::new(channel);
 
-    match matches.subcommand() {
-        ("get", Some(matches)) => {
-            match (
-                &matches.value_of("key"),
-                &matches.value_of("addr"),
-                &matches.args.len(),
-            ) {
-                (Some(key), Some(addr), 2) => {
-                    let addr = format!("http://{}", addr);
-                    info!("try to connect to a server with addr: {}", addr);
-                    let mut client = create_grpc_client(addr).await?;
-
-                    let request = tonic::Request::new(KvsCommandRequest {
-                        cmd: Some(Cmd::Get {
-                            0: Get {
-                                key: key.to_string(),
-                            },
-                        }),
-                    });
-                    let response = client.send(request).await?.into_inner();
-
-                    debug!("Response: {:?}", response);
-
-                    match response {
-                        KvsCommandResponse {
-                            status:
-                                Some(ServerResponseStatus::Ok {
-                                    0: ServerOk { msg },
-                                }),
-                        } => {
-                            println!("{}", msg);
-                            Ok(())
-                        }
-                        KvsCommandResponse {
-                            status: Some(ServerResponseStatus::Error { 0: Error { msg } }),
-                        } => {
-                            println!("Key not found");
-
-                            Ok(())
-                        }
-                        _ => unreachable!("invalid response"),
-                    }
-                }
-                (Some(key), None, 1) => {
-                    info!("try to connect to a server with addr: {}", DEFAULT_ADDR);
-                    let mut client = create_grpc_client(DEFAULT_ADDR.to_string()).await?;
-
-                    let request = tonic::Request::new(KvsCommandRequest {
-                        cmd: Some(Cmd::Get {
-                            0: Get {
-                                key: key.to_string(),
-                            },
-                        }),
-                    });
-                    let response = client.send(request).await?.into_inner();
-
-                    debug!("Response: {:?}", response);
-
-                    match response {
-                        KvsCommandResponse {
-                            status:
-                                Some(ServerResponseStatus::Ok {
-                                    0: ServerOk { msg },
-                                }),
-                        } => {
-                            println!("{}", msg);
-                            Ok(())
-                        }
-                        KvsCommandResponse {
-                            status: Some(ServerResponseStatus::Error { 0: Error { msg } }),
-                        } => {
-                            println!("Key not found");
-
-                            Ok(())
-                        }
-                        _ => unreachable!("invalid response"),
-                    }
-                }
-                _ => exit(1),
+    let (subcommand, matches) = match matches.subcommand() {
+        (subcommand, Some(matches)) => (subcommand, matches),
+        _ => exit(1),
+    };
+    let addr = matches.value_of("addr").unwrap_or(DEFAULT_ADDR);
+    if subcommand == "stats" {
+        let stats = request_stats(addr).await?;
+        if matches.is_present("json") {
+            println!("{}", serde_json::to_string_pretty(&stats)?);
+        } else {
+            print_stats(&stats);
+        }
+
+        return Ok(());
+    }
+    if subcommand == "backup" {
+        let dest_dir = matches.value_of("dir").unwrap_or("").to_owned();
+        let mut client = create_grpc_client(format!("http://{}", addr)).await?;
+        let backup = client
+            .backup(tonic::Request::new(BackupRequest { dest_dir }))
+            .await?
+            .into_inner();
+        println!("{}", backup.dir);
+
+        return Ok(());
+    }
+    if subcommand == "dump" {
+        return dump(addr).await;
+    }
+    if subcommand == "restore" {
+        return restore(addr).await;
+    }
+    let keyspace = matches.value_of("keyspace").unwrap_or("");
+    let encoding = Encoding::from_matches(matches);
+    let key = match matches.value_of("key") {
+        Some(key) => encoding.decode(key)?,
+        None => exit(1),
+    };
+
+    match subcommand {
+        "get" => {
+            let cmd = Cmd::Get {
+                0: Get {
+                    key,
+                    snapshot_id: 0,
+                },
+            };
+            match send_command(addr, keyspace, cmd).await? {
+                ServerResponseStatus::Ok {
+                    0: ServerOk { msg },
+                } => println!("{}", encoding.encode(msg)?),
+                ServerResponseStatus::Error { 0: Error { msg } } => println!("Key not found"),
+                _ => unreachable!("invalid response"),
             }
         }
-        ("set", Some(matches)) => {
-            match (
-                &matches.value_of("key"),
-                &matches.value_of("value"),
-                &matches.value_of("addr"),
-                &matches.args.len(),
-            ) {
-                (Some(key), Some(value), Some(addr), 3) => {
-                    let addr = format!("http://{}", addr);
-                    info!("try to connect to a server with addr: {}", addr);
-                    let mut client = create_grpc_client(addr).await?;
-
-                    let request = tonic::Request::new(KvsCommandRequest {
-                        cmd: Some(Cmd::Set {
-                            0: Set {
-                                key: key.to_string(),
-                                value: value.to_string(),
-                            },
-                        }),
-                    });
-                    let response = client.send(request).await?.into_inner();
-
-                    debug!("Response: {:?}", response);
-
-                    match response {
-                        KvsCommandResponse {
-                            status:
-                                Some(ServerResponseStatus::Ok {
-                                    0: ServerOk { msg },
-                                }),
-                        } => Ok(()),
-                        KvsCommandResponse {
-                            status: Some(ServerResponseStatus::Error { 0: Error { msg } }),
-                        } => {
-                            println!("Key was not insterted");
-
-                            exit(1);
-                        }
-                        _ => unreachable!("invalid response"),
-                    }
+        "set" => {
+            let value = match matches.value_of("value") {
+                Some(value) => encoding.decode(value)?,
+                None => exit(1),
+            };
+
+            let ttl_millis = match matches.value_of("ttl") {
+                Some(ttl) => parse_ttl(ttl)?.as_millis() as u64,
+                None => 0,
+            };
+
+            let cmd = Cmd::Set {
+                0: Set {
+                    key,
+                    value,
+                    ttl_millis,
+                },
+            };
+            match send_command(addr, keyspace, cmd).await? {
+                ServerResponseStatus::Ok { .. } => {}
+                ServerResponseStatus::Error { 0: Error { msg } } => {
+                    println!("Key was not insterted");
+
+                    exit(1);
                 }
-                (Some(key), Some(value), None, 2) => {
-                    info!("try to connect to a server with addr: {}", DEFAULT_ADDR);
-                    let mut client = create_grpc_client(DEFAULT_ADDR.to_string()).await?;
-
-                    let request = tonic::Request::new(KvsCommandRequest {
-                        cmd: Some(Cmd::Set {
-                            0: Set {
-                                key: key.to_string(),
-                                value: value.to_string(),
-                            },
-                        }),
-                    });
-                    let response = client.send(request).await?.into_inner();
-
-                    debug!("Response: {:?}", response);
-
-                    match response {
-                        KvsCommandResponse {
-                            status:
-                                Some(ServerResponseStatus::Ok {
-                                    0: ServerOk { msg },
-                                }),
-                        } => Ok(()),
-                        KvsCommandResponse {
-                            status: Some(ServerResponseStatus::Error { 0: Error { msg } }),
-                        } => {
-                            println!("Key was not insterted");
-
-                            exit(1);
-                        }
-                        _ => unreachable!("invalid response"),
-                    }
+                _ => unreachable!("invalid response"),
+            }
+        }
+        "incr" => {
+            let delta = match matches.value_of("delta") {
+                Some(delta) => delta.parse::<i64>()?,
+                None => 1,
+            };
+
+            match send_command(
+                addr,
+                keyspace,
+                Cmd::Incr {
+                    0: Incr { key, delta },
+                },
+            )
+            .await?
+            {
+                ServerResponseStatus::Ok {
+                    0: ServerOk { msg },
+                } => println!("{}", String::from_utf8(msg)?),
+                ServerResponseStatus::Error { 0: Error { msg } } => {
+                    eprintln!("Key does not hold an integer");
+
+                    exit(1);
                 }
-                _ => exit(1),
+                _ => unreachable!("invalid response"),
             }
         }
-        ("rm", Some(matches)) => {
-            match (
-                &matches.value_of("key"),
-                &matches.value_of("addr"),
-                &matches.args.len(),
-            ) {
-                (Some(key), Some(addr), 2) => {
-                    let addr = format!("http://{}", addr);
-                    info!("try to connect to a server with addr: {}", addr);
-                    let mut client = create_grpc_client(addr).await?;
-
-                    let request = tonic::Request::new(KvsCommandRequest {
-                        cmd: Some(Cmd::Remove {
-                            0: Remove {
-                                key: key.to_string(),
-                            },
-                        }),
-                    });
-                    let response = client.send(request).await?.into_inner();
-
-                    debug!("Response: {:?}", response);
-
-                    match response {
-                        KvsCommandResponse {
-                            status:
-                                Some(ServerResponseStatus::Ok {
-                                    0: ServerOk { msg },
-                                }),
-                        } => Ok(()),
-                        KvsCommandResponse {
-                            status: Some(ServerResponseStatus::Error { 0: Error { msg } }),
-                        } => {
-                            eprintln!("Key not found");
-
-                            exit(1);
-                        }
-                        _ => unreachable!("invalid response"),
-                    }
+        "append" => {
+            let value = match matches.value_of("value") {
+                Some(value) => encoding.decode(value)?,
+                None => exit(1),
+            };
+
+            match send_command(
+                addr,
+                keyspace,
+                Cmd::Append {
+                    0: Append { key, value },
+                },
+            )
+            .await?
+            {
+                ServerResponseStatus::Ok { .. } => {}
+                ServerResponseStatus::Error { 0: Error { msg } } => {
+                    eprintln!("Value was not appended");
+
+                    exit(1);
                 }
-                (Some(key), None, 1) => {
-                    info!("try to connect to a server with addr: {}", DEFAULT_ADDR);
-                    let mut client = create_grpc_client(DEFAULT_ADDR.to_string()).await?;
-
-                    let request = tonic::Request::new(KvsCommandRequest {
-                        cmd: Some(Cmd::Remove {
-                            0: Remove {
-                                key: key.to_string(),
-                            },
-                        }),
-                    });
-                    let response = client.send(request).await?.into_inner();
-
-                    debug!("Response: {:?}", response);
-
-                    match response {
-                        KvsCommandResponse {
-                            status:
-                                Some(ServerResponseStatus::Ok {
-                                    0: ServerOk { msg },
-                                }),
-                        } => Ok(()),
-                        KvsCommandResponse {
-                            status: Some(ServerResponseStatus::Error { 0: Error { msg } }),
-                        } => {
-                            eprintln!("Key not found");
-
-                            exit(1);
-                        }
-                        _ => unreachable!("invalid response"),
-                    }
+                _ => unreachable!("invalid response"),
+            }
+        }
+        "rm" if matches.is_present("prefix") => {
+            let cmd = Cmd::DeletePrefix {
+                0: DeletePrefix { prefix: key },
+            };
+            match send_command(addr, keyspace, cmd).await? {
+                ServerResponseStatus::Ok { .. } => {}
+                ServerResponseStatus::Error { 0: Error { msg } } => {
+                    eprintln!("Keys were not removed");
+
+                    exit(1);
                 }
-                _ => exit(1),
+                _ => unreachable!("invalid response"),
             }
         }
+        "rm" => match send_command(addr, keyspace, Cmd::Remove { 0: Remove { key } }).await? {
+            ServerResponseStatus::Ok { .. } => {}
+            ServerResponseStatus::Error { 0: Error { msg } } => {
+                eprintln!("Key not found");
+
+                exit(1);
+            }
+            _ => unreachable!("invalid response"),
+        },
         _ => panic!(),
     }
+
+    Ok(())
+}
+
+/// `<N>ms`, `<N>s`, `<N>m` or `<N>h`, a bare number is seconds
+fn parse_ttl(ttl: &str) -> std::result::Result<Duration, Box<dyn std::error::Error>> {
+    let split = ttl
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or_else(|| ttl.len());
+    let (amount, unit) = ttl.split_at(split);
+    let amount = amount.parse::<u64>()?;
+
+    let ttl = match unit {
+        "ms" => Duration::from_millis(amount),
+        "" | "s" => Duration::from_secs(amount),
+        "m" => Duration::from_secs(amount * 60),
+        "h" => Duration::from_secs(amount * 60 * 60),
+        _ => return Err(format!("unknown ttl unit: {}", unit).into()),
+    };
+    if ttl.as_millis() == 0 {
+        return Err("ttl has to be positive".into());
+    }
+
+    Ok(ttl)
+}
+
+/// sends a single command on `keyspace` to the server at `addr` and returns its response
+async fn send_command(
+    addr: &str,
+    keyspace: &str,
+    cmd: Cmd,
+) -> std::result::Result<ServerResponseStatus, Box<dyn std::error::Error>> {
+    let addr = format!("http://{}", addr);
+    info!("try to connect to a server with addr: {}", addr);
+    let mut client = create_grpc_client(addr).await?;
+
+    let request = tonic::Request::new(KvsCommandRequest {
+        cmd: Some(cmd),
+        keyspace: keyspace.to_owned(),
+    });
+    let response = match client.send(request).await {
+        Ok(response) => response.into_inner(),
+        // the command itself failed, each subcommand reports it in its own words
+        Err(status) => match status.code() {
+            Code::NotFound | Code::InvalidArgument | Code::FailedPrecondition => {
+                return Ok(ServerResponseStatus::Error {
+                    0: Error {
+                        msg: status.message().to_owned(),
+                    },
+                })
+            }
+            _ => return Err(status.into()),
+        },
+    };
+
+    debug!("Response: {:?}", response);
+
+    match response {
+        KvsCommandResponse {
+            status: Some(status),
+        } => Ok(status),
+        _ => unreachable!("invalid response"),
+    }
+}
+
+/// asks the server at `addr` for the figures of its store
+async fn request_stats(addr: &str) -> std::result::Result<EngineStats, Box<dyn std::error::Error>> {
+    let addr = format!("http://{}", addr);
+    info!("try to connect to a server with addr: {}", addr);
+    let mut client = create_grpc_client(addr).await?;
+
+    let stats = client
+        .stats(tonic::Request::new(StatsRequest {}))
+        .await?
+        .into_inner();
+    debug!("Response: {:?}", stats);
+
+    Ok(EngineStats {
+        engine: stats.engine,
+        keys: stats.keys,
+        keyspaces: stats.keyspaces,
+        disk_bytes: stats.disk_bytes,
+        live_bytes: stats.live_bytes,
+        dead_bytes: stats.dead_bytes,
+        segments: stats
+            .segments
+            .into_iter()
+            .map(|segment| SegmentStats {
+                name: segment.name,
+                bytes: segment.bytes,
+                dead_bytes: segment.dead_bytes,
+            })
+            .collect(),
+        compactions: stats.compactions,
+        last_compaction_millis: match stats.compactions {
+            0 => None,
+            _ => Some(stats.last_compaction_millis),
+        },
+    })
+}
+
+/// writes the dump of the store to stdout as the server sends it
+async fn dump(addr: &str) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let mut client = create_grpc_client(format!("http://{}", addr)).await?;
+    let mut chunks = client
+        .dump(tonic::Request::new(DumpRequest {}))
+        .await?
+        .into_inner();
+
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    while let Some(DumpChunk { data }) = chunks.message().await? {
+        stdout.write_all(&data)?;
+    }
+    stdout.flush()?;
+
+    Ok(())
+}
+
+/// sends the dump read from stdin to the server as it's read
+async fn restore(addr: &str) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let mut client = create_grpc_client(format!("http://{}", addr)).await?;
+
+    // a read error ends the chunks early, the server keeps what it got
+    let failed = Arc::new(Mutex::new(None));
+    let read_failed = Arc::clone(&failed);
+    let mut stdin = io::stdin();
+    let chunks = std::iter::from_fn(move || {
+        let mut data = vec![0; RESTORE_CHUNK_LEN];
+        match stdin.read(&mut data) {
+            Ok(0) => None,
+            Ok(len) => {
+                data.truncate(len);
+                Some(DumpChunk { data })
+            }
+            Err(e) => {
+                *read_failed.lock().expect("mutex not poisoned") = Some(e);
+                None
+            }
+        }
+    });
+    let restored = client
+        .restore(tonic::Request::new(tokio::stream::iter(chunks)))
+        .await?
+        .into_inner();
+
+    if let Some(e) = failed.lock().expect("mutex not poisoned").take() {
+        return Err(format!("stdin failed after {} records: {}", restored.records, e).into());
+    }
+    eprintln!("{} records restored", restored.records);
+
+    Ok(())
+}
+
+fn print_stats(stats: &EngineStats) {
+    println!("engine:       {}", stats.engine);
+    println!("keys:         {}", stats.keys);
+    println!("keyspaces:    {}", stats.keyspaces);
+    println!("disk bytes:   {}", stats.disk_bytes);
+    println!("live bytes:   {}", stats.live_bytes);
+    println!("dead bytes:   {}", stats.dead_bytes);
+    match stats.last_compaction_millis {
+        Some(millis) => println!(
+            "compactions:  {} (the last one took {}ms)",
+            stats.compactions, millis
+        ),
+        None => println!("compactions:  {}", stats.compactions),
+    }
+
+    if !stats.segments.is_empty() {
+        println!("segments:");
+        for segment in &stats.segments {
+            println!(
+                "  {:<12} {} bytes, {} dead",
+                segment.name, segment.bytes, segment.dead_bytes
+            );
+        }
+    }
 }
 
 #[cfg(test)]
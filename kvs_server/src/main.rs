@@ -3,125 +3,668 @@
 
 use clap::{load_yaml, App};
 use log::{error, info, LevelFilter};
-use tonic::{transport::Server, Request, Response, Status};
+use tonic::{transport::Server, Code, Request, Response, Status};
 // mod grpc::client_server;
 
+use std::collections::HashMap;
 use std::env::current_dir;
+use std::io::{self, Write};
 use std::net::SocketAddr;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use std::{
     process::exit,
     sync::{Arc, Mutex},
 };
+use tokio::sync::mpsc;
 
 use grpc::client_server::kvs_command_request::Cmd;
 use grpc::client_server::kvs_command_response::Status as ServerResponseStatus;
 use grpc::client_server::kvs_command_server::{KvsCommand, KvsCommandServer};
 use grpc::client_server::{
-    Error, Get, KvsCommandRequest, KvsCommandResponse, Ok as ServerOk, Remove, Set,
+    Append, BackupRequest, BackupResponse, Batch, BatchOp, CompareAndSwap, ConditionFailed,
+    DeletePrefix, DeleteRange, DropKeyspace, DumpChunk, DumpRequest, Get, Incr, KvsCommandRequest,
+    KvsCommandResponse, Ok as ServerOk, ReleaseSnapshot, Remove, RemoveIfEquals, RestoreResponse,
+    SegmentStats, Set, SetIfAbsent, Snapshot, SnapshotCreated, StatsRequest, StatsResponse,
 };
-use kvs::{KvStore, KvsEngine, Result, SledKvsEngine};
+use kvs::{
+    Durability, KvStore, KvsEngine, MemoryEngine, Options, Result, SledKvsEngine, WriteBatch,
+};
+
+/// the checkpoints are named after the unix time in milliseconds they were taken at
+const CHECKPOINT_PREFIX: &'static str = "checkpoint-";
+const DEFAULT_CHECKPOINT_KEEP: usize = 7;
+/// chunks of a dump the client is yet to take before the dump waits for it
+const DUMP_CHUNKS_IN_FLIGHT: usize = 16;
+const DEFAULT_SNAPSHOT_LEASE_SECS: u64 = 60;
+const DEFAULT_MAX_SNAPSHOTS: usize = 64;
 
-pub struct MySay {
-    store: Mutex<Box<dyn KvsEngine + Send>>,
+/// cloned into every blocking task which runs a request against the engine
+pub struct MySay<E: KvsEngine> {
+    store: E,
+    keyspaces: Arc<Keyspaces<E>>,
+    snapshots: Arc<Snapshots<E>>,
+    next_snapshot_id: Arc<AtomicU64>,
+    checkpoints: Option<Arc<Checkpoints>>,
 }
 
-#[tonic::async_trait]
-impl KvsCommand for MySay {
-    // our rpc impelemented as function
-    async fn send(
+impl<E: KvsEngine> Clone for MySay<E> {
+    fn clone(&self) -> Self {
+        MySay {
+            store: self.store.clone(),
+            keyspaces: Arc::clone(&self.keyspaces),
+            snapshots: Arc::clone(&self.snapshots),
+            next_snapshot_id: Arc::clone(&self.next_snapshot_id),
+            checkpoints: self.checkpoints.clone(),
+        }
+    }
+}
+
+/// the keyspaces opened so far, a request doesn't open its keyspace over again
+pub struct Keyspaces<E: KvsEngine> {
+    open: Mutex<HashMap<String, E>>,
+}
+
+impl<E: KvsEngine> Keyspaces<E> {
+    fn new() -> Self {
+        Keyspaces {
+            open: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// `store` itself for the default keyspace, the keyspace is opened the first time only
+    fn get(&self, store: &E, name: &str) -> Result<E> {
+        if name.is_empty() {
+            return Ok(store.clone());
+        }
+
+        let mut open = self.open.lock().expect("mutex not poisoned");
+        if let Some(keyspace) = open.get(name) {
+            return Ok(keyspace.clone());
+        }
+        let keyspace = store.open_tree(name)?;
+        open.insert(name.to_owned(), keyspace.clone());
+
+        Ok(keyspace)
+    }
+
+    /// drops the keyspace along with its handle, the handle of a dropped keyspace fails
+    /// every call while the name can be opened anew
+    fn drop_keyspace(&self, store: &E, name: &str) -> Result<()> {
+        let mut open = self.open.lock().expect("mutex not poisoned");
+        store.drop_tree(name)?;
+        open.remove(name);
+
+        Ok(())
+    }
+}
+
+/// snapshots taken by clients until they release them or leave them unused for `lease`,
+/// a client which goes away without releasing its snapshots can't keep them forever
+pub struct Snapshots<E: KvsEngine> {
+    taken: Mutex<HashMap<u64, (E, Instant)>>,
+    limits: SnapshotLimits,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SnapshotLimits {
+    lease: Duration,
+    /// snapshots taken at once, the next one fails with `ResourceExhausted`
+    max: usize,
+}
+
+impl<E: KvsEngine> Snapshots<E> {
+    fn new(limits: SnapshotLimits) -> Self {
+        Snapshots {
+            taken: Mutex::new(HashMap::new()),
+            limits,
+        }
+    }
+
+    /// the snapshot with the id, its lease starts over
+    fn get(&self, id: u64) -> Option<E> {
+        let mut taken = self.taken.lock().expect("mutex not poisoned");
+        let (snapshot, used) = taken.get_mut(&id)?;
+        *used = Instant::now();
+
+        Some(snapshot.clone())
+    }
+
+    /// `false` once there are `max` snapshots
+    fn insert(&self, id: u64, snapshot: E) -> bool {
+        let mut taken = self.taken.lock().expect("mutex not poisoned");
+        if taken.len() >= self.limits.max {
+            return false;
+        }
+        taken.insert(id, (snapshot, Instant::now()));
+
+        true
+    }
+
+    fn remove(&self, id: u64) -> Option<E> {
+        let released = self.taken.lock().expect("mutex not poisoned").remove(&id);
+
+        released.map(|(snapshot, _)| snapshot)
+    }
+
+    /// releases the snapshots left unused for longer than the lease
+    fn expire(&self) {
+        let lease = self.limits.lease;
+        let expired: Vec<E> = {
+            let mut taken = self.taken.lock().expect("mutex not poisoned");
+            let ids: Vec<u64> = taken
+                .iter()
+                .filter(|(_, (_, used))| used.elapsed() > lease)
+                .map(|(id, _)| *id)
+                .collect();
+            ids.iter()
+                .filter_map(|id| taken.remove(id))
+                .map(|(snapshot, _)| snapshot)
+                .collect()
+        };
+
+        // dropped outside of the lock, a snapshot can take a while to let go of its data
+        if !expired.is_empty() {
+            info!("snapshots: {} expired", expired.len());
+        }
+    }
+}
+
+/// releases the expired snapshots for as long as the server runs
+fn expire_snapshots<E: KvsEngine>(snapshots: Arc<Snapshots<E>>) {
+    let interval = (snapshots.limits.lease / 2).max(Duration::from_millis(100));
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        snapshots.expire();
+    });
+}
+
+impl<E: KvsEngine> MySay<E> {
+    fn new(store: E, checkpoints: Option<Arc<Checkpoints>>, snapshots: Arc<Snapshots<E>>) -> Self {
+        MySay {
+            store,
+            keyspaces: Arc::new(Keyspaces::new()),
+            snapshots,
+            // 0 stands for the live data
+            next_snapshot_id: Arc::new(AtomicU64::new(1)),
+            checkpoints,
+        }
+    }
+
+    /// `store` for 0, the same keyspace of the snapshot with the id otherwise
+    fn engine(&self, store: &E, snapshot_id: u64, keyspace: &str) -> Option<E> {
+        match snapshot_id {
+            0 => Some(store.clone()),
+            id => in_keyspace(&self.snapshots.get(id)?, keyspace).ok(),
+        }
+    }
+}
+
+/// the directory of the scheduled checkpoints, it holds the newest `keep` of them
+pub struct Checkpoints {
+    dir: PathBuf,
+    keep: usize,
+    /// `None` for checkpoints taken on request only
+    interval: Option<Duration>,
+    /// one checkpoint at a time, so they are pruned in the order they were taken
+    taking: Mutex<()>,
+}
+
+impl Checkpoints {
+    /// takes a checkpoint into a new directory and removes the oldest ones past `keep`
+    fn take<E: KvsEngine>(&self, store: &E) -> Result<PathBuf> {
+        let _taking = self.taking.lock().expect("mutex not poisoned");
+        std::fs::create_dir_all(&self.dir)?;
+
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_millis())
+            .unwrap_or(0);
+        let dest = self.dir.join(format!("{}{}", CHECKPOINT_PREFIX, millis));
+        store.checkpoint(&dest)?;
+
+        let mut taken = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = match entry.file_name().into_string() {
+                Ok(name) if name.starts_with(CHECKPOINT_PREFIX) => name,
+                _ => continue,
+            };
+            if let Ok(millis) = name[CHECKPOINT_PREFIX.len()..].parse::<u128>() {
+                taken.push((millis, entry.path()));
+            }
+        }
+        taken.sort_unstable();
+
+        let expired = taken.len().saturating_sub(self.keep);
+        for (_, path) in taken.into_iter().take(expired) {
+            std::fs::remove_dir_all(path)?;
+        }
+
+        Ok(dest)
+    }
+
+    /// the destination of a backup within the checkpoint directory,
+    /// `None` for anything but a single name, e.g. an absolute path or `..`,
+    /// and for the names of the scheduled checkpoints, which `take` removes
+    fn resolve(&self, dest_dir: &str) -> Option<PathBuf> {
+        if dest_dir.starts_with(CHECKPOINT_PREFIX) {
+            return None;
+        }
+
+        let dest_dir = Path::new(dest_dir);
+        let mut components = dest_dir.components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(_)), None) => Some(self.dir.join(dest_dir)),
+            _ => None,
+        }
+    }
+}
+
+/// takes a checkpoint every `interval` for as long as the server runs
+fn schedule_checkpoints<E: KvsEngine>(store: E, checkpoints: Arc<Checkpoints>, interval: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        match checkpoints.take(&store) {
+            Ok(dest) => info!("checkpoint: {}", dest.display()),
+            Err(e) => error!("checkpoint failed: {}", e),
+        }
+    });
+}
+
+/// sends what a dump writes to the client as chunks, the dump waits while the client
+/// is behind and stops once it's gone
+struct DumpWriter {
+    chunks: mpsc::Sender<std::result::Result<DumpChunk, Status>>,
+}
+
+impl Write for DumpWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let chunk = DumpChunk { data: buf.to_vec() };
+        futures::executor::block_on(self.chunks.send(Ok(chunk)))
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "the client is gone"))?;
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// runs engine calls, which fsync, wait for compaction or run transactions,
+/// on a thread meant for blocking instead of holding up the runtime
+async fn blocking<T, F>(f: F) -> std::result::Result<T, Status>
+where
+    F: FnOnce() -> std::result::Result<T, Status> + Send + 'static,
+    T: Send + 'static,
+{
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| Status::internal(format!("engine call failed: {}", e)))?
+}
+
+/// `store` itself for the default keyspace, used for the keyspaces of snapshots
+fn in_keyspace<E: KvsEngine>(store: &E, keyspace: &str) -> Result<E> {
+    match keyspace {
+        "" => Ok(store.clone()),
+        name => store.open_tree(name),
+    }
+}
+
+impl<E: KvsEngine> MySay<E> {
+    /// runs a command against the engine, it blocks so it's run by `blocking`
+    fn command(
         &self,
-        request: Request<KvsCommandRequest>,
-    ) -> std::result::Result<Response<KvsCommandResponse>, Status> {
-        let response: ServerResponseStatus =
-            if let KvsCommandRequest { cmd: Some(cmd) } = request.get_ref() {
-                match cmd {
-                    Cmd::Get { 0: Get { key } } => {
-                        if let Ok(Some(string)) = self
-                            .store
-                            .lock()
-                            .expect("mutex not poisoned")
-                            .get(key.to_owned())
-                        // .expect("error during fetching for get request")
-                        {
-                            ServerResponseStatus::Ok {
-                                0: ServerOk { msg: string },
-                            }
-                        } else {
-                            ServerResponseStatus::Error {
-                                0: Error {
-                                    msg: "get: key not found".to_string(),
-                                },
-                            }
-                        }
+        request: &KvsCommandRequest,
+    ) -> std::result::Result<ServerResponseStatus, Status> {
+        let keyspace = &request.keyspace;
+        let store = self
+            .keyspaces
+            .get(&self.store, keyspace)
+            .map_err(error_status)?;
 
-                        // ServerResponseStatus::Ok {
-                        //     0: ServerOk {
-                        //         msg: format!("get: {}", key),
-                        //     },
-                        // }
+        let response = if let KvsCommandRequest { cmd: Some(cmd), .. } = request {
+            match cmd {
+                Cmd::Get {
+                    0: Get { key, snapshot_id },
+                } => {
+                    match self.engine(&store, *snapshot_id, keyspace) {
+                        Some(store) => match store.get(key.to_owned()) {
+                            Ok(Some(value)) => ServerResponseStatus::Ok {
+                                0: ServerOk { msg: value },
+                            },
+                            Ok(None) => return Err(error_status(kvs::Error::KeyNotFound)),
+                            Err(e) => return Err(error_status(e)),
+                        },
+                        None => return Err(Status::not_found("unknown snapshot")),
                     }
-                    Cmd::Set {
-                        0: Set { key, value },
-                    } => {
-                        if let Ok(()) = self
-                            .store
-                            .lock()
-                            .expect("mutex not poisoned")
-                            .set(key.to_owned(), value.to_owned())
-                        {
-                            ServerResponseStatus::Ok {
-                                0: ServerOk { msg: "".to_owned() },
-                            }
+
+                    // ServerResponseStatus::Ok {
+                    //     0: ServerOk {
+                    //         msg: format!("get: {}", key),
+                    //     },
+                    // }
+                }
+                Cmd::Set {
+                    0:
+                        Set {
+                            key,
+                            value,
+                            ttl_millis,
+                        },
+                } => {
+                    let result = match ttl_millis {
+                        0 => store.set(key.to_owned(), value.to_owned()),
+                        _ => store.set_with_ttl(
+                            key.to_owned(),
+                            value.to_owned(),
+                            Duration::from_millis(*ttl_millis),
+                        ),
+                    };
+
+                    ok_response(result)?
+                    // ServerResponseStatus::Ok {
+                    //     0: ServerOk {
+                    //         msg: format!("set: {} {}", key, value),
+                    //     },
+                    // }
+                }
+                Cmd::Remove { 0: Remove { key } } => {
+                    ok_response(store.remove(key.to_owned()))?
+                    // ServerResponseStatus::Ok {
+                    //     0: ServerOk {
+                    //         msg: format!("remove: {}", key),
+                    //     },
+                    // }
+                }
+                Cmd::DeleteRange {
+                    0: DeleteRange { start, end },
+                } => ok_response(store.delete_range(start.to_owned(), end.to_owned()))?,
+                Cmd::DeletePrefix {
+                    0: DeletePrefix { prefix },
+                } => ok_response(store.delete_prefix(prefix.to_owned()))?,
+                Cmd::Incr {
+                    0: Incr { key, delta },
+                } => merge_response(&store, key, "add", delta.to_string().into_bytes())?,
+                Cmd::Append {
+                    0: Append { key, value },
+                } => merge_response(&store, key, "append", value.to_owned())?,
+                Cmd::Batch { 0: Batch { ops } } => {
+                    let mut batch = WriteBatch::new();
+                    for BatchOp { key, value, remove } in ops {
+                        if *remove {
+                            batch.delete(key.to_owned());
                         } else {
-                            ServerResponseStatus::Error {
-                                0: Error {
-                                    msg: "set: error during set".to_string(),
-                                },
-                            }
+                            batch.put(key.to_owned(), value.to_owned());
                         }
-                        // ServerResponseStatus::Ok {
-                        //     0: ServerOk {
-                        //         msg: format!("set: {} {}", key, value),
-                        //     },
-                        // }
                     }
-                    Cmd::Remove { 0: Remove { key } } => {
-                        if let Ok(()) = self
-                            .store
-                            .lock()
-                            .expect("mutex not poisoned")
-                            .remove(key.to_owned())
-                        {
-                            ServerResponseStatus::Ok {
-                                0: ServerOk { msg: "".to_owned() },
-                            }
-                        } else {
-                            ServerResponseStatus::Error {
-                                0: Error {
-                                    msg: "remove: error during remove".to_string(),
-                                },
-                            }
+
+                    ok_response(store.write_batch(batch))?
+                }
+                Cmd::CompareAndSwap {
+                    0:
+                        CompareAndSwap {
+                            key,
+                            expected,
+                            expect_absent,
+                            new,
+                            remove,
+                        },
+                } => {
+                    let expected = if *expect_absent {
+                        None
+                    } else {
+                        Some(expected.to_owned())
+                    };
+                    let new = if *remove { None } else { Some(new.to_owned()) };
+
+                    conditional_response(store.compare_and_swap(key.to_owned(), expected, new))?
+                }
+                Cmd::SetIfAbsent {
+                    0: SetIfAbsent { key, value },
+                } => conditional_response(store.set_if_absent(key.to_owned(), value.to_owned()))?,
+                Cmd::RemoveIfEquals {
+                    0: RemoveIfEquals { key, expected },
+                } => conditional_response(
+                    store.remove_if_equals(key.to_owned(), expected.to_owned()),
+                )?,
+                Cmd::Snapshot { 0: Snapshot {} } => match self.store.snapshot() {
+                    Ok(snapshot) => {
+                        let snapshot_id = self.next_snapshot_id.fetch_add(1, Ordering::SeqCst);
+                        if !self.snapshots.insert(snapshot_id, snapshot) {
+                            return Err(Status::resource_exhausted(
+                                "too many snapshots, release some first",
+                            ));
+                        }
+
+                        ServerResponseStatus::SnapshotCreated {
+                            0: SnapshotCreated { snapshot_id },
                         }
-                        // ServerResponseStatus::Ok {
-                        //     0: ServerOk {
-                        //         msg: format!("remove: {}", key),
-                        //     },
-                        // }
                     }
-                    _ => unreachable!(),
-                }
-            } else {
-                ServerResponseStatus::Error {
-                    0: Error {
-                        msg: "error: unknown command".to_string(),
-                    },
+                    Err(e) => return Err(error_status(e)),
+                },
+                Cmd::ReleaseSnapshot {
+                    0: ReleaseSnapshot { snapshot_id },
+                } => {
+                    let released = self.snapshots.remove(*snapshot_id);
+
+                    if let Some(_) = released {
+                        ServerResponseStatus::Ok {
+                            0: ServerOk { msg: Vec::new() },
+                        }
+                    } else {
+                        return Err(Status::not_found("unknown snapshot"));
+                    }
                 }
-            };
+                Cmd::DropKeyspace {
+                    0: DropKeyspace { name },
+                } => ok_response(self.keyspaces.drop_keyspace(&self.store, name))?,
+                _ => unreachable!(),
+            }
+        } else {
+            return Err(Status::invalid_argument("unknown command"));
+        };
+
+        Ok(response)
+    }
+}
+
+#[tonic::async_trait]
+impl<E: KvsEngine> KvsCommand for MySay<E> {
+    // our rpc impelemented as function
+    async fn send(
+        &self,
+        request: Request<KvsCommandRequest>,
+    ) -> std::result::Result<Response<KvsCommandResponse>, Status> {
+        let say = self.clone();
+        let request = request.into_inner();
+        let response = blocking(move || say.command(&request)).await?;
 
         Ok(Response::new(KvsCommandResponse {
             status: Some(response),
         }))
     }
+
+    async fn stats(
+        &self,
+        _request: Request<StatsRequest>,
+    ) -> std::result::Result<Response<StatsResponse>, Status> {
+        let store = self.store.clone();
+        let stats = blocking(move || store.stats().map_err(error_status)).await?;
+
+        Ok(Response::new(StatsResponse {
+            engine: stats.engine,
+            keys: stats.keys,
+            keyspaces: stats.keyspaces,
+            disk_bytes: stats.disk_bytes,
+            live_bytes: stats.live_bytes,
+            dead_bytes: stats.dead_bytes,
+            segments: stats
+                .segments
+                .into_iter()
+                .map(|segment| SegmentStats {
+                    name: segment.name,
+                    bytes: segment.bytes,
+                    dead_bytes: segment.dead_bytes,
+                })
+                .collect(),
+            compactions: stats.compactions,
+            last_compaction_millis: stats.last_compaction_millis.unwrap_or_default(),
+        }))
+    }
+
+    /// writes a checkpoint into the checkpoint directory, as the next scheduled one
+    /// or into a new directory the client names, refused without a checkpoint directory
+    async fn backup(
+        &self,
+        request: Request<BackupRequest>,
+    ) -> std::result::Result<Response<BackupResponse>, Status> {
+        let checkpoints = match &self.checkpoints {
+            Some(checkpoints) => Arc::clone(checkpoints),
+            None => return Err(Status::invalid_argument("no checkpoint directory")),
+        };
+        let store = self.store.clone();
+        let dest_dir = request.into_inner().dest_dir;
+        let dest = blocking(move || match dest_dir.as_str() {
+            "" => checkpoints.take(&store).map_err(error_status),
+            dest_dir => {
+                let dest = checkpoints.resolve(dest_dir).ok_or_else(|| {
+                    Status::invalid_argument(format!(
+                        "the destination must be a single directory name not starting with {}",
+                        CHECKPOINT_PREFIX
+                    ))
+                })?;
+                std::fs::create_dir_all(&checkpoints.dir).map_err(|e| error_status(e.into()))?;
+                store.checkpoint(&dest).map_err(error_status)?;
+                Ok(dest)
+            }
+        })
+        .await?;
+        info!("backup: {}", dest.display());
+
+        Ok(Response::new(BackupResponse {
+            dir: dest.display().to_string(),
+        }))
+    }
+
+    type DumpStream = mpsc::Receiver<std::result::Result<DumpChunk, Status>>;
+
+    /// the store is exported on its own thread as the client takes the chunks
+    async fn dump(
+        &self,
+        _request: Request<DumpRequest>,
+    ) -> std::result::Result<Response<Self::DumpStream>, Status> {
+        let (chunks, receiver) = mpsc::channel(DUMP_CHUNKS_IN_FLIGHT);
+        let store = self.store.clone();
+        thread::spawn(move || {
+            let mut writer = DumpWriter { chunks };
+            match kvs::export(&store, &mut writer) {
+                Ok(records) => info!("dump: {} records", records),
+                Err(e) => {
+                    error!("dump failed: {}", e);
+                    let _ = futures::executor::block_on(writer.chunks.send(Err(error_status(e))));
+                }
+            }
+        });
+
+        Ok(Response::new(receiver))
+    }
+
+    /// imports the whole lines received so far with every chunk
+    async fn restore(
+        &self,
+        request: Request<tonic::Streaming<DumpChunk>>,
+    ) -> std::result::Result<Response<RestoreResponse>, Status> {
+        let mut chunks = request.into_inner();
+        let mut pending = Vec::new();
+        let mut records = 0;
+        while let Some(DumpChunk { data }) = chunks.message().await? {
+            pending.extend_from_slice(&data);
+            if let Some(end) = pending.iter().rposition(|byte| *byte == b'\n') {
+                let rest = pending.split_off(end + 1);
+                records += import_lines(&self.store, pending).await?;
+                pending = rest;
+            }
+        }
+        records += import_lines(&self.store, pending).await?;
+        info!("restore: {} records", records);
+
+        Ok(Response::new(RestoreResponse { records }))
+    }
+}
+
+/// imports whole lines of a dump, a dump the engine can't read is the client's fault
+async fn import_lines<E: KvsEngine>(store: &E, lines: Vec<u8>) -> std::result::Result<u64, Status> {
+    let store = store.clone();
+    blocking(move || {
+        kvs::import(&store, lines.as_slice()).map_err(|e| match e {
+            kvs::Error::Serialization(e) => Status::invalid_argument(format!("bad dump: {}", e)),
+            e => error_status(e),
+        })
+    })
+    .await
+}
+
+/// the gRPC status of a command the engine failed
+fn error_status(e: kvs::Error) -> Status {
+    let code = match &e {
+        kvs::Error::KeyNotFound => Code::NotFound,
+        kvs::Error::InvalidKeyspace
+        | kvs::Error::UnknownMergeOperator
+        | kvs::Error::InvalidMergeOperator
+        | kvs::Error::NotUtf8 => Code::InvalidArgument,
+        kvs::Error::ReadOnly
+        | kvs::Error::KeyspaceDropped
+        | kvs::Error::MergeFailed
+        | kvs::Error::Locked { .. } => Code::FailedPrecondition,
+        kvs::Error::Unsupported => Code::Unimplemented,
+        kvs::Error::ConditionFailed { .. } => Code::Aborted,
+        kvs::Error::Corruption { .. } => Code::DataLoss,
+        kvs::Error::Io(_) | kvs::Error::Serialization(_) | kvs::Error::Engine(_) => Code::Internal,
+    };
+
+    Status::new(code, e.to_string())
+}
+
+/// an empty ok for a write which went through
+fn ok_response(result: Result<()>) -> std::result::Result<ServerResponseStatus, Status> {
+    match result {
+        Ok(()) => Ok(ServerResponseStatus::Ok {
+            0: ServerOk { msg: Vec::new() },
+        }),
+        Err(e) => Err(error_status(e)),
+    }
+}
+
+/// response to a conditional write, a failed condition hands back the current value
+fn conditional_response(result: Result<()>) -> std::result::Result<ServerResponseStatus, Status> {
+    match result {
+        Err(kvs::Error::ConditionFailed { current }) => Ok(ServerResponseStatus::ConditionFailed {
+            0: ConditionFailed {
+                absent: current.is_none(),
+                current: current.unwrap_or_default(),
+            },
+        }),
+        result => ok_response(result),
+    }
+}
+
+/// response to a merge, it carries the value the merge made
+fn merge_response<E: KvsEngine>(
+    store: &E,
+    key: &[u8],
+    operator: &str,
+    operand: Vec<u8>,
+) -> std::result::Result<ServerResponseStatus, Status> {
+    match store.merge(key.to_owned(), operator, operand) {
+        Ok(value) => Ok(ServerResponseStatus::Ok {
+            0: ServerOk { msg: value },
+        }),
+        Err(e) => Err(error_status(e)),
+    }
 }
 
 #[tokio::main]
@@ -138,10 +681,48 @@ async fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
 
     let engine = match matches.value_of("engine") {
         Some("sled") => "sled",
+        Some("memory") => "memory",
         _ => "kvs",
     };
 
+    let durability = match matches.value_of("durability") {
+        Some(durability) => durability.parse::<Durability>()?,
+        None => Durability::default(),
+    };
+    let mut options = Options::new().durability(durability);
+    if let Some(limit) = matches.value_of("memory-limit") {
+        options = options.memory_limit(limit.parse::<usize>()?);
+    }
+
+    let checkpoints = match matches.value_of("checkpoint-dir") {
+        Some(dir) => Some(Arc::new(Checkpoints {
+            dir: PathBuf::from(dir),
+            keep: match matches.value_of("checkpoint-keep") {
+                Some(keep) => keep.parse::<usize>()?.max(1),
+                None => DEFAULT_CHECKPOINT_KEEP,
+            },
+            interval: match matches.value_of("checkpoint-interval") {
+                Some(secs) => Some(Duration::from_secs(secs.parse::<u64>()?)),
+                None => None,
+            },
+            taking: Mutex::new(()),
+        })),
+        None => None,
+    };
+    let snapshot_limits = SnapshotLimits {
+        lease: match matches.value_of("snapshot-lease") {
+            Some(secs) => Duration::from_secs(secs.parse::<u64>()?),
+            None => Duration::from_secs(DEFAULT_SNAPSHOT_LEASE_SECS),
+        },
+        max: match matches.value_of("max-snapshots") {
+            Some(max) => max.parse::<usize>()?,
+            None => DEFAULT_MAX_SNAPSHOTS,
+        },
+    };
+
     match try_find_config(&current_dir()?) {
+        // nothing on disk to mix up
+        _ if engine == "memory" => {}
         Ok(config) if config.contains(engine) => {}
         Ok(not_valid) if !not_valid.contains(engine) => {
             error!(
@@ -156,20 +737,72 @@ async fn main() -> std::result::Result<(), Box<dyn std::error::Error>> {
         _ => unreachable!(),
     }
 
-    let mut store: Mutex<Box<dyn KvsEngine + Send>> = if engine == "sled" {
-        Mutex::new(Box::new(SledKvsEngine::new(&current_dir()?)))
-    } else {
-        Mutex::new(Box::new(KvStore::open(&current_dir()?).unwrap()))
-    };
-
-    // let mut store: Mutex<Box<dyn KvsEngine + Send>> =
-    // Mutex::new(Box::new(KvStore::open(&current_dir()?).unwrap()));
-
     info!("version: {}", env!("CARGO_PKG_VERSION"));
     info!("addr: {}", addr);
     info!("engine: {}", engine);
+    info!("durability: {:?}", durability);
+
+    if engine == "sled" {
+        serve(
+            open_or_exit(SledKvsEngine::open_with(&current_dir()?, &options)),
+            addr,
+            checkpoints,
+            snapshot_limits,
+        )
+        .await
+    } else if engine == "memory" {
+        serve(
+            MemoryEngine::with_options(&options),
+            addr,
+            checkpoints,
+            snapshot_limits,
+        )
+        .await
+    } else {
+        serve(
+            open_or_exit(KvStore::open_with(&current_dir()?, &options)),
+            addr,
+            checkpoints,
+            snapshot_limits,
+        )
+        .await
+    }
+}
 
-    let say = MySay { store };
+/// a store which can't be opened, e.g. a locked or a corrupted one, ends the server
+fn open_or_exit<E: KvsEngine>(opened: Result<E>) -> E {
+    match opened {
+        Ok(store) => store,
+        Err(e) => {
+            error!("can't open the store: {}", e);
+            exit(1);
+        }
+    }
+}
+
+async fn serve<E: KvsEngine>(
+    store: E,
+    addr: SocketAddr,
+    checkpoints: Option<Arc<Checkpoints>>,
+    snapshot_limits: SnapshotLimits,
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    if let Some(checkpoints) = &checkpoints {
+        if let Some(interval) = checkpoints.interval {
+            info!(
+                "checkpoints: every {:?} into {}",
+                interval,
+                checkpoints.dir.display()
+            );
+            schedule_checkpoints(store.clone(), Arc::clone(checkpoints), interval);
+        }
+    }
+    info!(
+        "snapshots: at most {}, released after {:?} unused",
+        snapshot_limits.max, snapshot_limits.lease
+    );
+    let snapshots = Arc::new(Snapshots::new(snapshot_limits));
+    expire_snapshots(Arc::clone(&snapshots));
+    let say = MySay::new(store, checkpoints, snapshots);
     info!("Server listening on {}", addr);
     // adding our service to our server.
     Server::builder()
@@ -299,6 +299,38 @@ fn cli_access_server(engine: &str, addr: &str) {
         .success()
         .stdout(is_empty());
 
+    // binary key and value
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "00ff", "deadbeef", "--hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "00ff", "--hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("deadbeef\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "AP8=", "--base64", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("3q2+7w==\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "not hex", "--hex", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
     sender.send(()).unwrap();
     handle.join().unwrap();
 
@@ -18,7 +18,12 @@ use grpc::client_server::{
     kvs_command_request::Cmd,
     kvs_command_response::Status as ServerResponseStatus,
     kvs_command_server::{KvsCommand, KvsCommandServer},
-    {Error, Get, KvsCommandRequest, KvsCommandResponse, Ok as ServerOk, Remove, Set},
+    {
+        Append, BackupRequest, BackupResponse, Batch, BatchOp, CompareAndSwap, DeletePrefix,
+        DeleteRange, DropKeyspace, DumpChunk, DumpRequest, Error, Get, Incr, KvsCommandRequest,
+        KvsCommandResponse, Ok as ServerOk, ReleaseSnapshot, Remove, RemoveIfEquals,
+        RestoreResponse, SegmentStats, Set, SetIfAbsent, StatsRequest, StatsResponse,
+    },
 };
 
 use tests::utils::get_available_port;
@@ -36,24 +41,126 @@ impl KvsCommand for TestKvsServer {
         &self,
         request: Request<KvsCommandRequest>,
     ) -> std::result::Result<Response<KvsCommandResponse>, Status> {
+        let keyspace = &request.get_ref().keyspace;
         let response: ServerResponseStatus =
-            if let KvsCommandRequest { cmd: Some(cmd) } = request.get_ref() {
+            if let KvsCommandRequest { cmd: Some(cmd), .. } = request.get_ref() {
                 match cmd {
-                    Cmd::Get { 0: Get { key } } => ServerResponseStatus::Ok {
+                    Cmd::Get { 0: Get { key, .. } } if !keyspace.is_empty() => {
+                        ServerResponseStatus::Ok {
+                            0: ServerOk {
+                                msg: format!("get: {}/{}", keyspace, String::from_utf8_lossy(key))
+                                    .into_bytes(),
+                            },
+                        }
+                    }
+                    Cmd::Get { 0: Get { key, .. } } => ServerResponseStatus::Ok {
                         0: ServerOk {
-                            msg: format!("get: {}", key),
+                            msg: format!("get: {}", String::from_utf8_lossy(key)).into_bytes(),
                         },
                     },
                     Cmd::Set {
-                        0: Set { key, value },
+                        0: Set { key, value, .. },
                     } => ServerResponseStatus::Ok {
                         0: ServerOk {
-                            msg: format!("set: {} {}", key, value),
+                            msg: format!(
+                                "set: {} {}",
+                                String::from_utf8_lossy(key),
+                                String::from_utf8_lossy(value)
+                            )
+                            .into_bytes(),
                         },
                     },
                     Cmd::Remove { 0: Remove { key } } => ServerResponseStatus::Ok {
                         0: ServerOk {
-                            msg: format!("remove: {}", key),
+                            msg: format!("remove: {}", String::from_utf8_lossy(key)).into_bytes(),
+                        },
+                    },
+                    Cmd::DeleteRange {
+                        0: DeleteRange { start, end },
+                    } => ServerResponseStatus::Ok {
+                        0: ServerOk {
+                            msg: format!(
+                                "delete range: {} {}",
+                                String::from_utf8_lossy(start),
+                                String::from_utf8_lossy(end)
+                            )
+                            .into_bytes(),
+                        },
+                    },
+                    Cmd::DeletePrefix {
+                        0: DeletePrefix { prefix },
+                    } => ServerResponseStatus::Ok {
+                        0: ServerOk {
+                            msg: format!("delete prefix: {}", String::from_utf8_lossy(prefix))
+                                .into_bytes(),
+                        },
+                    },
+                    Cmd::Incr {
+                        0: Incr { key, delta },
+                    } => ServerResponseStatus::Ok {
+                        0: ServerOk {
+                            msg: format!("incr: {} {}", String::from_utf8_lossy(key), delta)
+                                .into_bytes(),
+                        },
+                    },
+                    Cmd::Append {
+                        0: Append { key, value },
+                    } => ServerResponseStatus::Ok {
+                        0: ServerOk {
+                            msg: format!(
+                                "append: {} {}",
+                                String::from_utf8_lossy(key),
+                                String::from_utf8_lossy(value)
+                            )
+                            .into_bytes(),
+                        },
+                    },
+                    Cmd::Batch { 0: Batch { ops } } => ServerResponseStatus::Ok {
+                        0: ServerOk {
+                            msg: format!("batch: {}", ops.len()).into_bytes(),
+                        },
+                    },
+                    Cmd::CompareAndSwap {
+                        0: CompareAndSwap { key, .. },
+                    } => ServerResponseStatus::Ok {
+                        0: ServerOk {
+                            msg: format!("compare and swap: {}", String::from_utf8_lossy(key))
+                                .into_bytes(),
+                        },
+                    },
+                    Cmd::SetIfAbsent {
+                        0: SetIfAbsent { key, .. },
+                    } => ServerResponseStatus::Ok {
+                        0: ServerOk {
+                            msg: format!("set if absent: {}", String::from_utf8_lossy(key))
+                                .into_bytes(),
+                        },
+                    },
+                    Cmd::RemoveIfEquals {
+                        0: RemoveIfEquals { key, .. },
+                    } => ServerResponseStatus::Ok {
+                        0: ServerOk {
+                            msg: format!("remove if equals: {}", String::from_utf8_lossy(key))
+                                .into_bytes(),
+                        },
+                    },
+                    Cmd::Snapshot { .. } => ServerResponseStatus::Ok {
+                        0: ServerOk {
+                            msg: b"snapshot".to_vec(),
+                        },
+                    },
+                    Cmd::ReleaseSnapshot {
+                        0: ReleaseSnapshot { snapshot_id },
+                    } => ServerResponseStatus::Ok {
+                        0: ServerOk {
+                            msg: format!("release snapshot: {}", snapshot_id).into_bytes(),
+                        },
+                    },
+                    Cmd::DropKeyspace {
+                        0: DropKeyspace { name },
+                    } => ServerResponseStatus::Ok {
+                        0: ServerOk {
+                            msg: format!("drop keyspace: {}", name).into_bytes(),
                         },
                     },
                 }
@@ -69,6 +176,68 @@ impl KvsCommand for TestKvsServer {
             status: Some(response),
         }))
     }
+
+    async fn stats(
+        &self,
+        _request: Request<StatsRequest>,
+    ) -> std::result::Result<Response<StatsResponse>, Status> {
+        Ok(Response::new(StatsResponse {
+            engine: "kvs".to_owned(),
+            keys: 2,
+            disk_bytes: 100,
+            live_bytes: 60,
+            dead_bytes: 40,
+            segments: vec![SegmentStats {
+                name: "1.log".to_owned(),
+                bytes: 100,
+                dead_bytes: 40,
+            }],
+            ..StatsResponse::default()
+        }))
+    }
+
+    async fn backup(
+        &self,
+        request: Request<BackupRequest>,
+    ) -> std::result::Result<Response<BackupResponse>, Status> {
+        let dir = match request.get_ref().dest_dir.as_str() {
+            "" => "checkpoints/checkpoint-1".to_owned(),
+            dest_dir => dest_dir.to_owned(),
+        };
+
+        Ok(Response::new(BackupResponse { dir }))
+    }
+
+    type DumpStream = futures::stream::Iter<std::vec::IntoIter<Result<DumpChunk, Status>>>;
+
+    async fn dump(
+        &self,
+        _request: Request<DumpRequest>,
+    ) -> std::result::Result<Response<Self::DumpStream>, Status> {
+        let chunks = vec![
+            Ok(DumpChunk {
+                data: b"{\"key\":\"a\",\"value\":\"1\"}\n{\"key\":".to_vec(),
+            }),
+            Ok(DumpChunk {
+                data: b"\"b\",\"value\":\"2\"}\n".to_vec(),
+            }),
+        ];
+
+        Ok(Response::new(futures::stream::iter(chunks)))
+    }
+
+    async fn restore(
+        &self,
+        request: Request<tonic::Streaming<DumpChunk>>,
+    ) -> std::result::Result<Response<RestoreResponse>, Status> {
+        let mut chunks = request.into_inner();
+        let mut records = 0;
+        while let Some(DumpChunk { data }) = chunks.message().await? {
+            records += data.iter().filter(|byte| **byte == b'\n').count() as u64;
+        }
+
+        Ok(Response::new(RestoreResponse { records }))
+    }
 }
 
 async fn client(
@@ -94,7 +263,7 @@ async fn client(
                     0: ServerOk { msg },
                 }),
         } => {
-            predicate(msg);
+            predicate(String::from_utf8_lossy(&msg).into_owned());
         }
         KvsCommandResponse {
             status: Some(ServerResponseStatus::Error { 0: Error { msg } }),
@@ -133,9 +302,11 @@ async fn client_sends_get_message() {
     let request = KvsCommandRequest {
         cmd: Some(Cmd::Get {
             0: Get {
-                key: "key1".to_owned(),
+                key: b"key1".to_vec(),
+                snapshot_id: 0,
             },
         }),
+        keyspace: String::new(),
     };
     let expected_response_message = "get: key1".to_owned();
 
@@ -194,10 +365,12 @@ async fn client_sends_set_message() -> std::result::Result<(), Box<dyn std::erro
     let request = KvsCommandRequest {
         cmd: Some(Cmd::Set {
             0: Set {
-                key: "key1".to_owned(),
-                value: "value1".to_owned(),
+                key: b"key1".to_vec(),
+                value: b"value1".to_vec(),
+                ttl_millis: 0,
             },
         }),
+        keyspace: String::new(),
     };
     let expected_response_message = "set: key1 value1".to_owned();
     let predicate = |msg| {
@@ -234,9 +407,10 @@ async fn client_sends_remove_message() -> std::result::Result<(), Box<dyn std::e
     let request = KvsCommandRequest {
         cmd: Some(Cmd::Remove {
             0: Remove {
-                key: "key1".to_owned(),
+                key: b"key1".to_vec(),
             },
         }),
+        keyspace: String::new(),
     };
     let expected_response_message = "remove: key1".to_owned();
 
@@ -267,12 +441,239 @@ async fn client_sends_remove_message() -> std::result::Result<(), Box<dyn std::e
     Ok(())
 }
 
+#[tokio::test]
+async fn client_sends_delete_prefix_message() -> std::result::Result<(), Box<dyn std::error::Error>>
+{
+    let (sender, receiver) = oneshot::channel::<()>();
+
+    let request = KvsCommandRequest {
+        cmd: Some(Cmd::DeletePrefix {
+            0: DeletePrefix {
+                prefix: b"tenant1/".to_vec(),
+            },
+        }),
+        keyspace: String::new(),
+    };
+    let expected_response_message = "delete prefix: tenant1/".to_owned();
+
+    let predicate = |msg| {
+        assert_eq!(msg, expected_response_message);
+        assert_ne!(msg, "");
+    };
+
+    let mut port = PORTS.lock().unwrap();
+    let available_port = get_available_port(&port).unwrap();
+    let mut addr = format!("http://127.0.0.1:{}", available_port);
+
+    while tonic::transport::Channel::from_shared(addr).is_err() {
+        port.insert(available_port, true);
+        let available_port = get_available_port(&port).unwrap();
+        addr = format!("http://127.0.0.1:{}", available_port);
+    }
+
+    port.insert(available_port, true);
+    drop(port);
+
+    future::join(
+        server(receiver, available_port),
+        client(sender, available_port, request, predicate),
+    )
+    .await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_sends_incr_message() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let (sender, receiver) = oneshot::channel::<()>();
+
+    let request = KvsCommandRequest {
+        cmd: Some(Cmd::Incr {
+            0: Incr {
+                key: b"counter".to_vec(),
+                delta: -3,
+            },
+        }),
+        keyspace: String::new(),
+    };
+    let expected_response_message = "incr: counter -3".to_owned();
+
+    let predicate = |msg| {
+        assert_eq!(msg, expected_response_message);
+        assert_ne!(msg, "");
+    };
+
+    let mut port = PORTS.lock().unwrap();
+    let available_port = get_available_port(&port).unwrap();
+    let mut addr = format!("http://127.0.0.1:{}", available_port);
+
+    while tonic::transport::Channel::from_shared(addr).is_err() {
+        port.insert(available_port, true);
+        let available_port = get_available_port(&port).unwrap();
+        addr = format!("http://127.0.0.1:{}", available_port);
+    }
+
+    port.insert(available_port, true);
+    drop(port);
+
+    future::join(
+        server(receiver, available_port),
+        client(sender, available_port, request, predicate),
+    )
+    .await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_sends_keyspace() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let (sender, receiver) = oneshot::channel::<()>();
+
+    let request = KvsCommandRequest {
+        cmd: Some(Cmd::Get {
+            0: Get {
+                key: b"key1".to_vec(),
+                snapshot_id: 0,
+            },
+        }),
+        keyspace: "orders".to_owned(),
+    };
+    let expected_response_message = "get: orders/key1".to_owned();
+
+    let predicate = |msg| {
+        assert_eq!(msg, expected_response_message);
+        assert_ne!(msg, "");
+    };
+
+    let mut port = PORTS.lock().unwrap();
+    let available_port = get_available_port(&port).unwrap();
+    let mut addr = format!("http://127.0.0.1:{}", available_port);
+
+    while tonic::transport::Channel::from_shared(addr).is_err() {
+        port.insert(available_port, true);
+        let available_port = get_available_port(&port).unwrap();
+        addr = format!("http://127.0.0.1:{}", available_port);
+    }
+
+    port.insert(available_port, true);
+    drop(port);
+
+    future::join(
+        server(receiver, available_port),
+        client(sender, available_port, request, predicate),
+    )
+    .await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_sends_batch_message() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let (sender, receiver) = oneshot::channel::<()>();
+
+    let request = KvsCommandRequest {
+        cmd: Some(Cmd::Batch {
+            0: Batch {
+                ops: vec![
+                    BatchOp {
+                        key: b"key1".to_vec(),
+                        value: b"value1".to_vec(),
+                        remove: false,
+                    },
+                    BatchOp {
+                        key: b"key2".to_vec(),
+                        value: Vec::new(),
+                        remove: true,
+                    },
+                ],
+            },
+        }),
+        keyspace: String::new(),
+    };
+    let expected_response_message = "batch: 2".to_owned();
+
+    let predicate = |msg| {
+        assert_eq!(msg, expected_response_message);
+        assert_ne!(msg, "");
+    };
+
+    let mut port = PORTS.lock().unwrap();
+    let available_port = get_available_port(&port).unwrap();
+    let mut addr = format!("http://127.0.0.1:{}", available_port);
+
+    while tonic::transport::Channel::from_shared(addr).is_err() {
+        port.insert(available_port, true);
+        let available_port = get_available_port(&port).unwrap();
+        addr = format!("http://127.0.0.1:{}", available_port);
+    }
+
+    port.insert(available_port, true);
+    drop(port);
+
+    future::join(
+        server(receiver, available_port),
+        client(sender, available_port, request, predicate),
+    )
+    .await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_sends_compare_and_swap_message(
+) -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let (sender, receiver) = oneshot::channel::<()>();
+
+    let request = KvsCommandRequest {
+        cmd: Some(Cmd::CompareAndSwap {
+            0: CompareAndSwap {
+                key: b"key1".to_vec(),
+                expected: b"value1".to_vec(),
+                expect_absent: false,
+                new: b"value2".to_vec(),
+                remove: false,
+            },
+        }),
+        keyspace: String::new(),
+    };
+    let expected_response_message = "compare and swap: key1".to_owned();
+
+    let predicate = |msg| {
+        assert_eq!(msg, expected_response_message);
+        assert_ne!(msg, "");
+    };
+
+    let mut port = PORTS.lock().unwrap();
+    let available_port = get_available_port(&port).unwrap();
+    let mut addr = format!("http://127.0.0.1:{}", available_port);
+
+    while tonic::transport::Channel::from_shared(addr).is_err() {
+        port.insert(available_port, true);
+        let available_port = get_available_port(&port).unwrap();
+        addr = format!("http://127.0.0.1:{}", available_port);
+    }
+
+    port.insert(available_port, true);
+    drop(port);
+
+    future::join(
+        server(receiver, available_port),
+        client(sender, available_port, request, predicate),
+    )
+    .await;
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn client_sends_empty_invalid_message() -> std::result::Result<(), Box<dyn std::error::Error>>
 {
     let (sender, receiver) = oneshot::channel::<()>();
 
-    let request = KvsCommandRequest { cmd: None };
+    let request = KvsCommandRequest {
+        cmd: None,
+        keyspace: String::new(),
+    };
     let expected_response_message = "error: unknown command".to_owned();
 
     let predicate = |msg| {
@@ -302,6 +703,132 @@ async fn client_sends_empty_invalid_message() -> std::result::Result<(), Box<dyn
     Ok(())
 }
 
+#[tokio::test]
+async fn client_requests_stats() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let (sender, receiver) = oneshot::channel::<()>();
+
+    let mut port = PORTS.lock().unwrap();
+    let available_port = get_available_port(&port).unwrap();
+    port.insert(available_port, true);
+    drop(port);
+
+    let client = async move {
+        let addr = format!("http://127.0.0.1:{}", available_port);
+        let channel = tonic::transport::Channel::from_shared(addr)
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let stats = KvsCommandClient::new(channel)
+            .stats(tonic::Request::new(StatsRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+
+        assert_eq!(stats.engine, "kvs");
+        assert_eq!(stats.keys, 2);
+        assert_eq!(stats.live_bytes + stats.dead_bytes, stats.disk_bytes);
+        assert_eq!(stats.segments[0].name, "1.log");
+
+        sender.send(()).unwrap();
+    };
+
+    future::join(server(receiver, available_port), client).await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_requests_backup() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let (sender, receiver) = oneshot::channel::<()>();
+
+    let mut port = PORTS.lock().unwrap();
+    let available_port = get_available_port(&port).unwrap();
+    port.insert(available_port, true);
+    drop(port);
+
+    let client = async move {
+        let addr = format!("http://127.0.0.1:{}", available_port);
+        let channel = tonic::transport::Channel::from_shared(addr)
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut client = KvsCommandClient::new(channel);
+
+        for (dest_dir, expected) in &[("", "checkpoints/checkpoint-1"), ("backup", "backup")] {
+            let backup = client
+                .backup(tonic::Request::new(BackupRequest {
+                    dest_dir: dest_dir.to_string(),
+                }))
+                .await
+                .unwrap()
+                .into_inner();
+            assert_eq!(backup.dir, *expected);
+        }
+
+        sender.send(()).unwrap();
+    };
+
+    future::join(server(receiver, available_port), client).await;
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn client_streams_dump_and_restore() -> std::result::Result<(), Box<dyn std::error::Error>> {
+    let (sender, receiver) = oneshot::channel::<()>();
+
+    let mut port = PORTS.lock().unwrap();
+    let available_port = get_available_port(&port).unwrap();
+    port.insert(available_port, true);
+    drop(port);
+
+    let client = async move {
+        let addr = format!("http://127.0.0.1:{}", available_port);
+        let channel = tonic::transport::Channel::from_shared(addr)
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut client = KvsCommandClient::new(channel);
+
+        let mut chunks = client
+            .dump(tonic::Request::new(DumpRequest {}))
+            .await
+            .unwrap()
+            .into_inner();
+        let mut dump = Vec::new();
+        while let Some(DumpChunk { data }) = chunks.message().await.unwrap() {
+            dump.extend_from_slice(&data);
+        }
+        assert_eq!(
+            String::from_utf8(dump.clone()).unwrap(),
+            "{\"key\":\"a\",\"value\":\"1\"}\n{\"key\":\"b\",\"value\":\"2\"}\n"
+        );
+
+        // the lines don't have to line up with the chunks
+        let chunks: Vec<DumpChunk> = dump
+            .chunks(5)
+            .map(|data| DumpChunk {
+                data: data.to_vec(),
+            })
+            .collect();
+        let restored = client
+            .restore(tonic::Request::new(futures::stream::iter(chunks)))
+            .await
+            .unwrap()
+            .into_inner();
+        assert_eq!(restored.records, 2);
+
+        sender.send(()).unwrap();
+    };
+
+    future::join(server(receiver, available_port), client).await;
+
+    Ok(())
+}
+
 // use std::future::Future;
 
 // macro_rules! impl_async_fn {
@@ -1,26 +1,74 @@
 #![cfg_attr(debug_assertions, allow(dead_code, unused_imports, warnings))]
 
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    export, import, Durability, Error, KvStore, KvsEngine, MemoryEngine, MemoryStorage,
+    MergeOperator, Options, RecoveryMode, Result, Retention, SledKvsEngine, WriteBatch,
+};
+use std::collections::BTreeMap;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
+/// runs a `check_*` function as a test of its own against a fresh store of every engine
+/// opened with `$options`, `Options::new()` if left out
+macro_rules! engine_tests {
+    ($name:ident, $check:ident) => {
+        engine_tests!($name, $check, Options::new());
+    };
+    ($name:ident, $check:ident, $options:expr) => {
+        mod $name {
+            use super::*;
+
+            #[test]
+            fn kv_store() -> Result<()> {
+                let temp_dir =
+                    TempDir::new().expect("unable to create temporary working directory");
+                $check(&KvStore::open_with(temp_dir.path(), &$options)?).map(|_| ())
+            }
+
+            #[test]
+            fn sled() -> Result<()> {
+                let temp_dir =
+                    TempDir::new().expect("unable to create temporary working directory");
+                $check(&SledKvsEngine::open_with(temp_dir.path(), &$options)?).map(|_| ())
+            }
+
+            #[test]
+            fn memory() -> Result<()> {
+                $check(&MemoryEngine::with_options(&$options)).map(|_| ())
+            }
+        }
+    };
+}
+
 // Should get previously stored value
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let mut store = KvStore::open(temp_dir.path())?;
 
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set_string("key1".to_owned(), "value1".to_owned())?;
+    store.set_string("key2".to_owned(), "value2".to_owned())?;
 
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        store.get_string("key2".to_owned())?,
+        Some("value2".to_owned())
+    );
 
     // Open from disk again and check persistent data
     drop(store);
     let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        store.get_string("key2".to_owned())?,
+        Some("value2".to_owned())
+    );
 
     Ok(())
 }
@@ -31,17 +79,29 @@ fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let mut store = KvStore::open(temp_dir.path())?;
 
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
-    store.set("key1".to_owned(), "value2".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    store.set_string("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    store.set_string("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value2".to_owned())
+    );
 
     // Open from disk again and check persistent data
     drop(store);
     let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
-    store.set("key1".to_owned(), "value3".to_owned())?;
-    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value2".to_owned())
+    );
+    store.set_string("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value3".to_owned())
+    );
 
     Ok(())
 }
@@ -52,13 +112,13 @@ fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let mut store = KvStore::open(temp_dir.path())?;
 
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+    store.set_string("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get_string("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
     let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get_string("key2".to_owned())?, None);
 
     Ok(())
 }
@@ -67,7 +127,7 @@ fn get_non_existent_value() -> Result<()> {
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let mut store = KvStore::open(temp_dir.path())?;
-    assert!(store.remove("key1".to_owned()).is_err());
+    assert!(store.remove_string("key1".to_owned()).is_err());
     Ok(())
 }
 
@@ -75,9 +135,9 @@ fn remove_non_existent_key() -> Result<()> {
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
     let mut store = KvStore::open(temp_dir.path())?;
-    store.set("key1".to_owned(), "value1".to_owned())?;
-    assert!(store.remove("key1".to_owned()).is_ok());
-    assert_eq!(store.get("key1".to_owned())?, None);
+    store.set_string("key1".to_owned(), "value1".to_owned())?;
+    assert!(store.remove_string("key1".to_owned()).is_ok());
+    assert_eq!(store.get_string("key1".to_owned())?, None);
     Ok(())
 }
 
@@ -104,7 +164,7 @@ fn compaction() -> Result<()> {
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             let value = format!("{}", iter);
-            store.set(key, value)?;
+            store.set_string(key, value)?;
         }
 
         let new_size = dir_size();
@@ -119,10 +179,1786 @@ fn compaction() -> Result<()> {
         let mut store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
-            assert_eq!(store.get(key)?, Some(format!("{}", iter)));
+            assert_eq!(store.get_string(key)?, Some(format!("{}", iter)));
         }
         return Ok(());
     }
 
     panic!("No compaction detected");
 }
+
+// Should read segments written in the legacy json format
+#[test]
+fn read_legacy_json_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(
+        temp_dir.path().join("2.log"),
+        r#"{"Insert":{"key":"key1","value":"value1"}}{"Insert":{"key":"key2","value":"value2"}}{"Remove":{"key":"key2"}}"#,
+    )?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get_string("key2".to_owned())?, None);
+
+    store.set_string("key2".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        store.get_string("key2".to_owned())?,
+        Some("value3".to_owned())
+    );
+
+    Ok(())
+}
+
+// Should refuse to open a store with a damaged record in the middle of a segment
+#[test]
+fn detect_corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_string("key1".to_owned(), "value1".to_owned())?;
+    store.set_string("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // flip the last byte of the first record's value
+    let log_path = temp_dir.path().join("2.log");
+    let mut bytes = std::fs::read(&log_path)?;
+    let first_record_end = 8 + 21 + "key1".len() + "value1".len();
+    bytes[first_record_end - 1] ^= 0xff;
+    std::fs::write(&log_path, bytes)?;
+
+    // the hint file lets `open` skip the segment, the damage shows up on read
+    let mut store = KvStore::open(temp_dir.path())?;
+    match store.get_string("key1".to_owned()) {
+        Err(Error::Corruption { segment, .. }) => assert_eq!(segment, "2.log"),
+        other => panic!("expected a corruption error, got {:?}", other),
+    }
+    drop(store);
+
+    std::fs::remove_file(temp_dir.path().join("2.hint"))?;
+    match KvStore::open(temp_dir.path()) {
+        Err(Error::Corruption { segment, offset }) => {
+            assert_eq!(segment, "2.log");
+            assert_eq!(offset, 8);
+        }
+        other => panic!("expected a corruption error, got {:?}", other.map(|_| ())),
+    }
+
+    // the damaged segment is moved aside in quarantine mode
+    let mut store = KvStore::open_with_recovery(temp_dir.path(), RecoveryMode::Quarantine)?;
+    assert_eq!(store.get_string("key1".to_owned())?, None);
+    assert_eq!(store.get_string("key2".to_owned())?, None);
+    assert_eq!(
+        store.recovery_report().quarantined,
+        vec!["2.log".to_owned()]
+    );
+    assert!(temp_dir.path().join("2.log.corrupt").exists());
+
+    Ok(())
+}
+
+// Should drop a half-written record at the end of the newest segment
+#[test]
+fn recover_torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_string("key1".to_owned(), "value1".to_owned())?;
+    store.set_string("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let log_path = temp_dir.path().join("2.log");
+    let full_len = std::fs::metadata(&log_path)?.len();
+    let torn_len = full_len - 3;
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(&log_path)?
+        .set_len(torn_len)?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(store.get_string("key2".to_owned())?, None);
+
+    let report = store.recovery_report();
+    assert_eq!(report.records_kept, 1);
+    assert_eq!(report.records_discarded, 1);
+    assert_eq!(
+        report.truncated_bytes,
+        torn_len - (8 + 21 + "key1".len() as u64 + "value1".len() as u64)
+    );
+
+    // the torn bytes are gone, the next open is clean
+    store.set_string("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.recovery_report().records_discarded, 0);
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(
+        store.get_string("key3".to_owned())?,
+        Some("value3".to_owned())
+    );
+
+    Ok(())
+}
+
+// Should drop the zeros and the garbled record a crash left at the end of the newest segment
+#[test]
+fn recover_padded_tail() -> Result<()> {
+    let first_record_len = 8 + 21 + "key1".len() as u64 + "value1".len() as u64;
+    for garbled in &[false, true] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set_string("key1".to_owned(), "value1".to_owned())?;
+        store.set_string("key2".to_owned(), "value2".to_owned())?;
+        drop(store);
+
+        let log_path = temp_dir.path().join("2.log");
+        let mut bytes = std::fs::read(&log_path)?;
+        let full_len = bytes.len() as u64;
+        if *garbled {
+            bytes[full_len as usize - 1] ^= 0xff;
+        }
+        bytes.extend_from_slice(&[0u8; 4096]);
+        std::fs::write(&log_path, &bytes)?;
+
+        let mut store = KvStore::open(temp_dir.path())?;
+        let report = store.recovery_report();
+        if *garbled {
+            // the garbled record and the zeros after it are a single torn write
+            assert_eq!(store.get_string("key2".to_owned())?, None);
+            assert_eq!(report.records_kept, 1);
+            assert_eq!(report.records_discarded, 1);
+            assert_eq!(report.truncated_bytes, full_len + 4096 - first_record_len);
+        } else {
+            assert_eq!(
+                store.get_string("key2".to_owned())?,
+                Some("value2".to_owned())
+            );
+            assert_eq!(report.records_kept, 2);
+            assert_eq!(report.records_discarded, 0);
+            assert_eq!(report.truncated_bytes, 4096);
+        }
+    }
+
+    Ok(())
+}
+
+// Should refuse a newest segment with valid records after a damaged one
+#[test]
+fn detect_corrupted_length() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_string("key1".to_owned(), "value1".to_owned())?;
+    store.set_string("key2".to_owned(), "value2".to_owned())?;
+    store.set_string("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    std::fs::remove_file(temp_dir.path().join("2.hint")).ok();
+
+    // the value length of the second record points past the end of the segment
+    let log_path = temp_dir.path().join("2.log");
+    let mut bytes = std::fs::read(&log_path)?;
+    let second_record = 8 + 21 + "key1".len() + "value1".len();
+    bytes[second_record + 17..second_record + 21].copy_from_slice(&u32::MAX.to_le_bytes());
+    std::fs::write(&log_path, bytes)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(Error::Corruption { segment, offset }) => {
+            assert_eq!(segment, "2.log");
+            assert_eq!(offset, second_record as u64);
+        }
+        other => panic!("expected a corruption error, got {:?}", other.map(|_| ())),
+    }
+
+    // the record after the damage is lost along with the segment in quarantine mode
+    let store = KvStore::open_with_recovery(temp_dir.path(), RecoveryMode::Quarantine)?;
+    assert_eq!(store.recovery_report().records_discarded, 3);
+
+    Ok(())
+}
+
+// Should open a store whose newest segment has a magic which was cut off or zeroed
+#[test]
+fn recover_torn_magic() -> Result<()> {
+    for header in &[&b"KVS"[..], &[0u8; 8][..], &[0u8; 64][..]] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set_string("key1".to_owned(), "value1".to_owned())?;
+        drop(store);
+        // the session log of the next open is the newest segment
+        drop(KvStore::open(temp_dir.path())?);
+        std::fs::write(temp_dir.path().join("3.log"), header)?;
+
+        let mut store = KvStore::open(temp_dir.path())?;
+        assert_eq!(
+            store.get_string("key1".to_owned())?,
+            Some("value1".to_owned())
+        );
+        assert_eq!(store.recovery_report().truncated_bytes, header.len() as u64);
+    }
+
+    Ok(())
+}
+
+// Should write a hint file for a closed segment and load from it
+#[test]
+fn open_from_hint_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_string("key1".to_owned(), "value1".to_owned())?;
+    store.set_string("key2".to_owned(), "value2".to_owned())?;
+    store.remove_string("key1".to_owned())?;
+    drop(store);
+
+    assert!(temp_dir.path().join("2.hint").exists());
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_string("key1".to_owned())?, None);
+    assert_eq!(
+        store.get_string("key2".to_owned())?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(store.recovery_report().records_kept, 3);
+
+    // a hint which doesn't match its segment is ignored
+    store.set_string("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+    std::fs::copy(
+        temp_dir.path().join("2.hint"),
+        temp_dir.path().join("3.hint"),
+    )?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_string("key2".to_owned())?,
+        Some("value2".to_owned())
+    );
+    assert_eq!(
+        store.get_string("key3".to_owned())?,
+        Some("value3".to_owned())
+    );
+
+    Ok(())
+}
+
+// Should compact on demand and keep serving the live values
+#[test]
+fn compact_now() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_compaction_threshold(usize::max_value())?;
+
+    for iter in 0..10 {
+        for key_id in 0..100 {
+            store.set_string(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    store.remove_string("key0".to_owned())?;
+    let log_len = std::fs::metadata(temp_dir.path().join("2.log"))?.len();
+
+    store.compact_now()?;
+
+    assert!(!temp_dir.path().join("2.log").exists());
+    assert!(std::fs::metadata(temp_dir.path().join("3.log"))?.len() < log_len / 5);
+    assert_eq!(store.get_string("key0".to_owned())?, None);
+    assert_eq!(store.get_string("key1".to_owned())?, Some("9".to_owned()));
+
+    store.set_string("key1".to_owned(), "10".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_string("key0".to_owned())?, None);
+    assert_eq!(store.get_string("key1".to_owned())?, Some("10".to_owned()));
+    assert_eq!(store.get_string("key99".to_owned())?, Some("9".to_owned()));
+
+    Ok(())
+}
+
+// Should serve reads and writes from many threads through cloned handles
+#[test]
+fn concurrent_access() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            std::thread::spawn(move || {
+                for key_id in 0..100 {
+                    let key = format!("key{}_{}", thread_id, key_id);
+                    store
+                        .set_string(key.clone(), format!("value{}", key_id))
+                        .unwrap();
+                    assert_eq!(
+                        store.get_string(key).unwrap(),
+                        Some(format!("value{}", key_id))
+                    );
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().expect("thread panicked");
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for thread_id in 0..8 {
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get_string(format!("key{}_{}", thread_id, key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Should not let a second writer open a directory which is in use, but let readers in
+#[test]
+fn directory_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_string("key1".to_owned(), "value1".to_owned())?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(Error::Locked { pid }) => assert_eq!(pid, Some(std::process::id())),
+        other => panic!("expected a lock error, got {:?}", other.map(|_| ())),
+    }
+
+    // read-only stores open next to the writer and see its data as of then
+    store.set_string("key2".to_owned(), "value2".to_owned())?;
+    let reader1 = KvStore::open_read_only(temp_dir.path())?;
+    let reader2 = KvStore::open_read_only(temp_dir.path())?;
+    for reader in &[&reader1, &reader2] {
+        assert_eq!(
+            reader.get_string("key1".to_owned())?,
+            Some("value1".to_owned())
+        );
+        assert_eq!(
+            reader.get_string("key2".to_owned())?,
+            Some("value2".to_owned())
+        );
+    }
+    match reader1.set_string("key3".to_owned(), "value3".to_owned()) {
+        Err(Error::ReadOnly) => {}
+        other => panic!("expected a read-only error, got {:?}", other),
+    }
+    store.set_string("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(reader1.get_string("key3".to_owned())?, None);
+    assert_eq!(
+        KvStore::open_read_only(temp_dir.path())?.get_string("key3".to_owned())?,
+        Some("value3".to_owned())
+    );
+
+    // and don't keep a writer out
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.get_string("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+
+    Ok(())
+}
+
+// Should keep acknowledged writes under every durability policy
+#[test]
+fn durability_policies() -> Result<()> {
+    assert_eq!("always".parse(), Ok(Durability::Always));
+    assert_eq!("os".parse(), Ok(Durability::OsBuffered));
+    assert_eq!("100ms".parse(), Ok(Durability::EveryNMillis(100)));
+    assert!("0ms".parse::<Durability>().is_err());
+    assert!("sometimes".parse::<Durability>().is_err());
+
+    for durability in vec![
+        Durability::Always,
+        Durability::EveryNMillis(10),
+        Durability::OsBuffered,
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = Options::new().durability(durability);
+
+        let store = KvStore::open_with(temp_dir.path(), &options)?;
+        for key_id in 0..100 {
+            store.set_string(format!("key{}", key_id), format!("value{}", key_id))?;
+        }
+        store.remove_string("key0".to_owned())?;
+        drop(store);
+
+        let store = KvStore::open_with(temp_dir.path(), &options)?;
+        assert_eq!(store.get_string("key0".to_owned())?, None);
+        for key_id in 1..100 {
+            assert_eq!(
+                store.get_string(format!("key{}", key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+    }
+
+    Ok(())
+}
+
+fn check_scans<E: KvsEngine>(engine: &E) -> Result<()> {
+    for user_id in (0..300).rev() {
+        engine.set_string(
+            format!("user/{:03}/settings", user_id),
+            format!("settings{}", user_id),
+        )?;
+        engine.set_string(
+            format!("user/{:03}/name", user_id),
+            format!("name{}", user_id),
+        )?;
+    }
+    engine.set_string("zone".to_owned(), "z".to_owned())?;
+
+    let keys = |pairs: Vec<(Vec<u8>, Vec<u8>)>| -> Vec<Vec<u8>> {
+        pairs.into_iter().map(|(key, _)| key).collect()
+    };
+
+    let user = engine
+        .scan_prefix(b"user/123/".to_vec())?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        user,
+        vec![
+            (b"user/123/name".to_vec(), b"name123".to_vec()),
+            (b"user/123/settings".to_vec(), b"settings123".to_vec()),
+        ]
+    );
+
+    let page = engine
+        .scan(b"user/010/".to_vec()..b"user/020/".to_vec(), Some(3))?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        keys(page),
+        vec![
+            b"user/010/name".to_vec(),
+            b"user/010/settings".to_vec(),
+            b"user/011/name".to_vec(),
+        ]
+    );
+
+    let all = engine.scan(.., None)?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(all.len(), 601);
+    assert!(all.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    assert_eq!(all.last(), Some(&(b"zone".to_vec(), b"z".to_vec())));
+
+    assert_eq!(
+        engine
+            .scan(b"zone".to_vec()..b"user".to_vec(), None)?
+            .count(),
+        0
+    );
+    assert_eq!(engine.scan_prefix(b"nobody".to_vec())?.count(), 0);
+
+    Ok(())
+}
+
+// Should list keys in order by range and by prefix
+engine_tests!(scans, check_scans);
+
+// Should keep a scan going through a compaction
+#[test]
+fn scan_during_compaction_kv_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    check_scans(&store)?;
+
+    // overwrite the names and compact in the middle of a scan
+    for user_id in 0..300 {
+        store.set_string(
+            format!("user/{:03}/name", user_id),
+            format!("new{}", user_id),
+        )?;
+    }
+    let mut scan = store.scan_prefix(b"user/".to_vec())?;
+    let first = scan.next().expect("first pair")?;
+    store.compact_now()?;
+    let rest = scan.collect::<Result<Vec<_>>>()?;
+
+    assert_eq!(first, (b"user/000/name".to_vec(), b"new0".to_vec()));
+    assert_eq!(rest.len(), 599);
+    assert!(rest
+        .iter()
+        .filter(|(key, _)| key.ends_with(b"name"))
+        .all(|(_, value)| value.starts_with(b"new")));
+
+    Ok(())
+}
+
+// Should store keys and values which are not UTF-8
+#[test]
+fn binary_keys_and_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let key = vec![0, 159, 146, 150, 255];
+    let value: Vec<u8> = (0..=255).collect();
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(key.clone(), value.clone())?;
+    store.set(b"text".to_vec(), b"plain".to_vec())?;
+    assert_eq!(store.get(key.clone())?, Some(value.clone()));
+    assert_eq!(
+        store.get_string("text".to_owned())?,
+        Some("plain".to_owned())
+    );
+    drop(store);
+
+    // through the hint file and through the segment itself
+    for _ in 0..2 {
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get(key.clone())?, Some(value.clone()));
+        drop(store);
+        for entry in WalkDir::new(temp_dir.path()) {
+            let entry = entry.expect("directory entry");
+            if entry.path().extension() == Some("hint".as_ref()) {
+                std::fs::remove_file(entry.path())?;
+            }
+        }
+    }
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"raw".to_vec(), vec![0xff, 0xfe])?;
+    match store.get_string("raw".to_owned()) {
+        Err(Error::NotUtf8) => {}
+        other => panic!("expected a not UTF-8 error, got {:?}", other),
+    }
+
+    Ok(())
+}
+
+fn check_ttl<E: KvsEngine>(engine: &E) -> Result<()> {
+    let ttl = std::time::Duration::from_millis(200);
+    engine.set_with_ttl(b"session1".to_vec(), b"alice".to_vec(), ttl)?;
+    engine.set_with_ttl(b"session2".to_vec(), b"bob".to_vec(), ttl)?;
+    engine.set_with_ttl(b"session3".to_vec(), b"carol".to_vec(), ttl)?;
+    engine.set(b"session3".to_vec(), b"carol".to_vec())?;
+    engine.set_string("user".to_owned(), "dave".to_owned())?;
+
+    assert_eq!(engine.get(b"session1".to_vec())?, Some(b"alice".to_vec()));
+    assert_eq!(engine.scan_prefix(b"session".to_vec())?.count(), 3);
+
+    std::thread::sleep(ttl + std::time::Duration::from_millis(50));
+    assert!(engine.get(b"session1".to_vec())?.is_none());
+    assert!(engine.remove(b"session2".to_vec()).is_err());
+    let live = engine.scan(.., None)?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        live,
+        vec![
+            (b"session3".to_vec(), b"carol".to_vec()),
+            (b"user".to_vec(), b"dave".to_vec()),
+        ]
+    );
+
+    Ok(())
+}
+
+// Should hide keys once their ttl runs out
+engine_tests!(
+    ttl,
+    check_ttl,
+    Options::new().sweep_interval(std::time::Duration::from_millis(20))
+);
+
+// Should keep expired keys gone through a reopen and a compaction
+#[test]
+fn expiry_after_reopen_kv_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options::new().sweep_interval(std::time::Duration::from_millis(20));
+
+    let store = KvStore::open_with(temp_dir.path(), &options)?;
+    check_ttl(&store)?;
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), &options)?;
+    assert_eq!(store.get(b"session1".to_vec())?, None);
+    assert_eq!(store.get(b"session3".to_vec())?, Some(b"carol".to_vec()));
+    store.set_with_ttl(
+        b"session4".to_vec(),
+        b"erin".to_vec(),
+        std::time::Duration::from_secs(3600),
+    )?;
+    store.compact_now()?;
+    drop(store);
+
+    // expired records are gone from the segments, live ones keep their expiry
+    for entry in WalkDir::new(temp_dir.path()) {
+        let entry = entry.expect("directory entry");
+        if entry.path().extension() == Some("hint".as_ref()) {
+            std::fs::remove_file(entry.path())?;
+        }
+        if entry.path().extension() == Some("log".as_ref()) {
+            let content = std::fs::read(entry.path())?;
+            assert!(!content.windows(5).any(|window| window == b"alice"));
+        }
+    }
+    let store = KvStore::open_with(temp_dir.path(), &options)?;
+    assert_eq!(store.get(b"session1".to_vec())?, None);
+    assert_eq!(store.get(b"session4".to_vec())?, Some(b"erin".to_vec()));
+    assert_eq!(store.scan(.., None)?.count(), 3);
+
+    Ok(())
+}
+
+fn check_max_ttl<E: KvsEngine>(engine: &E) -> Result<()> {
+    let forever = std::time::Duration::MAX;
+    engine.set_with_ttl(b"forever".to_vec(), b"1".to_vec(), forever)?;
+    let mut batch = WriteBatch::new();
+    batch.put_with_ttl(b"batched".to_vec(), b"2".to_vec(), forever);
+    engine.write_batch(batch)?;
+
+    for key in &[b"forever".to_vec(), b"batched".to_vec()] {
+        assert!(engine.get(key.clone())?.is_some());
+        let ttl = engine.ttl(key.clone())?.expect("the key expires");
+        assert!(ttl > std::time::Duration::from_secs(100 * 365 * 24 * 3600));
+    }
+
+    Ok(())
+}
+
+// Should keep a key with a ttl too long to count instead of overflowing its expiry
+engine_tests!(max_ttl, check_max_ttl);
+
+fn check_batch<E: KvsEngine>(engine: &E) -> Result<()> {
+    engine.set_string("alice".to_owned(), "10".to_owned())?;
+    engine.set_with_ttl(
+        b"bob".to_vec(),
+        b"0".to_vec(),
+        std::time::Duration::from_millis(100),
+    )?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .put(b"bob".to_vec(), b"10".to_vec())
+        .delete(b"alice".to_vec())
+        .delete(b"carol".to_vec())
+        .put(b"dave".to_vec(), b"1".to_vec())
+        .put(b"dave".to_vec(), b"2".to_vec());
+    assert_eq!(batch.len(), 5);
+    engine.write_batch(batch)?;
+    engine.write_batch(WriteBatch::new())?;
+
+    // the batch put drops the ttl of the key
+    std::thread::sleep(std::time::Duration::from_millis(150));
+    let pairs = engine.scan(.., None)?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            (b"bob".to_vec(), b"10".to_vec()),
+            (b"dave".to_vec(), b"2".to_vec()),
+        ]
+    );
+
+    Ok(())
+}
+
+// Should apply a write batch as a whole
+engine_tests!(write_batch, check_batch);
+
+// Should drop a write batch which wasn't committed before a crash
+#[test]
+fn torn_write_batch_kv_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+
+    let store = KvStore::open(temp_dir.path())?;
+    check_batch(&store)?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"alice".to_vec())?, None);
+    assert_eq!(store.get(b"dave".to_vec())?, Some(b"2".to_vec()));
+
+    let mut batch = WriteBatch::new();
+    batch
+        .put(b"erin".to_vec(), b"5".to_vec())
+        .delete(b"dave".to_vec());
+    store.write_batch(batch)?;
+    drop(store);
+
+    // a crash right before the commit record of the batch hit the disk
+    std::fs::remove_file(temp_dir.path().join("3.hint"))?;
+    let segment = std::fs::OpenOptions::new()
+        .write(true)
+        .open(temp_dir.path().join("3.log"))?;
+    let segment_len = segment.metadata()?.len();
+    segment.set_len(segment_len - 21)?;
+    drop(segment);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.recovery_report().records_discarded, 3);
+    assert_eq!(store.get(b"erin".to_vec())?, None);
+    assert_eq!(store.get(b"dave".to_vec())?, Some(b"2".to_vec()));
+    store.set_string("frank".to_owned(), "1".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"erin".to_vec())?, None);
+    assert_eq!(store.get_string("frank".to_owned())?, Some("1".to_owned()));
+
+    Ok(())
+}
+
+fn check_conditional_writes<E: KvsEngine>(engine: &E) -> Result<()> {
+    engine.set_if_absent(b"counter".to_vec(), b"1".to_vec())?;
+    match engine.set_if_absent(b"counter".to_vec(), b"5".to_vec()) {
+        Err(Error::ConditionFailed { current }) => assert_eq!(current, Some(b"1".to_vec())),
+        _ => panic!("set_if_absent overwrote an existing key"),
+    }
+
+    engine.compare_and_swap(
+        b"counter".to_vec(),
+        Some(b"1".to_vec()),
+        Some(b"2".to_vec()),
+    )?;
+    match engine.compare_and_swap(
+        b"counter".to_vec(),
+        Some(b"1".to_vec()),
+        Some(b"3".to_vec()),
+    ) {
+        Err(Error::ConditionFailed { current }) => assert_eq!(current, Some(b"2".to_vec())),
+        _ => panic!("compare_and_swap ignored a stale expected value"),
+    }
+    match engine.remove_if_equals(b"missing".to_vec(), b"1".to_vec()) {
+        Err(Error::ConditionFailed { current }) => assert_eq!(current, None),
+        _ => panic!("remove_if_equals removed a missing key"),
+    }
+    engine.remove_if_equals(b"counter".to_vec(), b"2".to_vec())?;
+    assert!(engine.get(b"counter".to_vec())?.is_none());
+
+    // every increment of a contended counter lands exactly once
+    engine.set(b"counter".to_vec(), b"0".to_vec())?;
+    let threads: Vec<_> = (0..4)
+        .map(|_| {
+            let engine = engine.clone();
+            std::thread::spawn(move || {
+                for _ in 0..25 {
+                    let mut current = engine.get(b"counter".to_vec()).unwrap();
+                    loop {
+                        let counter = String::from_utf8(current.clone().unwrap())
+                            .unwrap()
+                            .parse::<u32>()
+                            .unwrap();
+                        let new = (counter + 1).to_string().into_bytes();
+                        match engine.compare_and_swap(b"counter".to_vec(), current, Some(new)) {
+                            Ok(()) => break,
+                            Err(Error::ConditionFailed { current: actual }) => current = actual,
+                            Err(e) => panic!("{:?}", e),
+                        }
+                    }
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().expect("thread panicked");
+    }
+    assert_eq!(engine.get(b"counter".to_vec())?, Some(b"100".to_vec()));
+
+    Ok(())
+}
+
+// Should only write when the condition of a conditional write holds
+engine_tests!(conditional_writes, check_conditional_writes);
+
+fn check_snapshot<E: KvsEngine>(engine: &E) -> Result<E> {
+    engine.set_string("alice".to_owned(), "10".to_owned())?;
+    engine.set_string("bob".to_owned(), "20".to_owned())?;
+
+    let snapshot = engine.snapshot()?;
+    engine.set_string("alice".to_owned(), "15".to_owned())?;
+    engine.remove_string("bob".to_owned())?;
+    engine.set_string("carol".to_owned(), "30".to_owned())?;
+
+    assert_eq!(
+        snapshot.get_string("alice".to_owned())?,
+        Some("10".to_owned())
+    );
+    assert_eq!(
+        snapshot.get_string("bob".to_owned())?,
+        Some("20".to_owned())
+    );
+    assert_eq!(snapshot.scan(.., None)?.count(), 2);
+    assert!(snapshot
+        .set_string("dave".to_owned(), "40".to_owned())
+        .is_err());
+    match snapshot.remove_string("alice".to_owned()) {
+        Err(Error::ReadOnly) => {}
+        _ => panic!("a snapshot took a write"),
+    }
+    assert_eq!(
+        engine.get_string("alice".to_owned())?,
+        Some("15".to_owned())
+    );
+
+    Ok(snapshot)
+}
+
+// Should read the data as of the moment a snapshot was taken
+engine_tests!(snapshot, check_snapshot);
+
+// Should keep the segments and the versions a snapshot reads through a compaction
+#[test]
+fn snapshot_during_compaction_kv_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), &Options::new().garbage_ratio(0.0))?;
+
+    let snapshot = check_snapshot(&store)?;
+
+    // the segments the snapshot reads from and the versions it reads outlive a compaction
+    store.compact_now()?;
+    assert!(temp_dir.path().join("2.log").exists());
+    let as_of = vec![
+        (b"alice".to_vec(), b"10".to_vec()),
+        (b"bob".to_vec(), b"20".to_vec()),
+    ];
+    assert_eq!(snapshot.scan(.., None)?.collect::<Result<Vec<_>>>()?, as_of);
+    assert_eq!(
+        snapshot
+            .scan_prefix(b"bo".to_vec())?
+            .collect::<Result<Vec<_>>>()?,
+        &as_of[1..]
+    );
+    assert_eq!(snapshot.get_versions(b"alice".to_vec(), 10)?.len(), 1);
+    assert_eq!(store.get_versions(b"alice".to_vec(), 10)?.len(), 2);
+    assert_eq!(snapshot.stats()?.keys, 2);
+    store.set_string("bob".to_owned(), "25".to_owned())?;
+    store.compact_now()?;
+    assert_eq!(
+        snapshot.get_string("bob".to_owned())?,
+        Some("20".to_owned())
+    );
+
+    // the next compaction goes by the retention again
+    drop(snapshot);
+    assert!(!temp_dir.path().join("2.log").exists());
+    store.compact_now()?;
+    assert_eq!(store.get_versions(b"alice".to_vec(), 10)?.len(), 1);
+    assert_eq!(store.get_versions(b"bob".to_vec(), 10)?.len(), 1);
+    assert_eq!(store.get_string("alice".to_owned())?, Some("15".to_owned()));
+    assert_eq!(store.get_string("bob".to_owned())?, Some("25".to_owned()));
+
+    Ok(())
+}
+
+// Should keep the versions a snapshot reads with sled until it's dropped
+#[test]
+fn snapshot_versions_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open_with(temp_dir.path(), &Options::new())?;
+    let snapshot = check_snapshot(&engine)?;
+
+    // the versions the snapshot reads outlive the retention until it's dropped
+    let as_of = vec![
+        (b"alice".to_vec(), b"10".to_vec()),
+        (b"bob".to_vec(), b"20".to_vec()),
+    ];
+    assert_eq!(
+        snapshot
+            .scan(b"b".to_vec().., None)?
+            .collect::<Result<Vec<_>>>()?,
+        &as_of[1..]
+    );
+    assert_eq!(
+        snapshot
+            .scan_prefix(b"bo".to_vec())?
+            .collect::<Result<Vec<_>>>()?,
+        &as_of[1..]
+    );
+    assert_eq!(
+        snapshot.scan(.., Some(1))?.collect::<Result<Vec<_>>>()?,
+        &as_of[..1]
+    );
+    assert_eq!(snapshot.get_versions(b"alice".to_vec(), 10)?.len(), 1);
+    assert_eq!(engine.get_versions(b"alice".to_vec(), 10)?.len(), 2);
+    engine.set_string("bob".to_owned(), "25".to_owned())?;
+    assert_eq!(
+        snapshot.get_string("bob".to_owned())?,
+        Some("20".to_owned())
+    );
+
+    drop(snapshot);
+    assert_eq!(engine.get_versions(b"alice".to_vec(), 10)?.len(), 1);
+    assert_eq!(engine.get_versions(b"bob".to_vec(), 10)?.len(), 1);
+    assert_eq!(engine.get_string("bob".to_owned())?, Some("25".to_owned()));
+
+    Ok(())
+}
+
+fn check_versions<E: KvsEngine>(engine: &E) -> Result<()> {
+    engine.set(b"a".to_vec(), b"1".to_vec())?;
+    engine.set(b"a".to_vec(), b"2".to_vec())?;
+    engine.remove(b"a".to_vec())?;
+    engine.set(b"a".to_vec(), b"3".to_vec())?;
+
+    let versions = engine.get_versions(b"a".to_vec(), 3)?;
+    let values: Vec<_> = versions.iter().map(|(_, value)| value.clone()).collect();
+    assert_eq!(values, vec![Some(b"3".to_vec()), None, Some(b"2".to_vec())]);
+    assert!(versions[0].0 > versions[1].0 && versions[1].0 > versions[2].0);
+
+    assert_eq!(
+        engine.get_at(b"a".to_vec(), versions[2].0)?,
+        Some(b"2".to_vec())
+    );
+    assert_eq!(engine.get_at(b"a".to_vec(), versions[1].0)?, None);
+    assert_eq!(
+        engine.get_at(b"a".to_vec(), versions[0].0)?,
+        Some(b"3".to_vec())
+    );
+    assert_eq!(
+        engine.get_at(b"a".to_vec(), u64::max_value())?,
+        Some(b"3".to_vec())
+    );
+    assert!(engine.get_versions(b"b".to_vec(), 3)?.is_empty());
+
+    Ok(())
+}
+
+// Should keep the versions of a key the retention asks for
+engine_tests!(
+    versions,
+    check_versions,
+    Options::new().retention(Retention::LastVersions(3))
+);
+
+// Should drop the versions past the retention in a compaction
+#[test]
+fn versions_after_compaction_kv_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options::new().retention(Retention::LastVersions(3));
+    let store = KvStore::open_with(temp_dir.path(), &options)?;
+    check_versions(&store)?;
+
+    // a compaction drops the oldest version, a removal alone leaves nothing behind
+    store.set(b"b".to_vec(), b"1".to_vec())?;
+    store.remove(b"b".to_vec())?;
+    store.compact_now()?;
+    assert_eq!(store.get_versions(b"a".to_vec(), 10)?.len(), 3);
+    assert_eq!(store.get_versions(b"b".to_vec(), 10)?.len(), 2);
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), &options)?;
+    let values: Vec<_> = store
+        .get_versions(b"a".to_vec(), 10)?
+        .into_iter()
+        .map(|(_, value)| value)
+        .collect();
+    assert_eq!(values, vec![Some(b"3".to_vec()), None, Some(b"2".to_vec())]);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    store.compact_now()?;
+    assert_eq!(store.get_versions(b"a".to_vec(), 10)?.len(), 1);
+    assert!(store.get_versions(b"b".to_vec(), 10)?.is_empty());
+
+    Ok(())
+}
+
+// Should drop the versions past the retention right away with sled and in memory
+#[test]
+fn versions_pruned_on_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options::new().retention(Retention::LastVersions(3));
+
+    let engine = SledKvsEngine::open_with(temp_dir.path(), &options)?;
+    check_versions(&engine)?;
+    assert_eq!(engine.get_versions(b"a".to_vec(), 10)?.len(), 3);
+
+    let engine = MemoryEngine::with_options(&options);
+    check_versions(&engine)?;
+    assert_eq!(engine.get_versions(b"a".to_vec(), 10)?.len(), 3);
+
+    Ok(())
+}
+
+fn check_keyspaces<E: KvsEngine>(engine: &E) -> Result<()> {
+    let orders = engine.open_tree("orders")?;
+    engine.set(b"a".to_vec(), b"1".to_vec())?;
+    orders.set(b"a".to_vec(), b"2".to_vec())?;
+    orders.set(b"b".to_vec(), b"3".to_vec())?;
+
+    assert_eq!(engine.get(b"a".to_vec())?, Some(b"1".to_vec()));
+    assert_eq!(orders.get(b"a".to_vec())?, Some(b"2".to_vec()));
+    assert_eq!(engine.scan(.., None)?.count(), 1);
+    assert_eq!(orders.scan(.., None)?.count(), 2);
+    for name in &["", "__kvs_expiries"] {
+        match engine.open_tree(name) {
+            Err(Error::InvalidKeyspace) => {}
+            _ => panic!("opened a reserved keyspace"),
+        }
+    }
+
+    // the keys of a dropped keyspace are gone at once
+    engine.drop_tree("orders")?;
+    match orders.get(b"a".to_vec()) {
+        Ok(None) | Err(Error::KeyspaceDropped) => {}
+        other => panic!("read a dropped keyspace: {:?}", other),
+    }
+    match orders.set(b"c".to_vec(), b"4".to_vec()) {
+        Err(Error::KeyspaceDropped) => {}
+        _ => panic!("wrote to a dropped keyspace"),
+    }
+    assert_eq!(engine.get(b"a".to_vec())?, Some(b"1".to_vec()));
+
+    let orders = engine.open_tree("orders")?;
+    assert_eq!(orders.scan(.., None)?.count(), 0);
+    orders.set(b"c".to_vec(), b"4".to_vec())?;
+    assert_eq!(orders.get(b"c".to_vec())?, Some(b"4".to_vec()));
+
+    Ok(())
+}
+
+// Should keep the keys of every keyspace apart
+engine_tests!(keyspaces, check_keyspaces);
+
+// Should keep keyspaces and their drops through a reopen and a compaction
+#[test]
+fn keyspaces_after_reopen_kv_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    check_keyspaces(&store)?;
+    store
+        .open_tree("users")?
+        .set(b"a".to_vec(), b"5".to_vec())?;
+    drop(store);
+
+    // keyspaces and drops outlive a reopen and a compaction
+    for _ in 0..2 {
+        let store = KvStore::open(temp_dir.path())?;
+        let (orders, users) = (store.open_tree("orders")?, store.open_tree("users")?);
+        assert_eq!(store.get(b"a".to_vec())?, Some(b"1".to_vec()));
+        assert_eq!(orders.get(b"a".to_vec())?, None);
+        assert_eq!(orders.get(b"c".to_vec())?, Some(b"4".to_vec()));
+        assert_eq!(users.get(b"a".to_vec())?, Some(b"5".to_vec()));
+        store.compact_now()?;
+    }
+
+    Ok(())
+}
+
+fn keys<E: KvsEngine>(engine: &E) -> Result<Vec<Vec<u8>>> {
+    engine
+        .scan(.., None)?
+        .map(|pair| pair.map(|(key, _)| key))
+        .collect()
+}
+
+fn check_delete_range<E: KvsEngine>(engine: &E) -> Result<()> {
+    for key in &["a", "b1", "b2", "b3", "c", "d", "e\u{7f}", "f"] {
+        engine.set(key.as_bytes().to_vec(), b"1".to_vec())?;
+    }
+    engine.set(vec![b'e', 0xff], b"1".to_vec())?;
+
+    engine.delete_prefix(b"b".to_vec())?;
+    engine.delete_range(b"c".to_vec(), b"d".to_vec())?;
+    engine.delete_prefix(vec![b'e', 0xff])?;
+    // an empty range removes nothing
+    engine.delete_range(b"z".to_vec(), b"a".to_vec())?;
+    assert_eq!(
+        keys(engine)?,
+        vec![
+            b"a".to_vec(),
+            b"d".to_vec(),
+            b"e\x7f".to_vec(),
+            b"f".to_vec()
+        ]
+    );
+
+    // the keys written after a range delete stay
+    engine.set(b"b2".to_vec(), b"2".to_vec())?;
+    engine.delete_range(b"e".to_vec(), Vec::new())?;
+    assert_eq!(
+        keys(engine)?,
+        vec![b"a".to_vec(), b"b2".to_vec(), b"d".to_vec()]
+    );
+    assert_eq!(engine.get(b"b2".to_vec())?, Some(b"2".to_vec()));
+
+    Ok(())
+}
+
+// Should remove every key of a range at once
+engine_tests!(delete_range, check_delete_range);
+
+// Should keep the range tombstones through a reopen and a compaction
+#[test]
+fn range_tombstones_after_reopen_kv_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options::new().retention(Retention::LastVersions(2));
+    let store = KvStore::open_with(temp_dir.path(), &options)?;
+    check_delete_range(&store)?;
+    drop(store);
+
+    // the tombstones outlive a reopen and a compaction
+    for _ in 0..2 {
+        let store = KvStore::open_with(temp_dir.path(), &options)?;
+        assert_eq!(
+            keys(&store)?,
+            vec![b"a".to_vec(), b"b2".to_vec(), b"d".to_vec()]
+        );
+        assert_eq!(store.get(b"b2".to_vec())?, Some(b"2".to_vec()));
+
+        let values: Vec<_> = store
+            .get_versions(b"b1".to_vec(), 10)?
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect();
+        assert_eq!(values, vec![None, Some(b"1".to_vec())]);
+        store.compact_now()?;
+    }
+
+    Ok(())
+}
+
+fn check_stats<E: KvsEngine>(engine: &E) -> Result<()> {
+    let users = engine.open_tree("users")?;
+    for key in &["a", "b", "c"] {
+        engine.set(key.as_bytes().to_vec(), b"value".to_vec())?;
+    }
+    engine.set(b"a".to_vec(), b"new value".to_vec())?;
+    engine.remove(b"b".to_vec())?;
+    users.set(b"alice".to_vec(), b"1".to_vec())?;
+    users.set(b"bob".to_vec(), b"2".to_vec())?;
+
+    // the figures are of the whole store through any handle
+    for handle in &[engine, &users] {
+        let stats = handle.stats()?;
+        assert_eq!(stats.keys, 4);
+        assert_eq!(stats.keyspaces, 1);
+        assert!(stats.disk_bytes > 0);
+    }
+
+    Ok(())
+}
+
+// Should count the keys of every keyspace
+engine_tests!(stats, check_stats);
+
+// Should count the live and dead bytes of every segment
+#[test]
+fn segment_stats_kv_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), &Options::new().garbage_ratio(0.0))?;
+    check_stats(&store)?;
+
+    let stats = store.stats()?;
+    assert_eq!(stats.engine, "kvs");
+    assert!(stats.dead_bytes > 0);
+    assert_eq!(stats.disk_bytes, stats.live_bytes + stats.dead_bytes);
+    assert_eq!(
+        stats
+            .segments
+            .iter()
+            .map(|segment| segment.bytes)
+            .sum::<u64>(),
+        stats.disk_bytes
+    );
+    assert_eq!(stats.compactions, 0);
+    assert_eq!(stats.last_compaction_millis, None);
+
+    store.compact_now()?;
+    let compacted = store.stats()?;
+    assert_eq!(compacted.keys, 4);
+    assert_eq!(compacted.dead_bytes, 0);
+    assert!(compacted.disk_bytes < stats.disk_bytes);
+    assert_eq!(compacted.compactions, 1);
+    assert!(compacted.last_compaction_millis.is_some());
+
+    Ok(())
+}
+
+// Should count the bytes the keys take in memory
+#[test]
+fn memory_stats() -> Result<()> {
+    let engine = MemoryEngine::new();
+    check_stats(&engine)?;
+
+    let stats = engine.stats()?;
+    assert_eq!(stats.engine, "memory");
+    assert_eq!(stats.live_bytes, stats.disk_bytes);
+    assert!(stats.segments.is_empty());
+
+    Ok(())
+}
+
+fn check_checkpoint<E, F>(engine: &E, open: F) -> Result<()>
+where
+    E: KvsEngine,
+    F: Fn(&std::path::Path) -> Result<E>,
+{
+    let backups = TempDir::new().expect("unable to create temporary working directory");
+    let users = engine.open_tree("users")?;
+    for i in 0..200 {
+        engine.set(format!("key{}", i).into_bytes(), vec![b'v'; 64])?;
+    }
+    engine.remove(b"key0".to_vec())?;
+    users.set(b"alice".to_vec(), b"1".to_vec())?;
+
+    let dest = backups.path().join("checkpoint");
+    engine.checkpoint(&dest)?;
+    // a checkpoint never replaces another one
+    assert!(engine.checkpoint(&dest).is_err());
+
+    // the writes after the checkpoint stay out of it
+    engine.set(b"key1".to_vec(), b"new".to_vec())?;
+    engine.set(b"later".to_vec(), b"value".to_vec())?;
+    users.remove(b"alice".to_vec())?;
+
+    let restored = open(&dest)?;
+    assert!(restored.get(b"key0".to_vec())?.is_none());
+    assert_eq!(restored.get(b"key1".to_vec())?, Some(vec![b'v'; 64]));
+    assert_eq!(restored.get(b"key199".to_vec())?, Some(vec![b'v'; 64]));
+    assert!(restored.get(b"later".to_vec())?.is_none());
+    assert_eq!(
+        restored.open_tree("users")?.get(b"alice".to_vec())?,
+        Some(b"1".to_vec())
+    );
+    assert_eq!(engine.get(b"key1".to_vec())?, Some(b"new".to_vec()));
+
+    Ok(())
+}
+
+// Should restore the store as of the checkpoint by opening its directory
+#[test]
+fn checkpoint_kv_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options::new().max_segment_size(4096);
+    let store = KvStore::open_with(temp_dir.path(), &options)?;
+    check_checkpoint(&store, |dest| KvStore::open_with(dest, &options))?;
+
+    // the linked segments outlive a compaction of the live store
+    store.compact_now()?;
+    let backups = TempDir::new().expect("unable to create temporary working directory");
+    store.checkpoint(&backups.path().join("compacted"))?;
+    drop(store);
+    let restored = KvStore::open(&backups.path().join("compacted"))?;
+    assert_eq!(restored.get(b"key1".to_vec())?, Some(b"new".to_vec()));
+    assert_eq!(restored.get(b"key2".to_vec())?, Some(vec![b'v'; 64]));
+
+    Ok(())
+}
+
+// Should restore the store as of the checkpoint with sled
+#[test]
+fn checkpoint_sled() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open_with(temp_dir.path(), &Options::new())?;
+    check_checkpoint(&engine, |dest| {
+        SledKvsEngine::open_with(dest, &Options::new())
+    })
+}
+
+// Should refuse a checkpoint in memory
+#[test]
+fn checkpoint_memory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    match MemoryEngine::new().checkpoint(&temp_dir.path().join("checkpoint")) {
+        Err(Error::Unsupported) => Ok(()),
+        result => panic!("unexpected {:?}", result),
+    }
+}
+
+fn fill_for_dump<E: KvsEngine>(engine: &E) -> Result<()> {
+    for i in 0..3000 {
+        engine.set(format!("key{:04}", i).into_bytes(), b"value".to_vec())?;
+    }
+    engine.set(vec![0, 255], vec![254, 1])?;
+    engine.set_with_ttl(
+        b"session".to_vec(),
+        b"token".to_vec(),
+        std::time::Duration::from_secs(3600),
+    )?;
+    engine
+        .open_tree("users")?
+        .set(b"alice".to_vec(), b"1".to_vec())?;
+
+    Ok(())
+}
+
+fn check_restored<E: KvsEngine>(engine: &E) -> Result<()> {
+    assert_eq!(engine.scan(.., None)?.count(), 3002);
+    assert_eq!(engine.get(b"key2999".to_vec())?, Some(b"value".to_vec()));
+    assert_eq!(engine.get(vec![0, 255])?, Some(vec![254, 1]));
+    let ttl = engine.ttl(b"session".to_vec())?.expect("the ttl is kept");
+    assert!(
+        ttl <= std::time::Duration::from_secs(3600) && ttl > std::time::Duration::from_secs(3000)
+    );
+    assert_eq!(engine.ttl(b"key0000".to_vec())?, None);
+    assert_eq!(engine.tree_names()?, vec!["users".to_owned()]);
+    assert_eq!(
+        engine.open_tree("users")?.get(b"alice".to_vec())?,
+        Some(b"1".to_vec())
+    );
+
+    Ok(())
+}
+
+// Should move every keyspace with the ttls from one engine to another through a dump
+#[test]
+fn export_import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(&temp_dir.path().join("kvs"))?;
+    fill_for_dump(&store)?;
+
+    let mut dump = Vec::new();
+    assert_eq!(export(&store, &mut dump)?, 3003);
+    let lines: Vec<&str> = std::str::from_utf8(&dump)
+        .expect("the dump is text")
+        .lines()
+        .collect();
+    assert_eq!(lines.len(), 3003);
+    assert!(lines
+        .iter()
+        .any(|line| line.starts_with(r#"{"key_hex":"00ff","value_hex":"fe01","version":"#)));
+    assert!(lines
+        .iter()
+        .any(|line| line.starts_with(r#"{"key":"session","value":"token","ttl_millis":"#)));
+    assert!(lines
+        .iter()
+        .any(|line| line.starts_with(r#"{"keyspace":"users","key":"alice","value":"1","#)));
+
+    let restored = KvStore::open(&temp_dir.path().join("restored"))?;
+    assert_eq!(import(&restored, dump.as_slice())?, 3003);
+    check_restored(&restored)?;
+
+    let sled = SledKvsEngine::open_with(&temp_dir.path().join("sled"), &Options::new())?;
+    assert_eq!(import(&sled, dump.as_slice())?, 3003);
+    check_restored(&sled)?;
+
+    let memory = MemoryEngine::new();
+    assert_eq!(import(&memory, dump.as_slice())?, 3003);
+    check_restored(&memory)?;
+
+    Ok(())
+}
+
+// Should stop an import at a bad record and keep the batches before it
+#[test]
+fn import_bad_record() -> Result<()> {
+    let engine = MemoryEngine::new();
+    let dump = concat!(
+        r#"{"key":"a","value":"1"}"#,
+        "\n",
+        r#"{"key":"b","value_hex":"zz"}"#,
+        "\n",
+        r#"{"key":"c","value":"3"}"#,
+        "\n",
+    );
+
+    match import(&engine, dump.as_bytes()) {
+        Err(Error::Serialization(_)) => {}
+        result => panic!("unexpected {:?}", result),
+    }
+    assert!(engine.get(b"c".to_vec())?.is_none());
+    assert!(import(&engine, &b"{\"key\":\"a\"}"[..]).is_err());
+
+    Ok(())
+}
+
+fn check_merge<E: KvsEngine>(engine: &E) -> Result<()> {
+    engine.merge(b"counter".to_vec(), "add", b"5".to_vec())?;
+    assert_eq!(
+        engine.merge(b"counter".to_vec(), "add", b"-2".to_vec())?,
+        b"3".to_vec()
+    );
+    engine.set(b"log".to_vec(), b"a".to_vec())?;
+    engine.merge(b"log".to_vec(), "append", b"b".to_vec())?;
+    engine.merge(b"log".to_vec(), "append", b"c".to_vec())?;
+    engine.merge(b"tags".to_vec(), "union", br#"["x","y"]"#.to_vec())?;
+    engine.merge(b"tags".to_vec(), "union", br#"["y","z"]"#.to_vec())?;
+
+    assert_eq!(engine.get(b"counter".to_vec())?, Some(b"3".to_vec()));
+    assert_eq!(engine.get(b"log".to_vec())?, Some(b"abc".to_vec()));
+    assert_eq!(
+        engine.get(b"tags".to_vec())?,
+        Some(br#"["x","y","z"]"#.to_vec())
+    );
+    assert_eq!(
+        engine
+            .scan_prefix(b"c".to_vec())?
+            .collect::<Result<Vec<_>>>()?,
+        vec![(b"counter".to_vec(), b"3".to_vec())]
+    );
+
+    match engine.merge(b"counter".to_vec(), "add", b"one".to_vec()) {
+        Err(Error::MergeFailed) => {}
+        _ => panic!("merged an operand which isn't an integer"),
+    }
+    match engine.merge(b"counter".to_vec(), "multiply", b"2".to_vec()) {
+        Err(Error::UnknownMergeOperator) => {}
+        _ => panic!("merged with an unknown operator"),
+    }
+    assert_eq!(engine.get(b"counter".to_vec())?, Some(b"3".to_vec()));
+
+    // an operand which can't be folded into the value is never written
+    engine.set_with_ttl(
+        b"name".to_vec(),
+        b"abc".to_vec(),
+        std::time::Duration::from_secs(3600),
+    )?;
+    match engine.merge(b"name".to_vec(), "add", b"1".to_vec()) {
+        Err(Error::MergeFailed) => {}
+        _ => panic!("merged an integer into a value which isn't one"),
+    }
+    assert_eq!(engine.get(b"name".to_vec())?, Some(b"abc".to_vec()));
+    assert!(engine.ttl(b"name".to_vec())?.is_some());
+    let versions = engine.get_versions(b"name".to_vec(), 10)?;
+    assert_eq!(versions.len(), 1);
+    assert_eq!(versions[0].1, Some(b"abc".to_vec()));
+    assert_eq!(
+        engine.merge(b"name".to_vec(), "append", b"d".to_vec())?,
+        b"abcd".to_vec()
+    );
+
+    // a write replaces the operands merged before it
+    engine.set(b"counter".to_vec(), b"10".to_vec())?;
+    engine.merge(b"counter".to_vec(), "add", b"1".to_vec())?;
+    assert_eq!(engine.get(b"counter".to_vec())?, Some(b"11".to_vec()));
+
+    Ok(())
+}
+
+#[derive(Debug)]
+struct Max;
+
+impl MergeOperator for Max {
+    fn merge(&self, _key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Result<Vec<u8>> {
+        Ok(existing.unwrap_or_default().max(operand).to_vec())
+    }
+}
+
+// Should fold the merged operands into the value
+engine_tests!(merge, check_merge);
+
+// Should refuse a merge operator with a name too long for the log
+#[test]
+fn long_merge_operator_name() -> Result<()> {
+    match Options::new().merge_operator(&"m".repeat(256), Max) {
+        Err(Error::InvalidMergeOperator) => {}
+        _ => panic!("registered a merge operator with a name longer than 255 bytes"),
+    }
+    Options::new().merge_operator(&"m".repeat(255), Max)?;
+
+    Ok(())
+}
+
+// Should keep the operands through a reopen and fold them in a compaction
+#[test]
+fn merge_after_reopen_kv_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options::new()
+        .retention(Retention::LastVersions(2))
+        .merge_operator("max", Max)?;
+    let store = KvStore::open_with(temp_dir.path(), &options)?;
+    check_merge(&store)?;
+    store.merge(b"max".to_vec(), "max", b"b".to_vec())?;
+    store.merge(b"max".to_vec(), "max", b"a".to_vec())?;
+    drop(store);
+
+    // the operands outlive a reopen and get folded by a compaction
+    for _ in 0..2 {
+        let store = KvStore::open_with(temp_dir.path(), &options)?;
+        assert_eq!(store.get(b"counter".to_vec())?, Some(b"11".to_vec()));
+        assert_eq!(store.get(b"log".to_vec())?, Some(b"abc".to_vec()));
+        assert_eq!(store.get(b"max".to_vec())?, Some(b"b".to_vec()));
+
+        let values: Vec<_> = store
+            .get_versions(b"log".to_vec(), 2)?
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect();
+        assert_eq!(values, vec![Some(b"abc".to_vec()), Some(b"ab".to_vec())]);
+        store.compact_now()?;
+    }
+
+    Ok(())
+}
+
+// Should get `None` for a missing key and fail to remove one in memory
+#[test]
+fn missing_keys_memory() -> Result<()> {
+    let engine = MemoryEngine::new();
+    assert_eq!(engine.get(b"key1".to_vec())?, None);
+    match engine.remove(b"key1".to_vec()) {
+        Err(Error::KeyNotFound) => {}
+        other => panic!("expected a key not found error, got {:?}", other),
+    }
+
+    engine.set(b"key1".to_vec(), b"value1".to_vec())?;
+    engine.remove(b"key1".to_vec())?;
+    assert_eq!(engine.get(b"key1".to_vec())?, None);
+
+    Ok(())
+}
+
+// Should evict the least recently used keys once the data outgrows the limit
+#[test]
+fn lru_eviction_memory() -> Result<()> {
+    let engine = MemoryEngine::with_options(&Options::new().memory_limit(30));
+    engine.set(b"alice".to_vec(), b"0123456789".to_vec())?;
+    engine.set(b"bob".to_vec(), b"0123456789".to_vec())?;
+    assert!(engine.get(b"alice".to_vec())?.is_some());
+
+    // alice was used last, so bob makes room
+    engine.set(b"carol".to_vec(), b"0123456789".to_vec())?;
+    assert_eq!(engine.get(b"bob".to_vec())?, None);
+    assert!(engine.get(b"alice".to_vec())?.is_some());
+    assert!(engine.get(b"carol".to_vec())?.is_some());
+
+    // the size of a key counts as well
+    let users = engine.open_tree("users")?;
+    users.set(b"dave".to_vec(), b"0123456789".to_vec())?;
+    assert_eq!(keys(&engine)?, vec![b"carol".to_vec()]);
+    assert_eq!(keys(&users)?, vec![b"dave".to_vec()]);
+
+    Ok(())
+}
+
+/// the value every key written so far is left with, `None` for a removal
+type Written = BTreeMap<Vec<u8>, Option<Vec<u8>>>;
+
+/// writes to a store kept in `storage` until a write fails, `acknowledged` gets the writes
+/// which went through and `in_flight` the one which failed, it could have gone through as well
+fn crash_workload(
+    storage: &MemoryStorage,
+    options: &Options,
+    acknowledged: &mut Written,
+    in_flight: &mut Written,
+) {
+    let store = match KvStore::open_with_storage(storage.clone(), options) {
+        Ok(store) => store,
+        Err(_) => return,
+    };
+
+    let mut writes = Vec::new();
+    for i in 0..12 {
+        let key = format!("key{}", i % 5).into_bytes();
+        match i % 4 {
+            3 => writes.push((key, None)),
+            _ => writes.push((key, Some(format!("value{}", i).into_bytes()))),
+        }
+    }
+
+    for (i, (key, value)) in writes.into_iter().enumerate() {
+        if i == 8 && store.compact_now().is_err() {
+            return;
+        }
+
+        let written = match &value {
+            Some(value) => store.set(key.clone(), value.clone()),
+            None => match store.remove(key.clone()) {
+                Err(Error::KeyNotFound) => Ok(()),
+                result => result,
+            },
+        };
+        match written {
+            Ok(()) => {
+                acknowledged.insert(key, value);
+            }
+            Err(_) => {
+                in_flight.insert(key, value);
+                return;
+            }
+        }
+    }
+}
+
+// Should keep every acknowledged write through a crash at any point of the workload
+#[test]
+fn crash_at_every_op() -> Result<()> {
+    let options = Options::new().durability(Durability::Always);
+    let storage = MemoryStorage::new();
+    crash_workload(&storage, &options, &mut Written::new(), &mut Written::new());
+    let ops = storage.ops();
+
+    for crash_after in 0..ops {
+        let storage = MemoryStorage::new();
+        storage.crash_after(crash_after);
+        let (mut acknowledged, mut in_flight) = (Written::new(), Written::new());
+        crash_workload(&storage, &options, &mut acknowledged, &mut in_flight);
+
+        let store = KvStore::open_with_storage(storage.lose_power(), &options)?;
+        for key in acknowledged.keys().chain(in_flight.keys()) {
+            let recovered = store.get(key.clone())?;
+            let before = acknowledged.get(key).cloned().unwrap_or(None);
+            assert!(
+                recovered == before || Some(&recovered) == in_flight.get(key),
+                "{:?} recovered as {:?} after a crash at op {}",
+                String::from_utf8_lossy(key),
+                recovered,
+                crash_after
+            );
+        }
+        for pair in store.scan(.., None)? {
+            let (key, _) = pair?;
+            assert!(acknowledged.contains_key(&key) || in_flight.contains_key(&key));
+        }
+    }
+
+    Ok(())
+}
+
+// Should cut off a record torn by a crash in the middle of its write
+#[test]
+fn torn_write_recovery() -> Result<()> {
+    let storage = MemoryStorage::new();
+    let store = KvStore::open_with_storage(storage.clone(), &Options::new())?;
+    store.set(b"alice".to_vec(), b"1".to_vec())?;
+    storage.short_write(10);
+    storage.crash_after(1);
+    assert!(store.set(b"bob".to_vec(), b"2".to_vec()).is_err());
+    drop(store);
+
+    let store = KvStore::open_with_storage(storage.restart(), &Options::new())?;
+    assert_eq!(store.recovery_report().records_discarded, 1);
+    assert_eq!(store.recovery_report().truncated_bytes, 10);
+    assert_eq!(store.get(b"alice".to_vec())?, Some(b"1".to_vec()));
+    assert_eq!(store.get(b"bob".to_vec())?, None);
+
+    Ok(())
+}
+
+// Should replay segments in the order of their generations past 9 of them
+#[test]
+fn replay_order_past_nine_segments() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for i in 0..12 {
+        let mut store = KvStore::open(temp_dir.path())?;
+        store.set_string("key".to_owned(), format!("{}", i))?;
+    }
+    assert!(temp_dir.path().join("13.log").exists());
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_string("key".to_owned())?, Some("11".to_owned()));
+
+    Ok(())
+}
+
+// Should ignore and remove a segment the manifest doesn't list
+#[test]
+fn manifest_over_directory_listing() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_string("key".to_owned(), "old".to_owned())?;
+    drop(store);
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_string("key".to_owned(), "new".to_owned())?;
+    drop(store);
+
+    // as if a compaction had died right before putting its segment in the manifest
+    std::fs::copy(temp_dir.path().join("2.log"), temp_dir.path().join("9.log"))?;
+
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_string("key".to_owned())?, Some("new".to_owned()));
+    assert!(!temp_dir.path().join("9.log").exists());
+    drop(store);
+
+    // a store without a manifest has all of its segments live
+    std::fs::remove_file(temp_dir.path().join("MANIFEST"))?;
+    let mut store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_string("key".to_owned())?, Some("new".to_owned()));
+    assert!(temp_dir.path().join("MANIFEST").exists());
+
+    Ok(())
+}
+
+// Should seal full segments and compact only the ones which are mostly stale
+#[test]
+fn incremental_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = Options::new()
+        .max_segment_size(4096)
+        .compaction_threshold(usize::max_value());
+    let log_names = || -> Vec<String> {
+        let mut names: Vec<String> = std::fs::read_dir(temp_dir.path())
+            .expect("unable to list the store")
+            .map(|entry| entry.expect("unable to list the store").file_name())
+            .filter_map(|name| name.into_string().ok())
+            .filter(|name| name.ends_with(".log"))
+            .collect();
+        names.sort_by_key(|name| name.trim_end_matches(".log").parse::<usize>().unwrap_or(0));
+        names
+    };
+
+    let store = KvStore::open_with(temp_dir.path(), &options)?;
+    let orders = store.open_tree("orders")?;
+    for i in 0..100 {
+        orders.set(format!("order{}", i).into_bytes(), vec![b'o'; 32])?;
+    }
+    for i in 0..200 {
+        store.set(format!("cold{}", i).into_bytes(), vec![b'c'; 32])?;
+    }
+    let mut sealed = log_names();
+    sealed.pop();
+    assert!(sealed.len() >= 3);
+
+    // the removal and the drop hide records of the segments left alone
+    store.remove(b"cold0".to_vec())?;
+    store.drop_tree("orders")?;
+    for iter in 0..50 {
+        for i in 0..10 {
+            store.set(
+                format!("hot{}", i).into_bytes(),
+                format!("{}", iter).into_bytes(),
+            )?;
+        }
+    }
+    let before = log_names().len();
+    store.compact_now()?;
+
+    assert!(log_names().len() < before);
+    for log_name in &sealed {
+        assert!(temp_dir.path().join(log_name).exists());
+    }
+    drop(orders);
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), &options)?;
+    assert_eq!(store.get(b"cold0".to_vec())?, None);
+    assert_eq!(store.get(b"cold1".to_vec())?, Some(vec![b'c'; 32]));
+    assert_eq!(store.get(b"hot3".to_vec())?, Some(b"49".to_vec()));
+    assert_eq!(store.open_tree("orders")?.scan(.., None)?.count(), 0);
+
+    Ok(())
+}
+
+// Should fail the writes it can't sync and lose only unsynced data to a power loss
+#[test]
+fn unsynced_data_loss() -> Result<()> {
+    let options = Options::new().durability(Durability::Always);
+    let storage = MemoryStorage::new();
+    let store = KvStore::open_with_storage(storage.clone(), &options)?;
+    store.set(b"alice".to_vec(), b"1".to_vec())?;
+    storage.fail_syncs(true);
+    assert!(store.set(b"bob".to_vec(), b"2".to_vec()).is_err());
+    storage.fail_syncs(false);
+    store.set(b"carol".to_vec(), b"3".to_vec())?;
+    drop(store);
+
+    let store = KvStore::open_with_storage(storage.lose_power(), &options)?;
+    assert_eq!(store.get(b"alice".to_vec())?, Some(b"1".to_vec()));
+    assert_eq!(store.get(b"carol".to_vec())?, Some(b"3".to_vec()));
+
+    // the OS keeps what it has through a crash of the process but not through a power loss
+    let storage = MemoryStorage::new();
+    let store = KvStore::open_with_storage(storage.clone(), &Options::new())?;
+    store.set(b"alice".to_vec(), b"1".to_vec())?;
+    let (restarted, powered_off) = (storage.restart(), storage.lose_power());
+    drop(store);
+
+    let store = KvStore::open_with_storage(restarted, &Options::new())?;
+    assert_eq!(store.get(b"alice".to_vec())?, Some(b"1".to_vec()));
+    let store = KvStore::open_with_storage(powered_off, &Options::new())?;
+    assert_eq!(store.get(b"alice".to_vec())?, None);
+
+    Ok(())
+}